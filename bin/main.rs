@@ -1,63 +1,193 @@
 extern crate browser_engine;
-use browser_engine::{command, css, css_parser, dom, html_parse, layout, render, style};
+use browser_engine::{command, document, dom, layout, raster, render, style};
 
 use std::env;
 use std::fs::File;
 use std::io::{BufReader, Read};
+use std::process;
+
+const USAGE: &'static str = "usage: main <file.html> [file.css ...] [options]
+
+Stylesheets linked from the page are loaded automatically; extra css files
+given on the command line are applied after them.
+
+options:
+    --width <px>      viewport width (default 1024)
+    --height <px>     viewport height (default 768)
+    --window          open the page in a window (default)
+    --png <file>      render the page to a PNG file
+    --dump <stage>    print one stage: dom, style, layout or display
+    --help            print this message";
+
+enum Output {
+    Window,
+    Png(String),
+    Dump(Stage),
+}
+
+enum Stage {
+    Dom,
+    Style,
+    Layout,
+    Display,
+}
+
+struct Options {
+    html_path: String,
+    css_paths: Vec<String>,
+    width: usize,
+    height: usize,
+    output: Output,
+}
 
 fn main() {
-    let nodes = get_html();
-    for n in nodes.iter() {
-        dom::pretty_print(n, 0);
+    let options = match parse_args(env::args().skip(1).collect()) {
+        Ok(o) => o,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(&options) {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(options: &Options) -> Result<(), String> {
+    let mut document = document::Document::load(&options.html_path)
+        .map_err(|e| format!("file: {}, error: {}", options.html_path, e))?;
+
+    for path in &options.css_paths {
+        document.add_stylesheet(&read_file(path)?, path);
     }
 
-    let ref root_node = nodes[0];
+    for warning in &document.warnings {
+        eprintln!("warning: {}", warning);
+    }
 
-    let stylesheet = get_css();
-    println!("{:?}", stylesheet);
+    if let Output::Dump(Stage::Dom) = options.output {
+        for n in document.nodes.iter() {
+            dom::pretty_print(n, 0);
+        }
+        return Ok(());
+    }
 
-    let style_tree_root = style::StyledNode::new(&root_node, &stylesheet);
-    style::pretty_print(&style_tree_root, 0);
+    let root_node = match document.root() {
+        Some(n) => n,
+        None => return Err(format!("{}: document is empty", options.html_path)),
+    };
 
     let mut viewport = layout::Dimensions::default();
-    viewport.content.width = 1024.0;
-    viewport.content.height = 768.0;
+    viewport.content.width = options.width as f32;
+    viewport.content.height = options.height as f32;
+
+    let style_tree_root = style::StyledNode::new(
+        root_node,
+        &document.stylesheets,
+        viewport.content.width,
+        viewport.content.height,
+    );
+
+    if let Output::Dump(Stage::Style) = options.output {
+        style::pretty_print(&style_tree_root, 0);
+        return Ok(());
+    }
 
-    let layout_tree = layout::layout_tree(&style_tree_root, viewport);
-    layout::pretty_print(&layout_tree, 0);
+    let layout_tree = layout::layout_tree(&style_tree_root, viewport, document.quirks_mode);
 
-    let display_commands = command::build_display_commands(&layout_tree);
-    render::render_loop(&display_commands);
-}
+    if let Output::Dump(Stage::Layout) = options.output {
+        layout::pretty_print(&layout_tree, 0);
+        return Ok(());
+    }
 
-fn get_html() -> Vec<dom::Node> {
-    let mut path = env::current_dir().unwrap();
-    path.push("example/example2.html");
+    let display_commands = command::build_display_commands(&layout_tree);
 
-    let mut file_reader = match File::open(&path) {
-        Ok(f) => BufReader::new(f),
-        Err(e) => panic!("file: {}, error: {}", path.display(), e),
-    };
+    match options.output {
+        Output::Window => render::render_loop(&display_commands, options.width, options.height),
+        Output::Png(ref path) => {
+            let canvas = raster::rasterize(&display_commands, options.width, options.height);
+            canvas
+                .write_png(path)
+                .map_err(|e| format!("file: {}, error: {}", path, e))?;
+        }
+        Output::Dump(_) => for c in display_commands.iter() {
+            println!("{:?}", c);
+        },
+    }
+    Ok(())
+}
 
-    let mut html_input = String::new();
-    file_reader.read_to_string(&mut html_input).unwrap();
+fn parse_args(args: Vec<String>) -> Result<Options, String> {
+    let mut paths = Vec::new();
+    let mut width = 1024;
+    let mut height = 768;
+    let mut output = Output::Window;
+
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.as_ref() {
+            "--help" | "-h" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            "--width" => width = parse_size(&arg, args.next())?,
+            "--height" => height = parse_size(&arg, args.next())?,
+            "--window" => output = Output::Window,
+            "--png" => match args.next() {
+                Some(path) => output = Output::Png(path),
+                None => return Err(String::from("--png needs an output file")),
+            },
+            "--dump" => {
+                let stage = match args.next() {
+                    Some(ref s) if s == "dom" => Stage::Dom,
+                    Some(ref s) if s == "style" => Stage::Style,
+                    Some(ref s) if s == "layout" => Stage::Layout,
+                    Some(ref s) if s == "display" => Stage::Display,
+                    Some(s) => return Err(format!("unknown dump stage '{}'", s)),
+                    None => return Err(String::from("--dump needs a stage")),
+                };
+                output = Output::Dump(stage);
+            }
+            s if s.starts_with("--") => return Err(format!("unknown option '{}'", s)),
+            _ => paths.push(arg),
+        }
+    }
 
-    let nodes = html_parse::HtmlParser::new(&html_input).parse_nodes();
-    nodes
+    if paths.is_empty() {
+        return Err(String::from("no html file given"));
+    }
+    let html_path = paths.remove(0);
+
+    Ok(Options {
+        html_path,
+        css_paths: paths,
+        width,
+        height,
+        output,
+    })
 }
 
-fn get_css() -> css::Stylesheet {
-    let mut path = env::current_dir().unwrap();
-    path.push("example/example2.css");
+fn parse_size(option: &str, value: Option<String>) -> Result<usize, String> {
+    match value {
+        Some(v) => match v.parse() {
+            Ok(n) if n > 0 => Ok(n),
+            _ => Err(format!("{} expects a positive integer, got '{}'", option, v)),
+        },
+        None => Err(format!("{} needs a value", option)),
+    }
+}
 
-    let mut file_reader = match File::open(&path) {
+fn read_file(path: &str) -> Result<String, String> {
+    let mut file_reader = match File::open(path) {
         Ok(f) => BufReader::new(f),
-        Err(e) => panic!("file: {}, error: {}", path.display(), e),
+        Err(e) => return Err(format!("file: {}, error: {}", path, e)),
     };
 
-    let mut css_input = String::new();
-    file_reader.read_to_string(&mut css_input).unwrap();
-
-    let stylesheet = css_parser::CssParser::new(&css_input).parse_stylesheet();
-    stylesheet
+    let mut input = String::new();
+    file_reader
+        .read_to_string(&mut input)
+        .map_err(|e| format!("file: {}, error: {}", path, e))?;
+    Ok(input)
 }
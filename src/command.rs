@@ -1,11 +1,12 @@
-use css::{Color, Value};
-use layout::{LayoutBox, Rectangle};
+use css::Color;
+use layout::{BoxType, LayoutBox, Rectangle};
 use std::fmt;
 
 pub type DisplayList = Vec<DisplayCommand>;
 
 pub enum DisplayCommand {
     SolidRectangle(Color, Rectangle),
+    Text(String, Rectangle, f32, Color),
 }
 
 pub fn build_display_commands(root: &LayoutBox) -> DisplayList {
@@ -16,8 +17,15 @@ pub fn build_display_commands(root: &LayoutBox) -> DisplayList {
 }
 
 fn render_layout_box(commands: &mut DisplayList, layout_box: &LayoutBox) {
-    render_background(commands, layout_box);
-    render_borders(commands, layout_box);
+    match layout_box.box_type {
+        // Anonymous boxes share their parent's style, which already painted.
+        BoxType::Anonymous | BoxType::Text => {}
+        _ => {
+            render_background(commands, layout_box);
+            render_borders(commands, layout_box);
+        }
+    }
+    render_text(commands, layout_box);
 
     for child in &layout_box.children {
         render_layout_box(commands, child);
@@ -25,78 +33,96 @@ fn render_layout_box(commands: &mut DisplayList, layout_box: &LayoutBox) {
 }
 
 fn render_background(commands: &mut DisplayList, layout_box: &LayoutBox) {
-    get_color(layout_box, "background-color").map(|color| {
+    if let Some(color) = get_color(layout_box, "background-color").filter(|c| c.a > 0.0) {
         commands.push(DisplayCommand::SolidRectangle(
             color,
             layout_box.dimensions.border_box(),
-        ))
-    });
-}
-
-fn get_color(layout_box: &LayoutBox, name: &str) -> Option<Color> {
-    match layout_box.styled_node.value(name) {
-        Some(v) => match **v {
-            Value::Color(ref c) => return Some(c.clone()),
-            _ => return None,
-        },
-        None => return None,
+        ));
     }
 }
 
-fn render_borders(commands: &mut DisplayList, layout_box: &LayoutBox) {
-    let color = match get_color(layout_box, "border-color") {
-        Some(color) => color,
+fn render_text(commands: &mut DisplayList, layout_box: &LayoutBox) {
+    let color = match get_color(layout_box, "color") {
+        Some(ref color) if color.a > 0.0 => color.clone(),
         _ => return,
     };
+    let font_size = layout_box.styled_node.num_or("font-size", 16.0);
+
+    for run in &layout_box.text_runs {
+        commands.push(DisplayCommand::Text(
+            run.text.clone(),
+            run.rect,
+            font_size,
+            color.clone(),
+        ));
+    }
+}
 
+fn get_color(layout_box: &LayoutBox, name: &str) -> Option<Color> {
+    layout_box.styled_node.color(name).cloned()
+}
+
+fn render_borders(commands: &mut DisplayList, layout_box: &LayoutBox) {
     let d = &layout_box.dimensions;
     let border_box = d.border_box();
 
-    commands.push(DisplayCommand::SolidRectangle(
-        color.clone(),
-        Rectangle {
-            x: border_box.x,
-            y: border_box.y,
-            width: d.border.left,
-            height: border_box.height,
-        },
-    ));
+    let sides = [
+        (
+            "border-left-color",
+            Rectangle {
+                x: border_box.x,
+                y: border_box.y,
+                width: d.border.left,
+                height: border_box.height,
+            },
+        ),
+        (
+            "border-right-color",
+            Rectangle {
+                x: border_box.x + border_box.width - d.border.right,
+                y: border_box.y,
+                width: d.border.right,
+                height: border_box.height,
+            },
+        ),
+        (
+            "border-top-color",
+            Rectangle {
+                x: border_box.x,
+                y: border_box.y,
+                width: border_box.width,
+                height: d.border.top,
+            },
+        ),
+        (
+            "border-bottom-color",
+            Rectangle {
+                x: border_box.x,
+                y: border_box.y + border_box.height - d.border.bottom,
+                width: border_box.width,
+                height: d.border.bottom,
+            },
+        ),
+    ];
 
-    commands.push(DisplayCommand::SolidRectangle(
-        color.clone(),
-        Rectangle {
-            x: border_box.x + border_box.width - d.border.right,
-            y: border_box.y,
-            width: d.border.right,
-            height: border_box.height,
-        },
-    ));
-
-    commands.push(DisplayCommand::SolidRectangle(
-        color.clone(),
-        Rectangle {
-            x: border_box.x,
-            y: border_box.y,
-            width: border_box.width,
-            height: d.border.top,
-        },
-    ));
+    for &(property, rect) in sides.iter() {
+        if rect.width <= 0.0 || rect.height <= 0.0 {
+            continue;
+        }
 
-    commands.push(DisplayCommand::SolidRectangle(
-        color,
-        Rectangle {
-            x: border_box.x,
-            y: border_box.y + border_box.height - d.border.bottom,
-            width: border_box.width,
-            height: d.border.bottom,
-        },
-    ));
+        if let Some(color) = get_color(layout_box, property).filter(|c| c.a > 0.0) {
+            commands.push(DisplayCommand::SolidRectangle(color, rect));
+        }
+    }
 }
 
 impl fmt::Debug for DisplayCommand {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             DisplayCommand::SolidRectangle(ref c, ref r) => write!(f, "{:?} {:?}", c, r),
+            DisplayCommand::Text(ref t, ref r, s, ref c) => {
+                write!(f, "{:?} {:?} size: {} {:?}", t, r, s, c)
+            }
         }
     }
 }
@@ -4,6 +4,52 @@ use std::default::Default;
 #[derive(PartialEq)]
 pub struct Stylesheet {
     pub rules: Vec<Rule>,
+    pub at_rules: Vec<AtRule>,
+    pub origin: Origin,
+}
+// Where a sheet came from. Author sheets override the user agent's defaults,
+// though the order flips for !important declarations.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Origin {
+    UserAgent,
+    Author,
+}
+#[derive(PartialEq)]
+pub struct AtRule {
+    pub name: String,
+    pub prelude: String,
+    // The rules inside grouping rules such as @media; None for statements
+    // like @import and for blocks the parser doesn't read.
+    pub block: Option<Stylesheet>,
+    // The query list of an @media rule, which matches when any one query
+    // does, or everything when empty.
+    pub media: Vec<MediaQuery>,
+    // How many of the enclosing sheet's rules come before this one.
+    pub index: usize,
+}
+#[derive(PartialEq, Clone)]
+pub struct MediaQuery {
+    pub negated: bool,
+    pub media_type: MediaType,
+    pub features: Vec<MediaFeature>,
+}
+#[derive(PartialEq, Clone)]
+pub enum MediaType {
+    All,
+    Screen,
+    Print,
+    Other(String),
+}
+#[derive(PartialEq, Clone)]
+pub enum MediaFeature {
+    MinWidth(Value),
+    MaxWidth(Value),
+    MinHeight(Value),
+    MaxHeight(Value),
+    Orientation(String),
+    PrefersColorScheme(String),
+    // A feature the engine doesn't know, which never matches.
+    Unknown(String),
 }
 #[derive(PartialEq)]
 pub struct Rule {
@@ -13,27 +59,97 @@ pub struct Rule {
 #[derive(PartialEq, Eq)]
 pub struct Selector {
     pub simple: Vec<SimpleSelector>,
+    // combinators[i] sits between simple[i] and simple[i + 1]: one of ' ', '>', '+' or '~'.
     pub combinators: Vec<char>,
+    // Set when the selector picks out the `::before` or `::after` box of the
+    // elements it matches instead of the elements themselves.
+    pub pseudo_element: Option<PseudoElement>,
+}
+#[derive(PartialEq, Eq, Clone, Copy)]
+pub enum PseudoElement {
+    Before,
+    After,
 }
 
 #[derive(PartialEq, Eq)]
 pub struct SimpleSelector {
     pub tag_name: Option<String>,
-    pub id: Option<String>,
+    // `*`, which matches every element and adds nothing to specificity.
+    pub universal: bool,
+    // More than one id is valid, though no element can match it.
+    pub ids: Vec<String>,
     pub classes: Vec<String>,
+    pub attributes: Vec<AttributeSelector>,
+    pub pseudo_classes: Vec<PseudoClass>,
+}
+#[derive(PartialEq, Eq)]
+pub struct AttributeSelector {
+    pub name: String,
+    pub operator: AttrOperator,
+    pub value: String,
+    // The `i` flag: compare the value without regard to ASCII case.
+    pub case_insensitive: bool,
+}
+#[derive(PartialEq, Eq)]
+pub enum AttrOperator {
+    // `[attr]`
+    Exists,
+    // `[attr=value]`
+    Equals,
+    // `[attr~=value]`: one of the whitespace-separated words.
+    Includes,
+    // `[attr|=value]`: exactly the value, or the value followed by `-`.
+    DashMatch,
+    // `[attr^=value]`
+    Prefix,
+    // `[attr$=value]`
+    Suffix,
+    // `[attr*=value]`
+    Substring,
+}
+#[derive(PartialEq, Eq)]
+pub enum PseudoClass {
+    Root,
+    Empty,
+    FirstChild,
+    LastChild,
+    OnlyChild,
+    FirstOfType,
+    LastOfType,
+    OnlyOfType,
+    // The positions An+B, as (A, B), counted from 1.
+    NthChild(i32, i32),
+    NthLastChild(i32, i32),
+    NthOfType(i32, i32),
+    NthLastOfType(i32, i32),
+    Not(Vec<Selector>),
+    Is(Vec<Selector>),
+    // Like `Is`, but adds nothing to specificity.
+    Where(Vec<Selector>),
 }
 #[derive(PartialEq)]
 pub struct Declaration {
     pub property: String,
     pub value: Value,
+    pub important: bool,
 }
-#[derive(PartialEq)]
+pub type Specificity = (usize, usize, usize);
+
+#[derive(PartialEq, Clone)]
 pub enum Value {
-    Color(Color),
+    Keyword(String),
+    Number(f32),
+    Percentage(f32),
     Length(f32, Unit),
-    Other(String),
+    Color(Color),
+    String(String),
+    Url(String),
+    // Values separated by one of ' ', ',' or '/'.
+    List(Vec<Value>, char),
+    // A function the parser doesn't evaluate itself, with its arguments.
+    Function(String, Vec<Value>),
 }
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 pub enum Unit {
     Em,
     Ex,
@@ -50,7 +166,17 @@ pub enum Unit {
     In,
     Pt,
     Pc,
-    Pct,
+}
+
+// What relative lengths are measured against.
+#[derive(Clone, Copy, Debug)]
+pub struct LengthContext {
+    pub font_size: f32,
+    pub root_font_size: f32,
+    pub viewport_width: f32,
+    pub viewport_height: f32,
+    // The width of `0` in the current font, which `ch` is measured in.
+    pub ch_advance: f32,
 }
 
 #[derive(PartialEq, Clone)]
@@ -63,28 +189,149 @@ pub struct Color {
 
 
 impl Stylesheet {
-    pub fn new(rules: Vec<Rule>) -> Stylesheet {
-        Stylesheet { rules }
+    pub fn new(rules: Vec<Rule>, at_rules: Vec<AtRule>, origin: Origin) -> Stylesheet {
+        Stylesheet {
+            rules,
+            at_rules,
+            origin,
+        }
+    }
+
+    // Marks the sheet and every block nested in it as coming from `origin`.
+    pub fn set_origin(&mut self, origin: Origin) {
+        self.origin = origin;
+
+        for at_rule in &mut self.at_rules {
+            if let Some(ref mut block) = at_rule.block {
+                block.set_origin(origin);
+            }
+        }
     }
 }
 impl Default for Stylesheet {
     fn default() -> Self {
-        Stylesheet { rules: Vec::new() }
+        Stylesheet {
+            rules: Vec::new(),
+            at_rules: Vec::new(),
+            origin: Origin::Author,
+        }
     }
 }
 impl fmt::Debug for Stylesheet {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut rule_result = String::new();
-        for rule in &self.rules {
-            if rule_result.len() > 0 {
-                rule_result.push_str("\n\n");
+        let mut at_rules = self.at_rules.iter().peekable();
+
+        for (i, rule) in self.rules.iter().map(|r| format!("{:?}", r)).enumerate() {
+            while let Some(at_rule) = at_rules.peek().filter(|a| a.index <= i).cloned() {
+                push_rule(&mut rule_result, &format!("{:?}", at_rule));
+                at_rules.next();
             }
-            rule_result.push_str(&format!("{:?}", rule));
+            push_rule(&mut rule_result, &rule);
+        }
+        for at_rule in at_rules {
+            push_rule(&mut rule_result, &format!("{:?}", at_rule));
         }
         write!(f, "{}", rule_result)
     }
 }
 
+fn push_rule(result: &mut String, rule: &str) {
+    if result.len() > 0 {
+        result.push_str("\n\n");
+    }
+    result.push_str(rule);
+}
+
+impl AtRule {
+    pub fn new(
+        name: String,
+        prelude: String,
+        block: Option<Stylesheet>,
+        media: Vec<MediaQuery>,
+        index: usize,
+    ) -> AtRule {
+        AtRule {
+            name,
+            prelude,
+            block,
+            media,
+            index,
+        }
+    }
+}
+
+// The engine always renders to a screen with a light color scheme, sized to
+// the viewport in `context`. Lengths in queries resolve against the initial
+// font size, not the root element's.
+impl MediaQuery {
+    pub fn new(negated: bool, media_type: MediaType, features: Vec<MediaFeature>) -> MediaQuery {
+        MediaQuery {
+            negated,
+            media_type,
+            features,
+        }
+    }
+
+    // What a query that fails to parse becomes: `not all`.
+    pub fn never() -> MediaQuery {
+        MediaQuery::new(true, MediaType::All, Vec::new())
+    }
+
+    pub fn matches(&self, context: &LengthContext) -> bool {
+        let type_matches = match self.media_type {
+            MediaType::All | MediaType::Screen => true,
+            MediaType::Print | MediaType::Other(_) => false,
+        };
+
+        let matches = type_matches && self.features.iter().all(|f| f.matches(context));
+        matches != self.negated
+    }
+}
+
+impl MediaFeature {
+    fn matches(&self, context: &LengthContext) -> bool {
+        let px = |v: &Value| v.to_px(context, 0.0).unwrap_or(0.0);
+        let width = context.viewport_width;
+        let height = context.viewport_height;
+
+        match *self {
+            MediaFeature::MinWidth(ref v) => width >= px(v),
+            MediaFeature::MaxWidth(ref v) => width <= px(v),
+            MediaFeature::MinHeight(ref v) => height >= px(v),
+            MediaFeature::MaxHeight(ref v) => height <= px(v),
+            MediaFeature::Orientation(ref o) => (height >= width) == (o == "portrait"),
+            MediaFeature::PrefersColorScheme(ref scheme) => scheme == "light",
+            MediaFeature::Unknown(_) => false,
+        }
+    }
+}
+
+impl fmt::Debug for AtRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut head = format!("@{}", self.name);
+        if !self.prelude.is_empty() {
+            head.push(' ');
+            head.push_str(&self.prelude);
+        }
+
+        match self.block {
+            Some(ref block) => {
+                let mut body = String::new();
+                for line in format!("{:?}", block).lines() {
+                    if !line.is_empty() {
+                        body.push_str("    ");
+                        body.push_str(line);
+                    }
+                    body.push('\n');
+                }
+                write!(f, "{} {{\n{}}}", head, body)
+            }
+            None => write!(f, "{};", head),
+        }
+    }
+}
+
 impl Rule {
     pub fn new(selectors: Vec<Selector>, declarations: Vec<Declaration>) -> Rule {
         Rule {
@@ -128,11 +375,28 @@ impl fmt::Debug for Rule {
 
 
 impl Selector {
-    pub fn new(simple: Vec<SimpleSelector>, combinators: Vec<char>) -> Selector {
+    pub fn new(
+        simple: Vec<SimpleSelector>,
+        combinators: Vec<char>,
+        pseudo_element: Option<PseudoElement>,
+    ) -> Selector {
         Selector {
             simple,
             combinators,
+            pseudo_element,
+        }
+    }
+
+    pub fn specificity(&self) -> Specificity {
+        let mut specificity = (0, 0, self.pseudo_element.iter().count());
+
+        for simple in &self.simple {
+            let (a, b, c) = simple.specificity();
+            specificity.0 += a;
+            specificity.1 += b;
+            specificity.2 += c;
         }
+        specificity
     }
 }
 impl Default for Selector {
@@ -140,6 +404,7 @@ impl Default for Selector {
         Selector {
             simple: Vec::new(),
             combinators: Vec::new(),
+            pseudo_element: None,
         }
     }
 }
@@ -147,13 +412,22 @@ impl fmt::Debug for Selector {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let mut result = String::new();
 
-        for sel in &self.simple {
-            if result.len() > 0 {
-                result.push_str(", ");
+        for (i, sel) in self.simple.iter().enumerate() {
+            if i > 0 {
+                match self.combinators[i - 1] {
+                    ' ' => result.push(' '),
+                    c => result.push_str(&format!(" {} ", c)),
+                }
             }
             result.push_str(&format!("{:?}", sel));
         }
 
+        match self.pseudo_element {
+            Some(PseudoElement::Before) => result.push_str("::before"),
+            Some(PseudoElement::After) => result.push_str("::after"),
+            None => {}
+        }
+
         write!(f, "{}", result)
     }
 }
@@ -161,23 +435,43 @@ impl fmt::Debug for Selector {
 impl SimpleSelector {
     pub fn new(
         tag_name: Option<String>,
-        id: Option<String>,
+        universal: bool,
+        ids: Vec<String>,
         classes: Vec<String>,
+        attributes: Vec<AttributeSelector>,
+        pseudo_classes: Vec<PseudoClass>,
     ) -> SimpleSelector {
         SimpleSelector {
             tag_name,
-            id,
+            universal,
+            ids,
             classes,
+            attributes,
+            pseudo_classes,
         }
     }
+
+    pub fn specificity(&self) -> Specificity {
+        let a = self.ids.len();
+        let b = self.classes.len() + self.attributes.len();
+        let c = self.tag_name.iter().count();
+
+        self.pseudo_classes
+            .iter()
+            .map(|p| p.specificity())
+            .fold((a, b, c), |acc, s| (acc.0 + s.0, acc.1 + s.1, acc.2 + s.2))
+    }
 }
 
 impl Default for SimpleSelector {
     fn default() -> Self {
         SimpleSelector {
             tag_name: None,
-            id: None,
+            universal: false,
+            ids: Vec::new(),
             classes: Vec::new(),
+            attributes: Vec::new(),
+            pseudo_classes: Vec::new(),
         }
     }
 }
@@ -188,15 +482,13 @@ impl fmt::Debug for SimpleSelector {
 
         match self.tag_name {
             Some(ref t) => result.push_str(t),
+            None if self.universal => result.push('*'),
             None => {}
         }
 
-        match self.id {
-            Some(ref s) => {
-                result.push('#');
-                result.push_str(s);
-            }
-            None => {}
+        for id in &self.ids {
+            result.push('#');
+            result.push_str(id);
         }
 
         for class in &self.classes {
@@ -204,13 +496,112 @@ impl fmt::Debug for SimpleSelector {
             result.push_str(class);
         }
 
+        for attribute in &self.attributes {
+            result.push_str(&format!("{:?}", attribute));
+        }
+
+        for pseudo_class in &self.pseudo_classes {
+            result.push_str(&format!("{:?}", pseudo_class));
+        }
+
         write!(f, "{}", result)
     }
 }
 
+impl PseudoClass {
+    // `:not()` and `:is()` count as their most specific argument.
+    pub fn specificity(&self) -> Specificity {
+        match *self {
+            PseudoClass::Not(ref list) | PseudoClass::Is(ref list) => list
+                .iter()
+                .map(|s| s.specificity())
+                .max()
+                .unwrap_or((0, 0, 0)),
+            PseudoClass::Where(_) => (0, 0, 0),
+            _ => (0, 1, 0),
+        }
+    }
+}
+
+impl fmt::Debug for PseudoClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let nth = |a: i32, b: i32| {
+            let an = match a {
+                1 => String::from("n"),
+                -1 => String::from("-n"),
+                a => format!("{}n", a),
+            };
+            match (a, b) {
+                (0, b) => format!("{}", b),
+                (_, 0) => an,
+                (_, b) => format!("{}{:+}", an, b),
+            }
+        };
+        let list = |selectors: &[Selector]| {
+            let selectors: Vec<String> = selectors.iter().map(|s| format!("{:?}", s)).collect();
+            selectors.join(", ")
+        };
+
+        match *self {
+            PseudoClass::Root => write!(f, ":root"),
+            PseudoClass::Empty => write!(f, ":empty"),
+            PseudoClass::FirstChild => write!(f, ":first-child"),
+            PseudoClass::LastChild => write!(f, ":last-child"),
+            PseudoClass::OnlyChild => write!(f, ":only-child"),
+            PseudoClass::FirstOfType => write!(f, ":first-of-type"),
+            PseudoClass::LastOfType => write!(f, ":last-of-type"),
+            PseudoClass::OnlyOfType => write!(f, ":only-of-type"),
+            PseudoClass::NthChild(a, b) => write!(f, ":nth-child({})", nth(a, b)),
+            PseudoClass::NthLastChild(a, b) => write!(f, ":nth-last-child({})", nth(a, b)),
+            PseudoClass::NthOfType(a, b) => write!(f, ":nth-of-type({})", nth(a, b)),
+            PseudoClass::NthLastOfType(a, b) => write!(f, ":nth-last-of-type({})", nth(a, b)),
+            PseudoClass::Not(ref s) => write!(f, ":not({})", list(s)),
+            PseudoClass::Is(ref s) => write!(f, ":is({})", list(s)),
+            PseudoClass::Where(ref s) => write!(f, ":where({})", list(s)),
+        }
+    }
+}
+
+impl AttributeSelector {
+    pub fn new(
+        name: String,
+        operator: AttrOperator,
+        value: String,
+        case_insensitive: bool,
+    ) -> AttributeSelector {
+        AttributeSelector {
+            name,
+            operator,
+            value,
+            case_insensitive,
+        }
+    }
+}
+
+impl fmt::Debug for AttributeSelector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let operator = match self.operator {
+            AttrOperator::Exists => return write!(f, "[{}]", self.name),
+            AttrOperator::Equals => "=",
+            AttrOperator::Includes => "~=",
+            AttrOperator::DashMatch => "|=",
+            AttrOperator::Prefix => "^=",
+            AttrOperator::Suffix => "$=",
+            AttrOperator::Substring => "*=",
+        };
+        let flag = if self.case_insensitive { " i" } else { "" };
+
+        write!(f, "[{}{}{:?}{}]", self.name, operator, self.value, flag)
+    }
+}
+
 impl Declaration {
-    pub fn new(property: String, value: Value) -> Declaration {
-        Declaration { property, value }
+    pub fn new(property: String, value: Value, important: bool) -> Declaration {
+        Declaration {
+            property,
+            value,
+            important,
+        }
     }
 }
 
@@ -218,7 +609,8 @@ impl Default for Declaration {
     fn default() -> Self {
         Declaration {
             property: String::from(""),
-            value: Value::Other(String::from("")),
+            value: Value::Keyword(String::from("")),
+            important: false,
         }
     }
 }
@@ -226,16 +618,93 @@ impl Default for Declaration {
 
 impl fmt::Debug for Declaration {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}: {:?}", self.property, self.value)
+        if self.important {
+            write!(f, "{}: {:?} !important", self.property, self.value)
+        } else {
+            write!(f, "{}: {:?}", self.property, self.value)
+        }
+    }
+}
+
+impl Value {
+    // Resolves a length to pixels; percentages are taken of `percent_base`.
+    pub fn to_px(&self, context: &LengthContext, percent_base: f32) -> Option<f32> {
+        match *self {
+            Value::Length(n, ref unit) => Some(n * unit.px_per_unit(context)),
+            Value::Percentage(n) => Some(n / 100.0 * percent_base),
+            _ => None,
+        }
+    }
+}
+
+impl Unit {
+    fn px_per_unit(&self, context: &LengthContext) -> f32 {
+        let vw = context.viewport_width / 100.0;
+        let vh = context.viewport_height / 100.0;
+
+        match *self {
+            Unit::Px => 1.0,
+            Unit::Em => context.font_size,
+            Unit::Rem => context.root_font_size,
+            Unit::Ex => context.font_size * 0.5,
+            Unit::Ch => context.ch_advance,
+            Unit::Vw => vw,
+            Unit::Vh => vh,
+            Unit::Vmin => vw.min(vh),
+            Unit::Vmax => vw.max(vh),
+            Unit::In => 96.0,
+            Unit::Cm => 96.0 / 2.54,
+            Unit::Mm => 96.0 / 25.4,
+            Unit::Q => 96.0 / 101.6,
+            Unit::Pt => 96.0 / 72.0,
+            Unit::Pc => 16.0,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Unit::Em => "em",
+            Unit::Ex => "ex",
+            Unit::Ch => "ch",
+            Unit::Rem => "rem",
+            Unit::Vh => "vh",
+            Unit::Vw => "vw",
+            Unit::Vmin => "vmin",
+            Unit::Vmax => "vmax",
+            Unit::Px => "px",
+            Unit::Mm => "mm",
+            Unit::Q => "q",
+            Unit::Cm => "cm",
+            Unit::In => "in",
+            Unit::Pt => "pt",
+            Unit::Pc => "pc",
+        }
     }
 }
 
 impl fmt::Debug for Value {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
+            Value::Keyword(ref k) => write!(f, "{}", k),
+            Value::Number(n) => write!(f, "{}", n),
+            Value::Percentage(n) => write!(f, "{}%", n),
+            Value::Length(n, ref unit) => write!(f, "{}{}", n, unit.name()),
             Value::Color(ref c) => write!(f, "{:?}", c),
-            Value::Length(l, _) => write!(f, "{:?}", l),
-            Value::Other(ref s) => write!(f, "{:?}", s),
+            Value::String(ref s) => write!(f, "{:?}", s),
+            Value::Url(ref u) => write!(f, "url({:?})", u),
+            Value::List(ref values, separator) => {
+                let separator = match separator {
+                    ',' => ", ",
+                    '/' => " / ",
+                    _ => " ",
+                };
+                let values: Vec<String> = values.iter().map(|v| format!("{:?}", v)).collect();
+                write!(f, "{}", values.join(separator))
+            }
+            Value::Function(ref name, ref args) => {
+                let args: Vec<String> = args.iter().map(|v| format!("{:?}", v)).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
         }
     }
 }
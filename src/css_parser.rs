@@ -1,764 +1,1879 @@
-use css::{Color, Declaration, Rule, Selector, SimpleSelector, Stylesheet, Unit, Value};
+use css::{AtRule, AttrOperator, AttributeSelector, Color, Declaration, MediaFeature, MediaQuery,
+          MediaType, PseudoClass, PseudoElement, Rule, Selector, SimpleSelector, Stylesheet,
+          Unit, Value};
+use css_shorthand;
+use css_tokenizer::{Token, TokenKind, Tokenizer};
 
-use std::iter::Peekable;
-use std::str::Chars;
+use std::fmt;
 
-pub struct CssParser<'a> {
-    chars: Peekable<Chars<'a>>,
+// Something the parser had to skip, and where in the source it happened.
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
 }
 
-impl<'a> CssParser<'a> {
+// Parses a stylesheet the way CSS Syntax Level 3 does: rules are read as
+// component values, so an invalid selector drops only its own rule and an
+// invalid declaration only itself, and parsing picks up again afterwards.
+pub struct CssParser {
+    tokens: Vec<Token>,
+    errors: Vec<ParseError>,
+}
+
+impl CssParser {
     pub fn new(full_css: &str) -> CssParser {
         CssParser {
-            chars: full_css.chars().peekable(),
+            tokens: Tokenizer::new(full_css).tokenize(),
+            errors: Vec::new(),
         }
     }
 
+    // Rules and declarations dropped while parsing, with the reason for each.
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+
     pub fn parse_stylesheet(&mut self) -> Stylesheet {
-        let mut stylesheet = Stylesheet::default();
+        let tokens = ::std::mem::replace(&mut self.tokens, Vec::new());
+        let stylesheet = self.parse_rule_list(&tokens, true);
 
-        while self.chars.peek().is_some() {
-            let selectors = self.parse_selectors();
-            let styles = self.parse_declarations();
-            let rule = Rule::new(selectors, styles);
+        self.tokens = tokens;
+        stylesheet
+    }
 
+    fn parse_rule_list(&mut self, tokens: &[Token], top_level: bool) -> Stylesheet {
+        let mut stylesheet = Stylesheet::default();
+        let mut cursor = Cursor::new(tokens);
 
-            stylesheet.rules.push(rule);
+        while let Some(token) = cursor.peek() {
+            match token.kind {
+                TokenKind::Whitespace => {
+                    cursor.next();
+                }
+                // HTML comment markers are allowed around a whole stylesheet.
+                TokenKind::Cdo | TokenKind::Cdc if top_level => {
+                    cursor.next();
+                }
+                TokenKind::AtKeyword(_) => {
+                    let mut at_rule = self.parse_at_rule(&mut cursor);
+                    at_rule.index = stylesheet.rules.len();
+                    stylesheet.at_rules.push(at_rule);
+                }
+                _ => if let Some(rule) = self.parse_qualified_rule(&mut cursor) {
+                    stylesheet.rules.push(rule);
+                },
+            }
         }
 
         stylesheet
     }
 
-    fn parse_selectors(&mut self) -> Vec<Selector> {
-        let mut selectors = Vec::new();
+    fn parse_at_rule(&mut self, cursor: &mut Cursor) -> AtRule {
+        let name = match cursor.next().map(|t| &t.kind) {
+            Some(&TokenKind::AtKeyword(ref name)) => name.to_lowercase(),
+            _ => String::new(),
+        };
 
-        while self.chars.peek().map_or(false, |c| *c != '{') {
-            let selector = self.parse_selector();
+        let start = cursor.pos;
+        while let Some(token) = cursor.peek() {
+            match token.kind {
+                TokenKind::Semicolon | TokenKind::OpenCurly => break,
+                _ => cursor.component_value(),
+            };
+        }
+        let prelude_tokens = &cursor.tokens[start..cursor.pos];
+        let prelude = serialize(prelude_tokens);
 
-            if selector != Selector::default() {
-                selectors.push(selector);
+        let block = match cursor.peek().map(|t| &t.kind) {
+            Some(&TokenKind::OpenCurly) => Some(block_contents(cursor.component_value())),
+            _ => {
+                cursor.next();
+                None
             }
+        };
 
-            self.consume_while(char::is_whitespace);
-            if self.chars.peek().map_or(false, |c| *c == ',') {
-                self.chars.next();
+        // @media is the only at-rule the engine applies; the others are
+        // reported and skipped.
+        let block = match name.as_ref() {
+            "media" => block.map(|tokens| self.parse_rule_list(tokens, false)),
+            // Stylesheets are always read as UTF-8.
+            "charset" => None,
+            _ => {
+                let at_keyword = &cursor.tokens[start - 1..];
+                self.error(at_keyword, 0, &format!("dropped rule: @{} is not supported", name));
+                None
             }
-        }
+        };
 
-        self.chars.next();
-        selectors
+        let media = match name.as_ref() {
+            "media" => self.parse_media_query_list(prelude_tokens),
+            _ => Vec::new(),
+        };
+
+        AtRule::new(name, prelude, block, media, 0)
     }
 
-    fn parse_selector(&mut self) -> Selector {
-        let mut sselector = SimpleSelector::default();
-        let mut selector = Selector::default();
+    // Queries that fail to parse turn into `not all` rather than taking the
+    // rest of the list down with them.
+    fn parse_media_query_list(&mut self, prelude: &[Token]) -> Vec<MediaQuery> {
+        if trim_whitespace(prelude).is_empty() {
+            return Vec::new();
+        }
 
-        self.consume_while(char::is_whitespace);
+        split_on(prelude, &TokenKind::Comma)
+            .into_iter()
+            .map(|tokens| match parse_media_query(trim_whitespace(tokens)) {
+                Ok(query) => query,
+                Err(e) => {
+                    self.error(tokens, 0, &format!("invalid media query: {}", e));
+                    MediaQuery::never()
+                }
+            })
+            .collect()
+    }
 
-        sselector.tag_name = match self.chars.peek() {
-            Some(&c) if is_valid_start_ident(c) => Some(self.parse_identifier()),
-            _ => None,
-        };
+    fn parse_qualified_rule(&mut self, cursor: &mut Cursor) -> Option<Rule> {
+        let start = cursor.pos;
 
-        let mut multiple_ids = false;
-        while self.chars
-            .peek()
-            .map_or(false, |c| *c != ',' && *c != '{' && !(*c).is_whitespace())
-        {
-            match self.chars.peek() {
-                Some(&c) if c == '#' => {
-                    self.chars.next();
-                    if sselector.id.is_some() || multiple_ids {
-                        sselector.id = None;
-                        multiple_ids = true;
-                        self.parse_id();
-                    } else {
-                        sselector.id = self.parse_id();
-                    }
+        loop {
+            match cursor.peek().map(|t| &t.kind) {
+                Some(&TokenKind::OpenCurly) => break,
+                Some(_) => {
+                    cursor.component_value();
+                }
+                None => {
+                    self.error(&cursor.tokens[start..], 0, "unexpected end of stylesheet in rule");
+                    return None;
                 }
-                Some(&c) if c == '.' => {
-                    self.chars.next();
-                    let class_name = self.parse_identifier();
+            }
+        }
+        let prelude = &cursor.tokens[start..cursor.pos];
+        let block = block_contents(cursor.component_value());
 
-                    if class_name != String::from("") {
-                        sselector.classes.push(class_name);
-                    }
+        match parse_selector_list(prelude) {
+            Ok(selectors) => Some(Rule::new(selectors, self.parse_declaration_list(block))),
+            Err(e) => {
+                self.error(prelude, 0, &format!("dropped rule: {}", e));
+                None
+            }
+        }
+    }
+
+    fn parse_declaration_list(&mut self, tokens: &[Token]) -> Vec<Declaration> {
+        let mut declarations = Vec::new();
+        let mut cursor = Cursor::new(tokens);
+
+        while let Some(token) = cursor.peek() {
+            match token.kind {
+                TokenKind::Whitespace | TokenKind::Semicolon => {
+                    cursor.next();
+                }
+                TokenKind::AtKeyword(_) => {
+                    self.parse_at_rule(&mut cursor);
                 }
                 _ => {
-                    self.consume_while(|c| c != ',' && c != '{');
+                    let start = cursor.pos;
+                    while cursor
+                        .peek()
+                        .map_or(false, |t| t.kind != TokenKind::Semicolon)
+                    {
+                        cursor.component_value();
+                    }
+
+                    declarations.extend(self.parse_declaration(&tokens[start..cursor.pos]));
                 }
             }
         }
 
-        if sselector != SimpleSelector::default() {
-            selector.simple.push(sselector);
+        declarations
+    }
+
+    // Shorthands come back as the longhands they stand for.
+    fn parse_declaration(&mut self, tokens: &[Token]) -> Vec<Declaration> {
+        let property = match tokens[0].kind {
+            TokenKind::Ident(ref name) => name.to_lowercase(),
+            _ => {
+                self.error(tokens, 0, "expected a property name");
+                return Vec::new();
+            }
+        };
+
+        let mut cursor = Cursor::new(tokens);
+        cursor.next();
+        cursor.skip_whitespace();
+        if cursor.next().map_or(true, |t| t.kind != TokenKind::Colon) {
+            self.error(tokens, 0, &format!("expected ':' after '{}'", property));
+            return Vec::new();
+        }
+
+        let (value, important) = split_important(trim_whitespace(&tokens[cursor.pos..]));
+        let bad_token = value.iter().find_map(|t| match t.kind {
+            TokenKind::BadString => Some("unterminated string"),
+            TokenKind::BadUrl => Some("invalid url"),
+            _ => None,
+        });
+        if let Some(reason) = bad_token {
+            self.error(tokens, 0, &format!("dropped declaration '{}': {}", property, reason));
+            return Vec::new();
+        }
+        if value.is_empty() {
+            self.error(tokens, 0, &format!("dropped declaration '{}': missing value", property));
+            return Vec::new();
+        }
+
+        let declarations = if css_shorthand::is_shorthand(&property) {
+            css_shorthand::expand(&property, &split_words(value), important)
+        } else {
+            parse_value(&property, serialize(value))
+                .map(|v| vec![Declaration::new(property.clone(), v, important)])
+        };
+
+        declarations.unwrap_or_else(|e| {
+            self.error(tokens, 0, &format!("dropped declaration '{}': {}", property, e));
+            Vec::new()
+        })
+    }
+
+    // Records an error at `tokens[i]`, or at the last token when `i` is past
+    // the end.
+    fn error(&mut self, tokens: &[Token], i: usize, message: &str) {
+        let (line, column) = match tokens.get(i).or_else(|| tokens.last()) {
+            Some(t) => (t.line, t.column),
+            None => (1, 1),
+        };
+
+        self.errors.push(ParseError {
+            line,
+            column,
+            message: String::from(message),
+        });
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+// A position in a run of tokens that steps over whole component values:
+// single tokens, or a block or function together with everything up to its
+// matching close.
+struct Cursor<'t> {
+    tokens: &'t [Token],
+    pos: usize,
+}
+
+impl<'t> Cursor<'t> {
+    fn new(tokens: &'t [Token]) -> Cursor<'t> {
+        Cursor { tokens, pos: 0 }
+    }
+
+    fn peek(&self) -> Option<&'t Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'t Token> {
+        let token = self.peek();
+        if token.is_some() {
+            self.pos += 1;
+        }
+        token
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().map_or(false, |t| t.kind == TokenKind::Whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn component_value(&mut self) -> &'t [Token] {
+        let start = self.pos;
+        let close = match self.next().map(|t| &t.kind) {
+            Some(&TokenKind::OpenCurly) => TokenKind::CloseCurly,
+            Some(&TokenKind::OpenSquare) => TokenKind::CloseSquare,
+            Some(&TokenKind::OpenParen) | Some(&TokenKind::Function(_)) => TokenKind::CloseParen,
+            _ => return &self.tokens[start..self.pos],
+        };
+
+        // An unclosed block runs to the end of the input.
+        while let Some(token) = self.peek() {
+            if token.kind == close {
+                self.pos += 1;
+                break;
+            }
+            self.component_value();
+        }
+        &self.tokens[start..self.pos]
+    }
+}
+
+// The tokens between a block's brackets; the closing one may be missing.
+fn block_contents(block: &[Token]) -> &[Token] {
+    let end = match block.last().map(|t| &t.kind) {
+        Some(&TokenKind::CloseCurly) | Some(&TokenKind::CloseSquare)
+        | Some(&TokenKind::CloseParen) if block.len() > 1 => block.len() - 1,
+        _ => block.len(),
+    };
+    &block[1.min(end)..end]
+}
+
+fn trim_whitespace(tokens: &[Token]) -> &[Token] {
+    let is_space = |t: &Token| t.kind == TokenKind::Whitespace;
+    let start = tokens.iter().position(|t| !is_space(t)).unwrap_or(tokens.len());
+    let end = tokens.iter().rposition(|t| !is_space(t)).map_or(start, |i| i + 1);
+
+    &tokens[start..end]
+}
+
+// Writes tokens back out as source text, with each run of whitespace and
+// comments reduced to a single space.
+fn serialize(tokens: &[Token]) -> String {
+    let mut text = String::new();
+
+    for token in trim_whitespace(tokens) {
+        match token.kind {
+            TokenKind::Whitespace => text.push(' '),
+            _ => text.push_str(&token.text),
+        }
+    }
+    text
+}
+
+// The space-separated parts of a value, as written; functions and blocks stay
+// whole, and other punctuation such as `/` or `,` is a part of its own.
+fn split_words(tokens: &[Token]) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut cursor = Cursor::new(tokens);
+
+    cursor.skip_whitespace();
+    while cursor.peek().is_some() {
+        words.push(serialize(cursor.component_value()));
+        cursor.skip_whitespace();
+    }
+    words
+}
+
+// Strips a trailing `!important`, which may have whitespace and comments
+// around the `!`.
+fn split_important(value: &[Token]) -> (&[Token], bool) {
+    let n = value.len();
+
+    if let Some(&TokenKind::Ident(ref name)) = value.last().map(|t| &t.kind) {
+        if name.eq_ignore_ascii_case("important") {
+            let rest = trim_whitespace(&value[..n - 1]);
+            if let Some(&TokenKind::Delim('!')) = rest.last().map(|t| &t.kind) {
+                return (trim_whitespace(&rest[..rest.len() - 1]), true);
+            }
+        }
+    }
+    (value, false)
+}
+
+// Splits at the separators that aren't nested inside a block or function.
+fn split_on<'t>(tokens: &'t [Token], separator: &TokenKind) -> Vec<&'t [Token]> {
+    let mut parts = Vec::new();
+    let mut cursor = Cursor::new(tokens);
+
+    loop {
+        let start = cursor.pos;
+        while cursor.peek().map_or(false, |t| t.kind != *separator) {
+            cursor.component_value();
+        }
+        parts.push(&tokens[start..cursor.pos]);
+
+        if cursor.next().is_none() {
+            break;
         }
+    }
+    parts
+}
+
+fn parse_selector_list(prelude: &[Token]) -> Result<Vec<Selector>, String> {
+    split_on(prelude, &TokenKind::Comma)
+        .into_iter()
+        .map(|tokens| parse_selector(trim_whitespace(tokens)))
+        .collect()
+}
 
-        selector
+// `[not | only] type [and (feature)]*` or `(feature) [and (feature)]*`.
+fn parse_media_query(tokens: &[Token]) -> Result<MediaQuery, String> {
+    let mut cursor = Cursor::new(tokens);
+    let mut words = Vec::new();
+    cursor.skip_whitespace();
+    while cursor.peek().is_some() {
+        words.push(cursor.component_value());
+        cursor.skip_whitespace();
     }
 
-    fn parse_identifier(&mut self) -> String {
-        let mut ident = String::new();
+    let ident = |word: Option<&&[Token]>| match word.map(|w| &w[0].kind) {
+        Some(&TokenKind::Ident(ref name)) => Some(name.to_lowercase()),
+        _ => None,
+    };
+    let mut words = words.into_iter().peekable();
+    let mut query = MediaQuery::new(false, MediaType::All, Vec::new());
 
-        match self.chars.peek() {
-            Some(&c) => if is_valid_start_ident(c) {
-                ident.push_str(&self.consume_while(is_valid_ident))
+    let mut needs_type = false;
+    match ident(words.peek()).as_ref().map(|s| s.as_str()) {
+        Some("not") => {
+            query.negated = true;
+            needs_type = true;
+            words.next();
+        }
+        Some("only") => {
+            needs_type = true;
+            words.next();
+        }
+        _ => {}
+    }
+
+    let mut needs_feature = false;
+    match ident(words.peek()) {
+        Some(ref name) if name != "and" => {
+            query.media_type = match name.as_ref() {
+                "all" => MediaType::All,
+                "screen" => MediaType::Screen,
+                "print" => MediaType::Print,
+                _ => MediaType::Other(name.clone()),
+            };
+            words.next();
+        }
+        _ if needs_type => return Err(String::from("expected a media type")),
+        _ => needs_feature = true,
+    }
+
+    loop {
+        if needs_feature {
+            match words.next() {
+                Some(word) if word[0].kind == TokenKind::OpenParen => {
+                    query.features.push(parse_media_feature(block_contents(word))?)
+                }
+                Some(word) => return Err(format!("expected '(', got '{}'", serialize(word))),
+                None => return Err(String::from("expected a media feature")),
+            }
+        }
+
+        match ident(words.peek()) {
+            Some(ref word) if word == "and" => {
+                words.next();
+                needs_feature = true;
+            }
+            _ => match words.next() {
+                Some(word) => return Err(format!("unexpected '{}'", serialize(word))),
+                None => break,
+            },
+        }
+    }
+
+    Ok(query)
+}
+
+fn parse_media_feature(tokens: &[Token]) -> Result<MediaFeature, String> {
+    let tokens = trim_whitespace(tokens);
+    let name = match tokens.first().map(|t| &t.kind) {
+        Some(&TokenKind::Ident(ref name)) => name.to_lowercase(),
+        _ => return Err(format!("expected a feature name in '({})'", serialize(tokens))),
+    };
+
+    let mut cursor = Cursor::new(tokens);
+    cursor.next();
+    cursor.skip_whitespace();
+    match cursor.next().map(|t| &t.kind) {
+        Some(&TokenKind::Colon) => {}
+        // Boolean and range forms aren't supported.
+        _ => return Ok(MediaFeature::Unknown(name)),
+    }
+    let value = serialize(&tokens[cursor.pos..]).to_lowercase();
+
+    let length = |value: &str| match translate_length(value)? {
+        Value::Percentage(_) => Err(format!("'{}' is not a length", value)),
+        v => Ok(v),
+    };
+
+    match name.as_ref() {
+        "min-width" => Ok(MediaFeature::MinWidth(length(&value)?)),
+        "max-width" => Ok(MediaFeature::MaxWidth(length(&value)?)),
+        "min-height" => Ok(MediaFeature::MinHeight(length(&value)?)),
+        "max-height" => Ok(MediaFeature::MaxHeight(length(&value)?)),
+        "orientation" => match value.as_ref() {
+            "portrait" | "landscape" => Ok(MediaFeature::Orientation(value)),
+            _ => Err(format!("unknown orientation '{}'", value)),
+        },
+        "prefers-color-scheme" => match value.as_ref() {
+            "light" | "dark" => Ok(MediaFeature::PrefersColorScheme(value)),
+            _ => Err(format!("unknown color scheme '{}'", value)),
+        },
+        _ => Ok(MediaFeature::Unknown(name)),
+    }
+}
+
+fn parse_selector(tokens: &[Token]) -> Result<Selector, String> {
+    let mut selector = Selector::default();
+    let mut cursor = Cursor::new(tokens);
+
+    loop {
+        selector.simple.push(parse_simple_selector(&mut cursor)?);
+
+        // A pseudo-element ends the selector.
+        if starts_pseudo_element(&cursor) {
+            selector.pseudo_element = Some(parse_pseudo_element(&mut cursor)?);
+            match cursor.peek() {
+                Some(t) => return Err(format!("unexpected '{}' after a pseudo-element", t.text)),
+                None => break,
+            }
+        }
+
+        let whitespace = cursor.peek().map_or(false, |t| t.kind == TokenKind::Whitespace);
+        cursor.skip_whitespace();
+
+        let combinator = match cursor.peek().map(|t| &t.kind) {
+            None => break,
+            Some(&TokenKind::Delim(c)) if is_combinator(c) => {
+                cursor.next();
+                cursor.skip_whitespace();
+                c
+            }
+            Some(_) if whitespace => ' ',
+            Some(_) => return Err(unexpected(cursor.peek())),
+        };
+        selector.combinators.push(combinator);
+    }
+
+    Ok(selector)
+}
+
+fn parse_simple_selector(cursor: &mut Cursor) -> Result<SimpleSelector, String> {
+    let mut sselector = SimpleSelector::default();
+
+    match cursor.peek().map(|t| &t.kind) {
+        Some(&TokenKind::Ident(ref name)) => {
+            sselector.tag_name = Some(name.to_lowercase());
+            cursor.next();
+        }
+        Some(&TokenKind::Delim('*')) => {
+            sselector.universal = true;
+            cursor.next();
+        }
+        _ => {}
+    }
+
+    while let Some(token) = cursor.peek() {
+        match token.kind {
+            // Whether a hash names an id depends on how it was written, so
+            // `#\31 0` is the id "10" while `#10` is no id at all.
+            TokenKind::Hash(ref name) if starts_identifier(&token.text[1..]) => {
+                sselector.ids.push(name.clone());
+            }
+            TokenKind::Delim('.') => match cursor.tokens.get(cursor.pos + 1).map(|t| &t.kind) {
+                Some(&TokenKind::Ident(ref class)) => {
+                    sselector.classes.push(class.clone());
+                    cursor.next();
+                }
+                _ => return Err(String::from("expected a class name after '.'")),
             },
-            None => {}
+            TokenKind::OpenSquare => {
+                let block = block_contents(cursor.component_value());
+                sselector.attributes.push(parse_attribute_selector(block)?);
+                continue;
+            }
+            TokenKind::Colon if starts_pseudo_element(cursor) => break,
+            TokenKind::Colon => {
+                cursor.next();
+                sselector.pseudo_classes.push(parse_pseudo_class(cursor)?);
+                continue;
+            }
+            TokenKind::Whitespace | TokenKind::Delim('>') | TokenKind::Delim('+')
+            | TokenKind::Delim('~') => break,
+            _ => return Err(unexpected(Some(token))),
         }
+        cursor.next();
+    }
 
-        ident.to_lowercase()
+    // `::before` on its own applies to every element.
+    if sselector == SimpleSelector::default() && !starts_pseudo_element(cursor) {
+        return Err(unexpected(cursor.peek()));
     }
+    Ok(sselector)
+}
 
-    fn parse_id(&mut self) -> Option<String> {
-        match &self.parse_identifier()[..] {
-            "" => None,
-            s @ _ => Some(s.to_string()),
+// `::name`, or `:before` and `:after`, which CSS 2 wrote with one colon.
+fn starts_pseudo_element(cursor: &Cursor) -> bool {
+    let kind = |offset: usize| cursor.tokens.get(cursor.pos + offset).map(|t| &t.kind);
+
+    match (kind(0), kind(1)) {
+        (Some(&TokenKind::Colon), Some(&TokenKind::Colon)) => true,
+        (Some(&TokenKind::Colon), Some(&TokenKind::Ident(ref name))) => {
+            name.eq_ignore_ascii_case("before") || name.eq_ignore_ascii_case("after")
         }
+        _ => false,
+    }
+}
+
+fn parse_pseudo_element(cursor: &mut Cursor) -> Result<PseudoElement, String> {
+    cursor.next();
+    if cursor.peek().map_or(false, |t| t.kind == TokenKind::Colon) {
+        cursor.next();
     }
 
-    fn parse_declarations(&mut self) -> Vec<Declaration> {
-        let mut declarations = Vec::<Declaration>::new();
+    match cursor.next() {
+        Some(token) => match token.kind {
+            TokenKind::Ident(ref name) if name.eq_ignore_ascii_case("before") => {
+                Ok(PseudoElement::Before)
+            }
+            TokenKind::Ident(ref name) if name.eq_ignore_ascii_case("after") => {
+                Ok(PseudoElement::After)
+            }
+            _ => Err(format!("unknown pseudo-element '::{}'", token.text)),
+        },
+        None => Err(String::from("expected a pseudo-element after '::'")),
+    }
+}
 
-        while self.chars.peek().map_or(false, |c| *c != '}') {
-            self.consume_while(char::is_whitespace);
+// `name`, or `name op value` with an optional `i` or `s` flag, where the value
+// is an identifier or a string.
+fn parse_attribute_selector(tokens: &[Token]) -> Result<AttributeSelector, String> {
+    let mut cursor = Cursor::new(tokens);
+    let kind = |token: Option<&Token>| token.map(|t| t.kind.clone());
+    let invalid = || format!("invalid attribute selector '[{}]'", serialize(tokens));
 
-            let property = self.consume_while(|x| x != ':').to_lowercase();
+    cursor.skip_whitespace();
+    // Attribute names are lowercased by the HTML parser.
+    let name = match kind(cursor.next()) {
+        Some(TokenKind::Ident(name)) => name.to_lowercase(),
+        _ => return Err(invalid()),
+    };
+    cursor.skip_whitespace();
 
-            self.chars.next();
-            self.consume_while(char::is_whitespace);
+    let operator = match kind(cursor.next()) {
+        None => return Ok(AttributeSelector::new(name, AttrOperator::Exists, String::new(), false)),
+        Some(TokenKind::Delim('=')) => AttrOperator::Equals,
+        Some(TokenKind::Delim(c)) if kind(cursor.peek()) == Some(TokenKind::Delim('=')) => {
+            cursor.next();
+            match c {
+                '~' => AttrOperator::Includes,
+                '|' => AttrOperator::DashMatch,
+                '^' => AttrOperator::Prefix,
+                '$' => AttrOperator::Suffix,
+                '*' => AttrOperator::Substring,
+                _ => return Err(invalid()),
+            }
+        }
+        _ => return Err(invalid()),
+    };
+    cursor.skip_whitespace();
 
-            let value = self.consume_while(|x| x != ';' && x != '\n' && x != '}')
-                .to_lowercase();
+    let value = match kind(cursor.next()) {
+        Some(TokenKind::Ident(value)) | Some(TokenKind::String(value)) => value,
+        _ => return Err(invalid()),
+    };
+    cursor.skip_whitespace();
 
-            let value_enum = match property.as_ref() {
-                "background-color" | "border-color" | "color" => {
-                    Value::Color(translate_color(&value))
+    let case_insensitive = match kind(cursor.next()) {
+        None => false,
+        Some(TokenKind::Ident(ref flag)) if flag.eq_ignore_ascii_case("i") => true,
+        Some(TokenKind::Ident(ref flag)) if flag.eq_ignore_ascii_case("s") => false,
+        _ => return Err(invalid()),
+    };
+    cursor.skip_whitespace();
+    if cursor.peek().is_some() {
+        return Err(invalid());
+    }
+
+    Ok(AttributeSelector::new(name, operator, value, case_insensitive))
+}
+
+// Reads the pseudo-class after a colon.
+fn parse_pseudo_class(cursor: &mut Cursor) -> Result<PseudoClass, String> {
+    let token = match cursor.peek() {
+        Some(token) => token,
+        None => return Err(String::from("expected a pseudo-class after ':'")),
+    };
+
+    match token.kind {
+        TokenKind::Ident(ref name) => {
+            cursor.next();
+            match name.to_lowercase().as_ref() {
+                "root" => Ok(PseudoClass::Root),
+                "empty" => Ok(PseudoClass::Empty),
+                "first-child" => Ok(PseudoClass::FirstChild),
+                "last-child" => Ok(PseudoClass::LastChild),
+                "only-child" => Ok(PseudoClass::OnlyChild),
+                "first-of-type" => Ok(PseudoClass::FirstOfType),
+                "last-of-type" => Ok(PseudoClass::LastOfType),
+                "only-of-type" => Ok(PseudoClass::OnlyOfType),
+                _ => Err(format!("unknown pseudo-class ':{}'", name)),
+            }
+        }
+        TokenKind::Function(ref name) => {
+            let args = trim_whitespace(block_contents(cursor.component_value()));
+
+            match name.to_lowercase().as_ref() {
+                "nth-child" => parse_nth(args).map(|(a, b)| PseudoClass::NthChild(a, b)),
+                "nth-last-child" => parse_nth(args).map(|(a, b)| PseudoClass::NthLastChild(a, b)),
+                "nth-of-type" => parse_nth(args).map(|(a, b)| PseudoClass::NthOfType(a, b)),
+                "nth-last-of-type" => {
+                    parse_nth(args).map(|(a, b)| PseudoClass::NthLastOfType(a, b))
                 }
-                "margin-right" |
-                "margin-bottom" |
-                "margin-left" |
-                "margin-top" |
-                "padding-right" |
-                "padding-bottom" |
-                "padding-left" |
-                "padding-top" |
-                "border-right-width" |
-                "border-bottom-width" |
-                "border-left-width" |
-                "border-top-width" |
-                "height" |
-                "width" => translate_length(&value),
-                _ => Value::Other(value),
+                "not" | "is" | "where" => {
+                    let list = parse_selector_list(args)?;
+                    if list.iter().any(|s| s.pseudo_element.is_some()) {
+                        return Err(format!("':{}()' can't hold a pseudo-element", name));
+                    }
+
+                    match name.to_lowercase().as_ref() {
+                        "not" => Ok(PseudoClass::Not(list)),
+                        "is" => Ok(PseudoClass::Is(list)),
+                        _ => Ok(PseudoClass::Where(list)),
+                    }
+                }
+                _ => Err(format!("unknown pseudo-class ':{}()'", name)),
+            }
+        }
+        _ => Err(format!("unexpected '{}' after ':'", token.text)),
+    }
+}
+
+// The An+B microsyntax: `odd`, `even`, an integer B, or A followed by `n`
+// and an optional signed B, where whitespace may only surround that sign.
+fn parse_nth(tokens: &[Token]) -> Result<(i32, i32), String> {
+    let text = serialize(tokens).to_lowercase();
+    let invalid = || format!("invalid An+B '{}'", text);
+    let integer = |s: &str| match s.chars().all(|c| c.is_ascii_digit()) && !s.is_empty() {
+        true => s.parse::<i32>().ok(),
+        false => None,
+    };
+    let signed = |s: &str| match s.chars().next() {
+        Some('-') => integer(&s[1..]).map(|n| -n),
+        Some('+') => integer(&s[1..]),
+        _ => integer(s),
+    };
+
+    let n = match text.as_ref() {
+        "odd" => return Ok((2, 1)),
+        "even" => return Ok((2, 0)),
+        _ => match text.find('n') {
+            Some(n) => n,
+            None => return signed(&text).map(|b| (0, b)).ok_or_else(invalid),
+        },
+    };
+
+    let a = match &text[..n] {
+        "" | "+" => 1,
+        "-" => -1,
+        a => signed(a).ok_or_else(invalid)?,
+    };
+
+    let rest = text[n + 1..].trim_start();
+    let b = match rest.chars().next() {
+        None => 0,
+        Some('+') => integer(rest[1..].trim_start()).ok_or_else(invalid)?,
+        Some('-') => -integer(rest[1..].trim_start()).ok_or_else(invalid)?,
+        Some(_) => return Err(invalid()),
+    };
+    Ok((a, b))
+}
+
+fn unexpected(token: Option<&Token>) -> String {
+    match token {
+        Some(t) => format!("unexpected '{}' in selector", t.text),
+        None => String::from("selector is empty"),
+    }
+}
+
+pub const FONT_SIZE_KEYWORDS: &'static [&'static str] = &[
+    "xx-small", "x-small", "small", "medium", "large", "x-large", "xx-large", "xxx-large",
+    "larger", "smaller",
+];
+
+// Parses the value of a longhand: checks it against what the property
+// accepts, and resolves the keywords that stand for a length. Keywords are
+// matched without regard to case, while strings, urls and names keep theirs.
+pub fn parse_value(property: &str, value: String) -> Result<Value, String> {
+    let tokens = Tokenizer::new(&value).tokenize();
+    let typed = translate_value(&tokens)?;
+    let keyword = match typed {
+        Value::Keyword(ref k) => k.clone(),
+        _ => String::new(),
+    };
+
+    match property {
+        _ if is_css_wide_keyword(&keyword) => Ok(typed),
+        "background-color" |
+        "border-top-color" |
+        "border-right-color" |
+        "border-bottom-color" |
+        "border-left-color" |
+        "color" => match keyword.as_ref() {
+            "currentcolor" => Ok(typed),
+            _ => translate_color(&value).map(Value::Color),
+        },
+        "border-top-style" |
+        "border-right-style" |
+        "border-bottom-style" |
+        "border-left-style" => match css_shorthand::is_border_style(&keyword) {
+            true => Ok(typed),
+            false => Err(format!("unknown border style '{}'", value)),
+        },
+        "border-right-width" |
+        "border-bottom-width" |
+        "border-left-width" |
+        "border-top-width" => match keyword.as_ref() {
+            "thin" => Ok(Value::Length(1.0, Unit::Px)),
+            "medium" => Ok(Value::Length(3.0, Unit::Px)),
+            "thick" => Ok(Value::Length(5.0, Unit::Px)),
+            _ => length(typed, &value).and_then(|v| non_negative(v, &value)),
+        },
+        "font-family" => font_family(&tokens),
+        "content" => content(&tokens),
+        "counter-reset" => counter_list(&tokens, 0.0),
+        "counter-increment" => counter_list(&tokens, 1.0),
+        // A bare number is a multiple of the font size.
+        "line-height" => match typed {
+            Value::Keyword(_) => Ok(typed),
+            Value::Number(n) if n >= 0.0 => Ok(typed),
+            _ => length(typed, &value).and_then(|v| non_negative(v, &value)),
+        },
+        "padding-right" |
+        "padding-bottom" |
+        "padding-left" |
+        "padding-top" => length_or(typed, &value, &[]).and_then(|v| non_negative(v, &value)),
+        "font-size" => {
+            length_or(typed, &value, FONT_SIZE_KEYWORDS).and_then(|v| non_negative(v, &value))
+        }
+        "height" |
+        "width" => length_or(typed, &value, &["auto"]).and_then(|v| non_negative(v, &value)),
+        "margin-right" |
+        "margin-bottom" |
+        "margin-left" |
+        "margin-top" => length_or(typed, &value, &["auto"]),
+        "text-indent" => length_or(typed, &value, &[]),
+        "letter-spacing" |
+        "word-spacing" => length_or(typed, &value, &["normal"]),
+        _ => Ok(typed),
+    }
+}
+
+// Turns the tokens of a value into a typed value: a single component, or a
+// list of them split at commas first, then at slashes, then at whitespace.
+fn translate_value(tokens: &[Token]) -> Result<Value, String> {
+    let tokens = trim_whitespace(tokens);
+
+    for separator in &[TokenKind::Comma, TokenKind::Delim('/')] {
+        let parts = split_on(tokens, separator);
+        if parts.len() > 1 {
+            let c = match *separator {
+                TokenKind::Delim(c) => c,
+                _ => ',',
             };
+            return translate_list(parts, c);
+        }
+    }
+
+    let mut words = Vec::new();
+    let mut cursor = Cursor::new(tokens);
+    while cursor.peek().is_some() {
+        words.push(cursor.component_value());
+        cursor.skip_whitespace();
+    }
 
-            let declaration = Declaration::new(property, value_enum);
+    match words.len() {
+        0 => Err(String::from("value is empty")),
+        1 => translate_component(words[0]),
+        _ => translate_list(words, ' '),
+    }
+}
 
-            if self.chars.peek().map_or(false, |c| *c == ';') {
-                declarations.push(declaration);
-                self.chars.next();
-            } else {
-                self.consume_while(char::is_whitespace);
-                if self.chars.peek().map_or(false, |c| *c == '}') {
-                    declarations.push(declaration);
+fn translate_list(parts: Vec<&[Token]>, separator: char) -> Result<Value, String> {
+    let values = parts
+        .into_iter()
+        .map(translate_value)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Value::List(values, separator))
+}
+
+// A single token, or a function together with its arguments.
+fn translate_component(tokens: &[Token]) -> Result<Value, String> {
+    match tokens[0].kind {
+        TokenKind::Ident(ref name) => Ok(Value::Keyword(name.to_lowercase())),
+        TokenKind::Number(n) => Ok(Value::Number(n)),
+        TokenKind::Percentage(n) => Ok(Value::Percentage(n)),
+        TokenKind::Dimension(n, ref unit) => match length_unit(&unit.to_lowercase()) {
+            Some(unit) => Ok(Value::Length(n, unit)),
+            None => Err(format!("unknown unit '{}' in '{}'", unit, tokens[0].text)),
+        },
+        TokenKind::String(ref s) => Ok(Value::String(s.clone())),
+        TokenKind::Url(ref url) => Ok(Value::Url(url.clone())),
+        TokenKind::Hash(ref hex) => translate_hex_color(hex).map(Value::Color),
+        TokenKind::Function(ref name) => {
+            let name = name.to_lowercase();
+            let contents = trim_whitespace(block_contents(tokens));
+
+            match name.as_ref() {
+                "rgb" | "rgba" | "hsl" | "hsla" => {
+                    translate_color(&serialize(tokens)).map(Value::Color)
+                }
+                _ if contents.is_empty() => Ok(Value::Function(name, Vec::new())),
+                _ => {
+                    let args = split_on(contents, &TokenKind::Comma)
+                        .into_iter()
+                        .map(translate_value)
+                        .collect::<Result<Vec<_>, _>>()?;
+
+                    match (name.as_ref(), args.as_slice()) {
+                        ("url", &[Value::String(ref url)]) => Ok(Value::Url(url.clone())),
+                        _ => Ok(Value::Function(name, args)),
+                    }
                 }
             }
-            self.consume_while(char::is_whitespace);
         }
+        _ => Err(format!("unexpected '{}' in value", serialize(tokens))),
+    }
+}
 
-        self.chars.next();
-        declarations
+// A comma-separated list of family names, each either quoted or a run of
+// identifiers, or generic families, which are keywords.
+fn font_family(tokens: &[Token]) -> Result<Value, String> {
+    let mut families = Vec::new();
+
+    for part in split_on(trim_whitespace(tokens), &TokenKind::Comma) {
+        let part = trim_whitespace(part);
+        let mut names = Vec::new();
+
+        for token in part {
+            match token.kind {
+                TokenKind::String(ref s) if part.len() == 1 => {
+                    families.push(Value::String(s.clone()))
+                }
+                TokenKind::Ident(ref name) => names.push(name.clone()),
+                TokenKind::Whitespace => {}
+                _ => return Err(format!("invalid font family '{}'", serialize(part))),
+            }
+        }
+
+        match names.len() {
+            0 if part.is_empty() => return Err(String::from("expected a font family")),
+            0 => {}
+            1 if is_generic_family(&names[0].to_lowercase()) => {
+                families.push(Value::Keyword(names[0].to_lowercase()))
+            }
+            _ => families.push(Value::String(names.join(" "))),
+        }
+    }
+
+    match families.len() {
+        1 => Ok(families.remove(0)),
+        _ => Ok(Value::List(families, ',')),
+    }
+}
+
+// `normal`, `none`, or a list of strings, `attr()`, `counter()`, `counters()`
+// and quote keywords.
+fn content(tokens: &[Token]) -> Result<Value, String> {
+    let mut items = Vec::new();
+    let mut cursor = Cursor::new(tokens);
+
+    cursor.skip_whitespace();
+    while cursor.peek().is_some() {
+        let component = cursor.component_value();
+        let item = match component[0].kind {
+            TokenKind::String(ref s) => Value::String(s.clone()),
+            TokenKind::Ident(ref name) => match name.to_lowercase().as_ref() {
+                keyword @ "normal" | keyword @ "none" | keyword @ "open-quote"
+                | keyword @ "close-quote" | keyword @ "no-open-quote"
+                | keyword @ "no-close-quote" => Value::Keyword(String::from(keyword)),
+                _ => return Err(format!("unexpected '{}' in content", name)),
+            },
+            TokenKind::Function(ref name) => content_function(name, block_contents(component))?,
+            _ => return Err(format!("unexpected '{}' in content", serialize(component))),
+        };
+        items.push(item);
+        cursor.skip_whitespace();
+    }
+
+    let is_alone = |v: &Value| *v == Value::Keyword(String::from("normal"))
+        || *v == Value::Keyword(String::from("none"));
+    if items.len() > 1 && items.iter().any(is_alone) {
+        return Err(String::from("'normal' and 'none' must stand alone in content"));
     }
 
+    match items.len() {
+        0 => Err(String::from("content is empty")),
+        1 => Ok(items.remove(0)),
+        _ => Ok(Value::List(items, ' ')),
+    }
+}
+
+// `attr(name)`, `counter(name[, style])` and `counters(name, separator[,
+// style])`. Counter names are keywords that keep their case.
+fn content_function(name: &str, args: &[Token]) -> Result<Value, String> {
+    let name = name.to_lowercase();
+    let args: Vec<&[Token]> = match trim_whitespace(args).is_empty() {
+        true => Vec::new(),
+        false => split_on(args, &TokenKind::Comma)
+            .into_iter()
+            .map(trim_whitespace)
+            .collect(),
+    };
+    let ident = |arg: &[Token]| match arg.first().map(|t| &t.kind) {
+        Some(&TokenKind::Ident(ref s)) if arg.len() == 1 => Some(s.clone()),
+        _ => None,
+    };
+    let string = |arg: &[Token]| match arg.first().map(|t| &t.kind) {
+        Some(&TokenKind::String(ref s)) if arg.len() == 1 => Some(s.clone()),
+        _ => None,
+    };
+    let style = |arg: Option<&&[Token]>| match arg {
+        Some(arg) => ident(arg).map(|s| Value::Keyword(s.to_lowercase())),
+        None => Some(Value::Keyword(String::from("decimal"))),
+    };
+    let invalid = || format!("invalid '{}()' in content", name);
 
-    fn consume_while<F>(&mut self, condition: F) -> String
-    where
-        F: Fn(char) -> bool,
-    {
-        let mut result = String::new();
-        while self.chars.peek().map_or(false, |c| condition(*c)) {
-            result.push(self.chars.next().unwrap());
+    let args = match (name.as_ref(), args.len()) {
+        ("attr", 1) => vec![Value::Keyword(ident(args[0]).ok_or_else(invalid)?.to_lowercase())],
+        ("counter", 1) | ("counter", 2) => vec![
+            Value::Keyword(ident(args[0]).ok_or_else(invalid)?),
+            style(args.get(1)).ok_or_else(invalid)?,
+        ],
+        ("counters", 2) | ("counters", 3) => vec![
+            Value::Keyword(ident(args[0]).ok_or_else(invalid)?),
+            Value::String(string(args[1]).ok_or_else(invalid)?),
+            style(args.get(2)).ok_or_else(invalid)?,
+        ],
+        _ => return Err(invalid()),
+    };
+    Ok(Value::Function(name, args))
+}
+
+// `none`, or counter names each followed by an optional integer, which
+// defaults to `default`. Names are keywords that keep their case.
+fn counter_list(tokens: &[Token], default: f32) -> Result<Value, String> {
+    let tokens = trim_whitespace(tokens);
+    let invalid = || format!("invalid counter list '{}'", serialize(tokens));
+
+    if let [Token { kind: TokenKind::Ident(ref name), .. }] = *tokens {
+        if name.eq_ignore_ascii_case("none") {
+            return Ok(Value::Keyword(String::from("none")));
+        }
+    }
+
+    let mut values = Vec::new();
+    let mut named = false;
+    for token in tokens {
+        match token.kind {
+            TokenKind::Whitespace => {}
+            TokenKind::Ident(ref name) => {
+                if name.eq_ignore_ascii_case("none") || is_css_wide_keyword(&name.to_lowercase()) {
+                    return Err(invalid());
+                }
+                values.push(Value::Keyword(name.clone()));
+                values.push(Value::Number(default));
+                named = true;
+            }
+            TokenKind::Number(n) if named && n.fract() == 0.0 => {
+                values.pop();
+                values.push(Value::Number(n));
+                named = false;
+            }
+            _ => return Err(invalid()),
         }
+    }
+    Ok(Value::List(values, ' '))
+}
 
-        result
+fn is_generic_family(name: &str) -> bool {
+    match name {
+        "serif" | "sans-serif" | "monospace" | "cursive" | "fantasy" | "system-ui" => true,
+        _ => false,
     }
 }
 
-fn translate_length(value: &str) -> Value {
-    let mut num_str = String::new();
-    let mut unit = String::new();
-    let mut parsing_num = true;
+// Lengths and percentages; a bare number only when it is zero.
+// A length, one of the `keywords` the property allows, or a calc() that is
+// kept as written.
+fn length_or(value: Value, text: &str, keywords: &[&str]) -> Result<Value, String> {
+    match value {
+        Value::Keyword(ref k) if keywords.contains(&k.as_str()) => Ok(value),
+        Value::Function(ref name, _) if name == "calc" => Ok(value),
+        _ => length(value, text),
+    }
+}
 
-    for c in value.chars() {
-        if c.is_numeric() && parsing_num {
-            num_str.push(c);
-        } else {
-            unit.push(c);
-            parsing_num = false;
+fn length(value: Value, text: &str) -> Result<Value, String> {
+    match value {
+        Value::Length(..) | Value::Percentage(_) => Ok(value),
+        Value::Number(n) if n == 0.0 => Ok(Value::Length(0.0, Unit::Px)),
+        Value::Number(_) => Err(format!("length '{}' is missing a unit", text)),
+        _ => Err(format!("expected a length, got '{}'", text)),
+    }
+}
+
+pub fn is_css_wide_keyword(value: &str) -> bool {
+    match value {
+        "inherit" | "initial" | "unset" => true,
+        _ => false,
+    }
+}
+
+// Splits a CSS number off the front of `input`: an optional sign, digits with
+// an optional fraction (at least one digit overall) and an optional exponent.
+// Returns the number and whatever follows it.
+pub fn parse_number(input: &str) -> Option<(f32, &str)> {
+    let bytes = input.as_bytes();
+    let digits_from = |start: usize| {
+        bytes[start..]
+            .iter()
+            .take_while(|b| b.is_ascii_digit())
+            .count()
+    };
+
+    let mut end = match bytes.first() {
+        Some(&b'+') | Some(&b'-') => 1,
+        _ => 0,
+    };
+
+    let integer_digits = digits_from(end);
+    end += integer_digits;
+
+    let mut fraction_digits = 0;
+    if bytes.get(end) == Some(&b'.') {
+        fraction_digits = digits_from(end + 1);
+        if fraction_digits > 0 {
+            end += 1 + fraction_digits;
         }
     }
 
-    let number = num_str.parse().unwrap_or(0.0);
+    if integer_digits + fraction_digits == 0 {
+        return None;
+    }
 
-    match unit.as_ref() {
-        "em" => Value::Length(number, Unit::Em),
-        "ex" => Value::Length(number, Unit::Ex),
-        "ch" => Value::Length(number, Unit::Ch),
-        "rem" => Value::Length(number, Unit::Rem),
-        "vh" => Value::Length(number, Unit::Vh),
-        "vw" => Value::Length(number, Unit::Vw),
-        "vmin" => Value::Length(number, Unit::Vmin),
-        "vmax" => Value::Length(number, Unit::Vmax),
-        "px" | "" => Value::Length(number, Unit::Px),
-        "mm" => Value::Length(number, Unit::Mm),
-        "q" => Value::Length(number, Unit::Q),
-        "cm" => Value::Length(number, Unit::Cm),
-        "in" => Value::Length(number, Unit::In),
-        "pt" => Value::Length(number, Unit::Pt),
-        "pc" => Value::Length(number, Unit::Pc),
-        "%" => Value::Length(number, Unit::Pct),
-        _ => Value::Length(number, Unit::Px),
+    // An exponent only counts when digits follow, so `2em` stays 2 with unit em.
+    if let Some(&b'e') | Some(&b'E') = bytes.get(end) {
+        let sign = match bytes.get(end + 1) {
+            Some(&b'+') | Some(&b'-') => 1,
+            _ => 0,
+        };
+        let exponent_digits = digits_from(end + 1 + sign);
+        if exponent_digits > 0 {
+            end += 1 + sign + exponent_digits;
+        }
     }
+
+    input[..end].parse().ok().map(|n| (n, &input[end..]))
 }
 
+pub fn translate_length(value: &str) -> Result<Value, String> {
+    let (number, unit) = match parse_number(value) {
+        Some(n) => n,
+        None => return Err(format!("expected a length, got '{}'", value)),
+    };
 
-fn translate_color(color: &str) -> Color {
-    if color.starts_with("#") {
-        if color.len() == 7 {
-            let red = match u8::from_str_radix(&color[1..3], 16) {
-                Ok(n) => n as f32 / 255.0,
-                Err(_) => 0.0,
-            };
-            let green = match u8::from_str_radix(&color[3..5], 16) {
-                Ok(n) => n as f32 / 255.0,
-                Err(_) => 0.0,
-            };
-            let blue = match u8::from_str_radix(&color[5..7], 16) {
-                Ok(n) => n as f32 / 255.0,
-                Err(_) => 0.0,
-            };
-            return Color::new(red, green, blue, 1.0);
-        } else if color.len() == 4 {
-            let red = match u8::from_str_radix(&color[1..2], 16) {
-                Ok(n) => n as f32 / 15.0,
-                Err(_) => 0.0,
-            };
-            let green = match u8::from_str_radix(&color[2..3], 16) {
-                Ok(n) => n as f32 / 15.0,
-                Err(_) => 0.0,
-            };
-            let blue = match u8::from_str_radix(&color[3..4], 16) {
-                Ok(n) => n as f32 / 15.0,
-                Err(_) => 0.0,
-            };
-            return Color::new(red, green, blue, 1.0);
-        } else {
-            return Color::default();
+    match unit.to_lowercase().as_ref() {
+        "%" => Ok(Value::Percentage(number)),
+        // Only zero may drop its unit.
+        "" if number == 0.0 => Ok(Value::Length(0.0, Unit::Px)),
+        "" => Err(format!("length '{}' is missing a unit", value)),
+        u => match length_unit(u) {
+            Some(unit) => Ok(Value::Length(number, unit)),
+            None => Err(format!("unknown unit '{}' in '{}'", u, value)),
+        },
+    }
+}
+
+fn length_unit(name: &str) -> Option<Unit> {
+    match name {
+        "em" => Some(Unit::Em),
+        "ex" => Some(Unit::Ex),
+        "ch" => Some(Unit::Ch),
+        "rem" => Some(Unit::Rem),
+        "vh" => Some(Unit::Vh),
+        "vw" => Some(Unit::Vw),
+        "vmin" => Some(Unit::Vmin),
+        "vmax" => Some(Unit::Vmax),
+        "px" => Some(Unit::Px),
+        "mm" => Some(Unit::Mm),
+        "q" => Some(Unit::Q),
+        "cm" => Some(Unit::Cm),
+        "in" => Some(Unit::In),
+        "pt" => Some(Unit::Pt),
+        "pc" => Some(Unit::Pc),
+        _ => None,
+    }
+}
+
+fn non_negative(length: Value, value: &str) -> Result<Value, String> {
+    match length {
+        Value::Length(n, _) | Value::Percentage(n) if n < 0.0 => {
+            Err(format!("'{}' may not be negative", value))
         }
-    } else if color.starts_with("rgb") {
-        return Color::default();
-    } else if color.starts_with("hsl") {
-        return Color::default();
+        _ => Ok(length),
+    }
+}
+
+
+// Color keywords, function names and hex digits all ignore case.
+pub fn translate_color(color: &str) -> Result<Color, String> {
+    let color = &color.to_lowercase();
+
+    if color.starts_with('#') {
+        return translate_hex_color(&color[1..]);
+    }
+
+    if let Some(open) = color.find('(') {
+        if !color.ends_with(')') {
+            return Err(format!("unterminated color function '{}'", color));
+        }
+        let args = split_color_args(&color[open + 1..color.len() - 1])?;
+
+        return match color[..open].trim() {
+            "rgb" | "rgba" => rgb_function(&args),
+            "hsl" | "hsla" => hsl_function(&args),
+            name => Err(format!("unknown color function '{}'", name)),
+        };
+    }
+
+    named_color(color).ok_or_else(|| format!("unknown color '{}'", color))
+}
+
+// #rgb, #rgba, #rrggbb and #rrggbbaa.
+fn translate_hex_color(hex: &str) -> Result<Color, String> {
+    if !hex.chars().all(|c| c.is_digit(16)) {
+        return Err(format!("invalid hex color '#{}'", hex));
+    }
+
+    let digit = |i: usize, width: usize| {
+        let n = u8::from_str_radix(&hex[i * width..(i + 1) * width], 16).unwrap_or(0);
+        match width {
+            1 => n as f32 / 15.0,
+            _ => n as f32 / 255.0,
+        }
+    };
+
+    match hex.len() {
+        3 => Ok(Color::new(digit(0, 1), digit(1, 1), digit(2, 1), 1.0)),
+        4 => Ok(Color::new(digit(0, 1), digit(1, 1), digit(2, 1), digit(3, 1))),
+        6 => Ok(Color::new(digit(0, 2), digit(1, 2), digit(2, 2), 1.0)),
+        8 => Ok(Color::new(digit(0, 2), digit(1, 2), digit(2, 2), digit(3, 2))),
+        _ => Err(format!("invalid hex color '#{}'", hex)),
+    }
+}
+
+struct ColorArgs<'a> {
+    channels: Vec<&'a str>,
+    alpha: Option<&'a str>,
+}
+
+// Accepts both the legacy comma form, `rgba(255, 0, 0, 0.5)`, and the
+// space-separated form with an optional slash before the alpha,
+// `rgb(255 0 0 / 50%)`.
+fn split_color_args<'a>(args: &'a str) -> Result<ColorArgs<'a>, String> {
+    let (channels, alpha): (Vec<&str>, Option<&str>) = if args.contains(',') {
+        let mut parts: Vec<&str> = args.split(',').map(str::trim).collect();
+        let alpha = if parts.len() == 4 { parts.pop() } else { None };
+        (parts, alpha)
     } else {
-        return match color {
-            "black" => Color::new(0.0, 0.0, 0.0, 1.0),
-            "silver" => Color::new(
-                0.7529411764705882,
-                0.7529411764705882,
-                0.7529411764705882,
-                1.0,
-            ),
-            "gray" | "grey" => Color::new(
-                0.5019607843137255,
-                0.5019607843137255,
-                0.5019607843137255,
-                1.0,
-            ),
-            "white" => Color::new(1.0, 1.0, 1.0, 1.0),
-            "maroon" => Color::new(0.5019607843137255, 0.0, 0.0, 1.0),
-            "red" => Color::new(1.0, 0.0, 0.0, 1.0),
-            "purple" => Color::new(0.5019607843137255, 0.0, 0.5019607843137255, 1.0),
-            "fuchsia" => Color::new(1.0, 0.0, 1.0, 1.0),
-            "green" => Color::new(0.0, 0.5019607843137255, 0.0, 1.0),
-            "lime" => Color::new(0.0, 1.0, 0.0, 1.0),
-            "olive" => Color::new(0.5019607843137255, 0.5019607843137255, 0.0, 1.0),
-            "yellow" => Color::new(1.0, 1.0, 0.0, 1.0),
-            "navy" => Color::new(0.0, 0.0, 0.5019607843137255, 1.0),
-            "blue" => Color::new(0.0, 0.0, 1.0, 1.0),
-            "teal" => Color::new(0.0, 0.5019607843137255, 0.5019607843137255, 1.0),
-            "aqua" => Color::new(0.0, 1.0, 1.0, 1.0),
-            "orange" => Color::new(1.0, 0.6470588235294118, 0.0, 1.0),
-            "aliceblue" => Color::new(0.9411764705882353, 0.9725490196078431, 1.0, 1.0),
-            "antiquewhite" => Color::new(
-                0.9803921568627451,
-                0.9215686274509803,
-                0.8431372549019608,
-                1.0,
-            ),
-            "aquamarine" => Color::new(0.4980392156862745, 1.0, 0.8313725490196079, 1.0),
-            "azure" => Color::new(0.9411764705882353, 1.0, 1.0, 1.0),
-            "beige" => Color::new(
-                0.9607843137254902,
-                0.9607843137254902,
-                0.8627450980392157,
-                1.0,
-            ),
-            "bisque" => Color::new(1.0, 0.8941176470588236, 0.7686274509803922, 1.0),
-            "blanchedalmond" => Color::new(1.0, 0.9215686274509803, 0.803921568627451, 1.0),
-            "blueviolet" => Color::new(
-                0.5411764705882353,
-                0.16862745098039217,
-                0.8862745098039215,
-                1.0,
-            ),
-            "brown" => Color::new(
-                0.6470588235294118,
-                0.16470588235294117,
-                0.16470588235294117,
-                1.0,
-            ),
-            "burlywood" => Color::new(
-                0.8705882352941177,
-                0.7215686274509804,
-                0.5294117647058824,
-                1.0,
-            ),
-            "cadetblue" => Color::new(
-                0.37254901960784315,
-                0.6196078431372549,
-                0.6274509803921569,
-                1.0,
-            ),
-            "chartreuse" => Color::new(0.4980392156862745, 1.0, 0.0, 1.0),
-            "chocolate" => Color::new(
-                0.8235294117647058,
-                0.4117647058823529,
-                0.11764705882352941,
-                1.0,
-            ),
-            "coral" => Color::new(1.0, 0.4980392156862745, 0.3137254901960784, 1.0),
-            "cornflowerblue" => Color::new(
-                0.39215686274509803,
-                0.5843137254901961,
-                0.9294117647058824,
-                1.0,
-            ),
-            "cornsilk" => Color::new(1.0, 0.9725490196078431, 0.8627450980392157, 1.0),
-            "crimson" => Color::new(
-                0.8627450980392157,
-                0.0784313725490196,
-                0.23529411764705882,
-                1.0,
-            ),
-            "darkblue" => Color::new(0.0, 0.0, 0.5450980392156862, 1.0),
-            "darkcyan" => Color::new(0.0, 0.5450980392156862, 0.5450980392156862, 1.0),
-            "darkgoldenrod" => Color::new(
-                0.7215686274509804,
-                0.5254901960784314,
-                0.043137254901960784,
-                1.0,
-            ),
-            "darkgray" | "darkgrey" => Color::new(
-                0.6627450980392157,
-                0.6627450980392157,
-                0.6627450980392157,
-                1.0,
-            ),
-            "darkgreen" => Color::new(0.0, 0.39215686274509803, 0.0, 1.0),
-            "darkkhaki" => Color::new(
-                0.7411764705882353,
-                0.7176470588235294,
-                0.4196078431372549,
-                1.0,
-            ),
-            "darkmagenta" => Color::new(0.5450980392156862, 0.0, 0.5450980392156862, 1.0),
-            "darkolivegreen" => Color::new(
-                0.3333333333333333,
-                0.4196078431372549,
-                0.1843137254901961,
-                1.0,
-            ),
-            "darkorange" => Color::new(1.0, 0.5490196078431373, 0.0, 1.0),
-            "darkorchid" => Color::new(0.6, 0.19607843137254902, 0.8, 1.0),
-            "darkred" => Color::new(0.5450980392156862, 0.0, 0.0, 1.0),
-            "darksalmon" => Color::new(
-                0.9137254901960784,
-                0.5882352941176471,
-                0.47843137254901963,
-                1.0,
-            ),
-            "darkseagreen" => Color::new(
-                0.5607843137254902,
-                0.7372549019607844,
-                0.5607843137254902,
-                1.0,
-            ),
-            "darkslateblue" => Color::new(
-                0.2823529411764706,
-                0.23921568627450981,
-                0.5450980392156862,
-                1.0,
-            ),
-            "darkslategray" | "darkslategrey" => Color::new(
-                0.1843137254901961,
-                0.30980392156862746,
-                0.30980392156862746,
-                1.0,
-            ),
-            "darkturquoise" => Color::new(0.0, 0.807843137254902, 0.8196078431372549, 1.0),
-            "darkviolet" => Color::new(0.5803921568627451, 0.0, 0.8274509803921568, 1.0),
-            "deeppink" => Color::new(1.0, 0.0784313725490196, 0.5764705882352941, 1.0),
-            "deepskyblue" => Color::new(0.0, 0.7490196078431373, 1.0, 1.0),
-            "dimgray" | "dimgrey" => Color::new(
-                0.4117647058823529,
-                0.4117647058823529,
-                0.4117647058823529,
-                1.0,
-            ),
-            "dodgerblue" => Color::new(0.11764705882352941, 0.5647058823529412, 1.0, 1.0),
-            "firebrick" => Color::new(
-                0.6980392156862745,
-                0.13333333333333333,
-                0.13333333333333333,
-                1.0,
-            ),
-            "floralwhite" => Color::new(1.0, 0.9803921568627451, 0.9411764705882353, 1.0),
-            "forestgreen" => Color::new(
-                0.13333333333333333,
-                0.5450980392156862,
-                0.13333333333333333,
-                1.0,
-            ),
-            "gainsboro" => Color::new(
-                0.8627450980392157,
-                0.8627450980392157,
-                0.8627450980392157,
-                1.0,
-            ),
-            "ghostwhite" => Color::new(0.9725490196078431, 0.9725490196078431, 1.0, 1.0),
-            "gold" => Color::new(1.0, 0.8431372549019608, 0.0, 1.0),
-            "goldenrod" => Color::new(
-                0.8549019607843137,
-                0.6470588235294118,
-                0.12549019607843137,
-                1.0,
-            ),
-            "greenyellow" => Color::new(0.6784313725490196, 1.0, 0.1843137254901961, 1.0),
-            "honeydew" => Color::new(0.9411764705882353, 1.0, 0.9411764705882353, 1.0),
-            "hotpink" => Color::new(1.0, 0.4117647058823529, 0.7058823529411765, 1.0),
-            "indianred" => Color::new(
-                0.803921568627451,
-                0.3607843137254902,
-                0.3607843137254902,
-                1.0,
-            ),
-            "indigo" => Color::new(0.29411764705882354, 0.0, 0.5098039215686274, 1.0),
-            "ivory" => Color::new(1.0, 1.0, 0.9411764705882353, 1.0),
-            "khaki" => Color::new(
-                0.9411764705882353,
-                0.9019607843137255,
-                0.5490196078431373,
-                1.0,
-            ),
-            "lavender" => Color::new(
-                0.9019607843137255,
-                0.9019607843137255,
-                0.9803921568627451,
-                1.0,
-            ),
-            "lavenderblush" => Color::new(1.0, 0.9411764705882353, 0.9607843137254902, 1.0),
-            "lawngreen" => Color::new(0.48627450980392156, 0.9882352941176471, 0.0, 1.0),
-            "lemonchiffon" => Color::new(1.0, 0.9803921568627451, 0.803921568627451, 1.0),
-            "lightblue" => Color::new(
-                0.6784313725490196,
-                0.8470588235294118,
-                0.9019607843137255,
-                1.0,
-            ),
-            "lightcoral" => Color::new(
-                0.9411764705882353,
-                0.5019607843137255,
-                0.5019607843137255,
-                1.0,
-            ),
-            "lightcyan" => Color::new(0.8784313725490196, 1.0, 1.0, 1.0),
-            "lightgoldenrodyellow" => Color::new(
-                0.9803921568627451,
-                0.9803921568627451,
-                0.8235294117647058,
-                1.0,
-            ),
-            "lightgray" | "lightgrey" => Color::new(
-                0.8274509803921568,
-                0.8274509803921568,
-                0.8274509803921568,
-                1.0,
-            ),
-            "lightgreen" => Color::new(
-                0.5647058823529412,
-                0.9333333333333333,
-                0.5647058823529412,
-                1.0,
-            ),
-            "lightpink" => Color::new(1.0, 0.7137254901960784, 0.7568627450980392, 1.0),
-            "lightsalmon" => Color::new(1.0, 0.6274509803921569, 0.47843137254901963, 1.0),
-            "lightseagreen" => Color::new(
-                0.12549019607843137,
-                0.6980392156862745,
-                0.6666666666666666,
-                1.0,
-            ),
-            "lightskyblue" => Color::new(
-                0.5294117647058824,
-                0.807843137254902,
-                0.9803921568627451,
-                1.0,
-            ),
-            "lightslategray" | "lightslategrey" => {
-                Color::new(0.4666666666666667, 0.5333333333333333, 0.6, 1.0)
-            }
-            "lightsteelblue" => Color::new(
-                0.6901960784313725,
-                0.7686274509803922,
-                0.8705882352941177,
-                1.0,
-            ),
-            "lightyellow" => Color::new(1.0, 1.0, 0.8784313725490196, 1.0),
-            "limegreen" => Color::new(
-                0.19607843137254902,
-                0.803921568627451,
-                0.19607843137254902,
-                1.0,
-            ),
-            "linen" => Color::new(
-                0.9803921568627451,
-                0.9411764705882353,
-                0.9019607843137255,
-                1.0,
-            ),
-            "mediumaquamarine" => Color::new(0.4, 0.803921568627451, 0.6666666666666666, 1.0),
-            "mediumblue" => Color::new(0.0, 0.0, 0.803921568627451, 1.0),
-            "mediumorchid" => Color::new(
-                0.7294117647058823,
-                0.3333333333333333,
-                0.8274509803921568,
-                1.0,
-            ),
-            "mediumpurple" => Color::new(
-                0.5764705882352941,
-                0.4392156862745098,
-                0.8588235294117647,
-                1.0,
-            ),
-            "mediumseagreen" => Color::new(
-                0.23529411764705882,
-                0.7019607843137254,
-                0.44313725490196076,
-                1.0,
-            ),
-            "mediumslateblue" => Color::new(
-                0.4823529411764706,
-                0.40784313725490196,
-                0.9333333333333333,
-                1.0,
-            ),
-            "mediumspringgreen" => Color::new(0.0, 0.9803921568627451, 0.6039215686274509, 1.0),
-            "mediumturquoise" => Color::new(0.2823529411764706, 0.8196078431372549, 0.8, 1.0),
-            "mediumvioletred" => Color::new(
-                0.7803921568627451,
-                0.08235294117647059,
-                0.5215686274509804,
-                1.0,
-            ),
-            "midnightblue" => Color::new(
-                0.09803921568627451,
-                0.09803921568627451,
-                0.4392156862745098,
-                1.0,
-            ),
-            "mintcream" => Color::new(0.9607843137254902, 1.0, 0.9803921568627451, 1.0),
-            "mistyrose" => Color::new(1.0, 0.8941176470588236, 0.8823529411764706, 1.0),
-            "moccasin" => Color::new(1.0, 0.8941176470588236, 0.7098039215686275, 1.0),
-            "navajowhite" => Color::new(1.0, 0.8705882352941177, 0.6784313725490196, 1.0),
-            "oldlace" => Color::new(
-                0.9921568627450981,
-                0.9607843137254902,
-                0.9019607843137255,
-                1.0,
-            ),
-            "olivedrab" => Color::new(
-                0.4196078431372549,
-                0.5568627450980392,
-                0.13725490196078433,
-                1.0,
-            ),
-            "orangered" => Color::new(1.0, 0.27058823529411763, 0.0, 1.0),
-            "orchid" => Color::new(
-                0.8549019607843137,
-                0.4392156862745098,
-                0.8392156862745098,
-                1.0,
-            ),
-            "palegoldenrod" => Color::new(
-                0.9333333333333333,
-                0.9098039215686274,
-                0.6666666666666666,
-                1.0,
-            ),
-            "palegreen" => Color::new(0.596078431372549, 0.984313725490196, 0.596078431372549, 1.0),
-            "paleturquoise" => Color::new(
-                0.6862745098039216,
-                0.9333333333333333,
-                0.9333333333333333,
-                1.0,
-            ),
-            "palevioletred" => Color::new(
-                0.8588235294117647,
-                0.4392156862745098,
-                0.5764705882352941,
-                1.0,
-            ),
-            "papayawhip" => Color::new(1.0, 0.9372549019607843, 0.8352941176470589, 1.0),
-            "peachpuff" => Color::new(1.0, 0.8549019607843137, 0.7254901960784313, 1.0),
-            "peru" => Color::new(
-                0.803921568627451,
-                0.5215686274509804,
-                0.24705882352941178,
-                1.0,
-            ),
-            "pink" => Color::new(1.0, 0.7529411764705882, 0.796078431372549, 1.0),
-            "plum" => Color::new(
-                0.8666666666666667,
-                0.6274509803921569,
-                0.8666666666666667,
-                1.0,
-            ),
-            "powderblue" => Color::new(
-                0.6901960784313725,
-                0.8784313725490196,
-                0.9019607843137255,
-                1.0,
-            ),
-            "rosybrown" => Color::new(
-                0.7372549019607844,
-                0.5607843137254902,
-                0.5607843137254902,
-                1.0,
-            ),
-            "royalblue" => Color::new(
-                0.2549019607843137,
-                0.4117647058823529,
-                0.8823529411764706,
-                1.0,
-            ),
-            "saddlebrown" => Color::new(
-                0.5450980392156862,
-                0.27058823529411763,
-                0.07450980392156863,
-                1.0,
-            ),
-            "salmon" => Color::new(
-                0.9803921568627451,
-                0.5019607843137255,
-                0.4470588235294118,
-                1.0,
-            ),
-            "sandybrown" => Color::new(
-                0.9568627450980393,
-                0.6431372549019608,
-                0.3764705882352941,
-                1.0,
-            ),
-            "seagreen" => Color::new(
-                0.1803921568627451,
-                0.5450980392156862,
-                0.3411764705882353,
-                1.0,
-            ),
-            "seashell" => Color::new(1.0, 0.9607843137254902, 0.9333333333333333, 1.0),
-            "sienna" => Color::new(
-                0.6274509803921569,
-                0.3215686274509804,
-                0.17647058823529413,
-                1.0,
-            ),
-            "skyblue" => Color::new(
-                0.5294117647058824,
-                0.807843137254902,
-                0.9215686274509803,
-                1.0,
-            ),
-            "slateblue" => Color::new(
-                0.41568627450980394,
-                0.35294117647058826,
-                0.803921568627451,
-                1.0,
-            ),
-            "slategray" | "slategrey" => Color::new(
-                0.4392156862745098,
-                0.5019607843137255,
-                0.5647058823529412,
-                1.0,
-            ),
-            "snow" => Color::new(1.0, 0.9803921568627451, 0.9803921568627451, 1.0),
-            "springgreen" => Color::new(0.0, 1.0, 0.4980392156862745, 1.0),
-            "steelblue" => Color::new(
-                0.27450980392156865,
-                0.5098039215686274,
-                0.7058823529411765,
-                1.0,
-            ),
-            "tan" => Color::new(
-                0.8235294117647058,
-                0.7058823529411765,
-                0.5490196078431373,
-                1.0,
-            ),
-            "thistle" => Color::new(
-                0.8470588235294118,
-                0.7490196078431373,
-                0.8470588235294118,
-                1.0,
-            ),
-            "tomato" => Color::new(1.0, 0.38823529411764707, 0.2784313725490196, 1.0),
-            "turquoise" => Color::new(
-                0.25098039215686274,
-                0.8784313725490196,
-                0.8156862745098039,
-                1.0,
-            ),
-            "violet" => Color::new(
-                0.9333333333333333,
-                0.5098039215686274,
-                0.9333333333333333,
-                1.0,
-            ),
-            "wheat" => Color::new(
-                0.9607843137254902,
-                0.8705882352941177,
-                0.7019607843137254,
-                1.0,
-            ),
-            "whitesmoke" => Color::new(
-                0.9607843137254902,
-                0.9607843137254902,
-                0.9607843137254902,
-                1.0,
-            ),
-            "yellowgreen" => Color::new(
-                0.6039215686274509,
-                0.803921568627451,
-                0.19607843137254902,
-                1.0,
-            ),
-            "rebeccapurple" => Color::new(0.4, 0.2, 0.6, 1.0),
-            _ => Color::new(0.0, 0.0, 0.0, 1.0),
+        let mut halves = args.splitn(2, '/');
+        let channels = halves.next().unwrap_or("").split_whitespace().collect();
+        (channels, halves.next().map(str::trim))
+    };
+
+    if channels.len() != 3 || channels.iter().any(|c| c.is_empty()) {
+        return Err(format!("expected three color channels, got '{}'", args));
+    }
+    if alpha.map_or(false, str::is_empty) {
+        return Err(format!("missing alpha value in '{}'", args));
+    }
+
+    Ok(ColorArgs { channels, alpha })
+}
+
+// Splits a color argument into its number and unit; `none` counts as zero.
+fn color_component(arg: &str) -> Result<(f32, &str), String> {
+    if arg == "none" {
+        return Ok((0.0, ""));
+    }
+    match parse_number(arg) {
+        Some(n) => Ok(n),
+        None => Err(format!("invalid color component '{}'", arg)),
+    }
+}
+
+fn color_alpha(args: &ColorArgs) -> Result<f32, String> {
+    let arg = match args.alpha {
+        Some(a) => a,
+        None => return Ok(1.0),
+    };
+
+    match color_component(arg)? {
+        (n, "") => Ok(clamp_unit(n)),
+        (n, "%") => Ok(clamp_unit(n / 100.0)),
+        _ => Err(format!("invalid alpha value '{}'", arg)),
+    }
+}
+
+fn rgb_function(args: &ColorArgs) -> Result<Color, String> {
+    let mut rgb = [0.0; 3];
+
+    for (i, arg) in args.channels.iter().enumerate() {
+        rgb[i] = match color_component(arg)? {
+            (n, "") => clamp_unit(n / 255.0),
+            (n, "%") => clamp_unit(n / 100.0),
+            _ => return Err(format!("invalid rgb channel '{}'", arg)),
         };
     }
+
+    Ok(Color::new(rgb[0], rgb[1], rgb[2], color_alpha(args)?))
 }
 
-fn is_valid_ident(c: char) -> bool {
-    is_valid_start_ident(c) || c.is_digit(10) || c == '-'
+fn hsl_function(args: &ColorArgs) -> Result<Color, String> {
+    let hue = match color_component(args.channels[0])? {
+        (n, "") | (n, "deg") => n,
+        (n, "grad") => n * 0.9,
+        (n, "rad") => n.to_degrees(),
+        (n, "turn") => n * 360.0,
+        _ => return Err(format!("invalid hue '{}'", args.channels[0])),
+    };
+
+    let mut sl = [0.0; 2];
+    for (i, arg) in args.channels[1..].iter().enumerate() {
+        sl[i] = match color_component(arg)? {
+            (n, "%") | (n, "") => clamp_unit(n / 100.0),
+            _ => return Err(format!("invalid hsl percentage '{}'", arg)),
+        };
+    }
+
+    let (r, g, b) = hsl_to_rgb(hue, sl[0], sl[1]);
+    Ok(Color::new(r, g, b, color_alpha(args)?))
+}
+
+// The conversion from CSS Color 4, with the hue in degrees and saturation and
+// lightness between 0 and 1.
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> (f32, f32, f32) {
+    let hue = ((hue % 360.0) + 360.0) % 360.0;
+    let channel = |n: f32| {
+        let k = (n + hue / 30.0) % 12.0;
+        let a = saturation * lightness.min(1.0 - lightness);
+        lightness - a * (k - 3.0).min(9.0 - k).min(1.0).max(-1.0)
+    };
+
+    (channel(0.0), channel(8.0), channel(4.0))
+}
+
+fn clamp_unit(n: f32) -> f32 {
+    n.max(0.0).min(1.0)
+}
+
+fn named_color(name: &str) -> Option<Color> {
+    Some(match name {
+        "transparent" => Color::new(0.0, 0.0, 0.0, 0.0),
+        "black" => Color::new(0.0, 0.0, 0.0, 1.0),
+        "silver" => Color::new(
+            0.7529411764705882,
+            0.7529411764705882,
+            0.7529411764705882,
+            1.0,
+        ),
+        "gray" | "grey" => Color::new(
+            0.5019607843137255,
+            0.5019607843137255,
+            0.5019607843137255,
+            1.0,
+        ),
+        "white" => Color::new(1.0, 1.0, 1.0, 1.0),
+        "maroon" => Color::new(0.5019607843137255, 0.0, 0.0, 1.0),
+        "red" => Color::new(1.0, 0.0, 0.0, 1.0),
+        "purple" => Color::new(0.5019607843137255, 0.0, 0.5019607843137255, 1.0),
+        "fuchsia" => Color::new(1.0, 0.0, 1.0, 1.0),
+        "green" => Color::new(0.0, 0.5019607843137255, 0.0, 1.0),
+        "lime" => Color::new(0.0, 1.0, 0.0, 1.0),
+        "olive" => Color::new(0.5019607843137255, 0.5019607843137255, 0.0, 1.0),
+        "yellow" => Color::new(1.0, 1.0, 0.0, 1.0),
+        "navy" => Color::new(0.0, 0.0, 0.5019607843137255, 1.0),
+        "blue" => Color::new(0.0, 0.0, 1.0, 1.0),
+        "teal" => Color::new(0.0, 0.5019607843137255, 0.5019607843137255, 1.0),
+        "aqua" => Color::new(0.0, 1.0, 1.0, 1.0),
+        "orange" => Color::new(1.0, 0.6470588235294118, 0.0, 1.0),
+        "aliceblue" => Color::new(0.9411764705882353, 0.9725490196078431, 1.0, 1.0),
+        "antiquewhite" => Color::new(
+            0.9803921568627451,
+            0.9215686274509803,
+            0.8431372549019608,
+            1.0,
+        ),
+        "aquamarine" => Color::new(0.4980392156862745, 1.0, 0.8313725490196079, 1.0),
+        "azure" => Color::new(0.9411764705882353, 1.0, 1.0, 1.0),
+        "beige" => Color::new(
+            0.9607843137254902,
+            0.9607843137254902,
+            0.8627450980392157,
+            1.0,
+        ),
+        "bisque" => Color::new(1.0, 0.8941176470588236, 0.7686274509803922, 1.0),
+        "blanchedalmond" => Color::new(1.0, 0.9215686274509803, 0.803921568627451, 1.0),
+        "blueviolet" => Color::new(
+            0.5411764705882353,
+            0.16862745098039217,
+            0.8862745098039215,
+            1.0,
+        ),
+        "brown" => Color::new(
+            0.6470588235294118,
+            0.16470588235294117,
+            0.16470588235294117,
+            1.0,
+        ),
+        "burlywood" => Color::new(
+            0.8705882352941177,
+            0.7215686274509804,
+            0.5294117647058824,
+            1.0,
+        ),
+        "cadetblue" => Color::new(
+            0.37254901960784315,
+            0.6196078431372549,
+            0.6274509803921569,
+            1.0,
+        ),
+        "chartreuse" => Color::new(0.4980392156862745, 1.0, 0.0, 1.0),
+        "chocolate" => Color::new(
+            0.8235294117647058,
+            0.4117647058823529,
+            0.11764705882352941,
+            1.0,
+        ),
+        "coral" => Color::new(1.0, 0.4980392156862745, 0.3137254901960784, 1.0),
+        "cornflowerblue" => Color::new(
+            0.39215686274509803,
+            0.5843137254901961,
+            0.9294117647058824,
+            1.0,
+        ),
+        "cornsilk" => Color::new(1.0, 0.9725490196078431, 0.8627450980392157, 1.0),
+        "crimson" => Color::new(
+            0.8627450980392157,
+            0.0784313725490196,
+            0.23529411764705882,
+            1.0,
+        ),
+        "darkblue" => Color::new(0.0, 0.0, 0.5450980392156862, 1.0),
+        "darkcyan" => Color::new(0.0, 0.5450980392156862, 0.5450980392156862, 1.0),
+        "darkgoldenrod" => Color::new(
+            0.7215686274509804,
+            0.5254901960784314,
+            0.043137254901960784,
+            1.0,
+        ),
+        "darkgray" | "darkgrey" => Color::new(
+            0.6627450980392157,
+            0.6627450980392157,
+            0.6627450980392157,
+            1.0,
+        ),
+        "darkgreen" => Color::new(0.0, 0.39215686274509803, 0.0, 1.0),
+        "darkkhaki" => Color::new(
+            0.7411764705882353,
+            0.7176470588235294,
+            0.4196078431372549,
+            1.0,
+        ),
+        "darkmagenta" => Color::new(0.5450980392156862, 0.0, 0.5450980392156862, 1.0),
+        "darkolivegreen" => Color::new(
+            0.3333333333333333,
+            0.4196078431372549,
+            0.1843137254901961,
+            1.0,
+        ),
+        "darkorange" => Color::new(1.0, 0.5490196078431373, 0.0, 1.0),
+        "darkorchid" => Color::new(0.6, 0.19607843137254902, 0.8, 1.0),
+        "darkred" => Color::new(0.5450980392156862, 0.0, 0.0, 1.0),
+        "darksalmon" => Color::new(
+            0.9137254901960784,
+            0.5882352941176471,
+            0.47843137254901963,
+            1.0,
+        ),
+        "darkseagreen" => Color::new(
+            0.5607843137254902,
+            0.7372549019607844,
+            0.5607843137254902,
+            1.0,
+        ),
+        "darkslateblue" => Color::new(
+            0.2823529411764706,
+            0.23921568627450981,
+            0.5450980392156862,
+            1.0,
+        ),
+        "darkslategray" | "darkslategrey" => Color::new(
+            0.1843137254901961,
+            0.30980392156862746,
+            0.30980392156862746,
+            1.0,
+        ),
+        "darkturquoise" => Color::new(0.0, 0.807843137254902, 0.8196078431372549, 1.0),
+        "darkviolet" => Color::new(0.5803921568627451, 0.0, 0.8274509803921568, 1.0),
+        "deeppink" => Color::new(1.0, 0.0784313725490196, 0.5764705882352941, 1.0),
+        "deepskyblue" => Color::new(0.0, 0.7490196078431373, 1.0, 1.0),
+        "dimgray" | "dimgrey" => Color::new(
+            0.4117647058823529,
+            0.4117647058823529,
+            0.4117647058823529,
+            1.0,
+        ),
+        "dodgerblue" => Color::new(0.11764705882352941, 0.5647058823529412, 1.0, 1.0),
+        "firebrick" => Color::new(
+            0.6980392156862745,
+            0.13333333333333333,
+            0.13333333333333333,
+            1.0,
+        ),
+        "floralwhite" => Color::new(1.0, 0.9803921568627451, 0.9411764705882353, 1.0),
+        "forestgreen" => Color::new(
+            0.13333333333333333,
+            0.5450980392156862,
+            0.13333333333333333,
+            1.0,
+        ),
+        "gainsboro" => Color::new(
+            0.8627450980392157,
+            0.8627450980392157,
+            0.8627450980392157,
+            1.0,
+        ),
+        "ghostwhite" => Color::new(0.9725490196078431, 0.9725490196078431, 1.0, 1.0),
+        "gold" => Color::new(1.0, 0.8431372549019608, 0.0, 1.0),
+        "goldenrod" => Color::new(
+            0.8549019607843137,
+            0.6470588235294118,
+            0.12549019607843137,
+            1.0,
+        ),
+        "greenyellow" => Color::new(0.6784313725490196, 1.0, 0.1843137254901961, 1.0),
+        "honeydew" => Color::new(0.9411764705882353, 1.0, 0.9411764705882353, 1.0),
+        "hotpink" => Color::new(1.0, 0.4117647058823529, 0.7058823529411765, 1.0),
+        "indianred" => Color::new(
+            0.803921568627451,
+            0.3607843137254902,
+            0.3607843137254902,
+            1.0,
+        ),
+        "indigo" => Color::new(0.29411764705882354, 0.0, 0.5098039215686274, 1.0),
+        "ivory" => Color::new(1.0, 1.0, 0.9411764705882353, 1.0),
+        "khaki" => Color::new(
+            0.9411764705882353,
+            0.9019607843137255,
+            0.5490196078431373,
+            1.0,
+        ),
+        "lavender" => Color::new(
+            0.9019607843137255,
+            0.9019607843137255,
+            0.9803921568627451,
+            1.0,
+        ),
+        "lavenderblush" => Color::new(1.0, 0.9411764705882353, 0.9607843137254902, 1.0),
+        "lawngreen" => Color::new(0.48627450980392156, 0.9882352941176471, 0.0, 1.0),
+        "lemonchiffon" => Color::new(1.0, 0.9803921568627451, 0.803921568627451, 1.0),
+        "lightblue" => Color::new(
+            0.6784313725490196,
+            0.8470588235294118,
+            0.9019607843137255,
+            1.0,
+        ),
+        "lightcoral" => Color::new(
+            0.9411764705882353,
+            0.5019607843137255,
+            0.5019607843137255,
+            1.0,
+        ),
+        "lightcyan" => Color::new(0.8784313725490196, 1.0, 1.0, 1.0),
+        "lightgoldenrodyellow" => Color::new(
+            0.9803921568627451,
+            0.9803921568627451,
+            0.8235294117647058,
+            1.0,
+        ),
+        "lightgray" | "lightgrey" => Color::new(
+            0.8274509803921568,
+            0.8274509803921568,
+            0.8274509803921568,
+            1.0,
+        ),
+        "lightgreen" => Color::new(
+            0.5647058823529412,
+            0.9333333333333333,
+            0.5647058823529412,
+            1.0,
+        ),
+        "lightpink" => Color::new(1.0, 0.7137254901960784, 0.7568627450980392, 1.0),
+        "lightsalmon" => Color::new(1.0, 0.6274509803921569, 0.47843137254901963, 1.0),
+        "lightseagreen" => Color::new(
+            0.12549019607843137,
+            0.6980392156862745,
+            0.6666666666666666,
+            1.0,
+        ),
+        "lightskyblue" => Color::new(
+            0.5294117647058824,
+            0.807843137254902,
+            0.9803921568627451,
+            1.0,
+        ),
+        "lightslategray" | "lightslategrey" => {
+            Color::new(0.4666666666666667, 0.5333333333333333, 0.6, 1.0)
+        }
+        "lightsteelblue" => Color::new(
+            0.6901960784313725,
+            0.7686274509803922,
+            0.8705882352941177,
+            1.0,
+        ),
+        "lightyellow" => Color::new(1.0, 1.0, 0.8784313725490196, 1.0),
+        "limegreen" => Color::new(
+            0.19607843137254902,
+            0.803921568627451,
+            0.19607843137254902,
+            1.0,
+        ),
+        "linen" => Color::new(
+            0.9803921568627451,
+            0.9411764705882353,
+            0.9019607843137255,
+            1.0,
+        ),
+        "mediumaquamarine" => Color::new(0.4, 0.803921568627451, 0.6666666666666666, 1.0),
+        "mediumblue" => Color::new(0.0, 0.0, 0.803921568627451, 1.0),
+        "mediumorchid" => Color::new(
+            0.7294117647058823,
+            0.3333333333333333,
+            0.8274509803921568,
+            1.0,
+        ),
+        "mediumpurple" => Color::new(
+            0.5764705882352941,
+            0.4392156862745098,
+            0.8588235294117647,
+            1.0,
+        ),
+        "mediumseagreen" => Color::new(
+            0.23529411764705882,
+            0.7019607843137254,
+            0.44313725490196076,
+            1.0,
+        ),
+        "mediumslateblue" => Color::new(
+            0.4823529411764706,
+            0.40784313725490196,
+            0.9333333333333333,
+            1.0,
+        ),
+        "mediumspringgreen" => Color::new(0.0, 0.9803921568627451, 0.6039215686274509, 1.0),
+        "mediumturquoise" => Color::new(0.2823529411764706, 0.8196078431372549, 0.8, 1.0),
+        "mediumvioletred" => Color::new(
+            0.7803921568627451,
+            0.08235294117647059,
+            0.5215686274509804,
+            1.0,
+        ),
+        "midnightblue" => Color::new(
+            0.09803921568627451,
+            0.09803921568627451,
+            0.4392156862745098,
+            1.0,
+        ),
+        "mintcream" => Color::new(0.9607843137254902, 1.0, 0.9803921568627451, 1.0),
+        "mistyrose" => Color::new(1.0, 0.8941176470588236, 0.8823529411764706, 1.0),
+        "moccasin" => Color::new(1.0, 0.8941176470588236, 0.7098039215686275, 1.0),
+        "navajowhite" => Color::new(1.0, 0.8705882352941177, 0.6784313725490196, 1.0),
+        "oldlace" => Color::new(
+            0.9921568627450981,
+            0.9607843137254902,
+            0.9019607843137255,
+            1.0,
+        ),
+        "olivedrab" => Color::new(
+            0.4196078431372549,
+            0.5568627450980392,
+            0.13725490196078433,
+            1.0,
+        ),
+        "orangered" => Color::new(1.0, 0.27058823529411763, 0.0, 1.0),
+        "orchid" => Color::new(
+            0.8549019607843137,
+            0.4392156862745098,
+            0.8392156862745098,
+            1.0,
+        ),
+        "palegoldenrod" => Color::new(
+            0.9333333333333333,
+            0.9098039215686274,
+            0.6666666666666666,
+            1.0,
+        ),
+        "palegreen" => Color::new(0.596078431372549, 0.984313725490196, 0.596078431372549, 1.0),
+        "paleturquoise" => Color::new(
+            0.6862745098039216,
+            0.9333333333333333,
+            0.9333333333333333,
+            1.0,
+        ),
+        "palevioletred" => Color::new(
+            0.8588235294117647,
+            0.4392156862745098,
+            0.5764705882352941,
+            1.0,
+        ),
+        "papayawhip" => Color::new(1.0, 0.9372549019607843, 0.8352941176470589, 1.0),
+        "peachpuff" => Color::new(1.0, 0.8549019607843137, 0.7254901960784313, 1.0),
+        "peru" => Color::new(
+            0.803921568627451,
+            0.5215686274509804,
+            0.24705882352941178,
+            1.0,
+        ),
+        "pink" => Color::new(1.0, 0.7529411764705882, 0.796078431372549, 1.0),
+        "plum" => Color::new(
+            0.8666666666666667,
+            0.6274509803921569,
+            0.8666666666666667,
+            1.0,
+        ),
+        "powderblue" => Color::new(
+            0.6901960784313725,
+            0.8784313725490196,
+            0.9019607843137255,
+            1.0,
+        ),
+        "rosybrown" => Color::new(
+            0.7372549019607844,
+            0.5607843137254902,
+            0.5607843137254902,
+            1.0,
+        ),
+        "royalblue" => Color::new(
+            0.2549019607843137,
+            0.4117647058823529,
+            0.8823529411764706,
+            1.0,
+        ),
+        "saddlebrown" => Color::new(
+            0.5450980392156862,
+            0.27058823529411763,
+            0.07450980392156863,
+            1.0,
+        ),
+        "salmon" => Color::new(
+            0.9803921568627451,
+            0.5019607843137255,
+            0.4470588235294118,
+            1.0,
+        ),
+        "sandybrown" => Color::new(
+            0.9568627450980393,
+            0.6431372549019608,
+            0.3764705882352941,
+            1.0,
+        ),
+        "seagreen" => Color::new(
+            0.1803921568627451,
+            0.5450980392156862,
+            0.3411764705882353,
+            1.0,
+        ),
+        "seashell" => Color::new(1.0, 0.9607843137254902, 0.9333333333333333, 1.0),
+        "sienna" => Color::new(
+            0.6274509803921569,
+            0.3215686274509804,
+            0.17647058823529413,
+            1.0,
+        ),
+        "skyblue" => Color::new(
+            0.5294117647058824,
+            0.807843137254902,
+            0.9215686274509803,
+            1.0,
+        ),
+        "slateblue" => Color::new(
+            0.41568627450980394,
+            0.35294117647058826,
+            0.803921568627451,
+            1.0,
+        ),
+        "slategray" | "slategrey" => Color::new(
+            0.4392156862745098,
+            0.5019607843137255,
+            0.5647058823529412,
+            1.0,
+        ),
+        "snow" => Color::new(1.0, 0.9803921568627451, 0.9803921568627451, 1.0),
+        "springgreen" => Color::new(0.0, 1.0, 0.4980392156862745, 1.0),
+        "steelblue" => Color::new(
+            0.27450980392156865,
+            0.5098039215686274,
+            0.7058823529411765,
+            1.0,
+        ),
+        "tan" => Color::new(
+            0.8235294117647058,
+            0.7058823529411765,
+            0.5490196078431373,
+            1.0,
+        ),
+        "thistle" => Color::new(
+            0.8470588235294118,
+            0.7490196078431373,
+            0.8470588235294118,
+            1.0,
+        ),
+        "tomato" => Color::new(1.0, 0.38823529411764707, 0.2784313725490196, 1.0),
+        "turquoise" => Color::new(
+            0.25098039215686274,
+            0.8784313725490196,
+            0.8156862745098039,
+            1.0,
+        ),
+        "violet" => Color::new(
+            0.9333333333333333,
+            0.5098039215686274,
+            0.9333333333333333,
+            1.0,
+        ),
+        "wheat" => Color::new(
+            0.9607843137254902,
+            0.8705882352941177,
+            0.7019607843137254,
+            1.0,
+        ),
+        "whitesmoke" => Color::new(
+            0.9607843137254902,
+            0.9607843137254902,
+            0.9607843137254902,
+            1.0,
+        ),
+        "yellowgreen" => Color::new(
+            0.6039215686274509,
+            0.803921568627451,
+            0.19607843137254902,
+            1.0,
+        ),
+        "rebeccapurple" => Color::new(0.4, 0.2, 0.6, 1.0),
+        _ => return None,
+    })
+}
+
+fn is_combinator(c: char) -> bool {
+    c == '>' || c == '+' || c == '~'
+}
+
+fn starts_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    match chars.next() {
+        Some('-') => chars
+            .next()
+            .map_or(false, |c| is_valid_start_ident(c) || c == '-' || c == '\\'),
+        Some(c) => is_valid_start_ident(c) || c == '\\',
+        None => false,
+    }
 }
 
 fn is_valid_start_ident(c: char) -> bool {
@@ -780,3 +1895,268 @@ fn is_lower_letter(c: char) -> bool {
 fn is_non_ascii(c: char) -> bool {
     c >= '\u{0080}'
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(css: &str) -> (Stylesheet, Vec<String>) {
+        let mut parser = CssParser::new(css);
+        let stylesheet = parser.parse_stylesheet();
+        let errors = parser.errors().iter().map(|e| e.to_string()).collect();
+        (stylesheet, errors)
+    }
+
+    // The declarations of a single rule whose block is `block`.
+    fn declarations(block: &str) -> Vec<String> {
+        let (stylesheet, errors) = parse(&format!("a {{ {} }}", block));
+        assert_eq!(errors, Vec::<String>::new());
+
+        stylesheet.rules[0]
+            .declarations
+            .iter()
+            .map(|d| format!("{:?}", d))
+            .collect()
+    }
+
+    fn selectors(stylesheet: &Stylesheet) -> Vec<String> {
+        stylesheet
+            .rules
+            .iter()
+            .map(|r| format!("{:?}", r.selectors[0]))
+            .collect()
+    }
+
+    #[test]
+    fn numbers_and_exponents() {
+        assert_eq!(
+            declarations("width: 1e2px; margin-left: .5rem; line-height: 1.5; height: 1E1%"),
+            vec![
+                "width: 100px",
+                "margin-left: 0.5rem",
+                "line-height: 1.5",
+                "height: 10%",
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_in_selectors_and_strings() {
+        let (stylesheet, _) = parse(".a\\:b, #\\31 0 { content: \"x\\\"y\" }");
+        assert_eq!(format!("{:?}", stylesheet.rules[0].selectors[1]), "#10");
+        assert_eq!(
+            format!("{:?}", stylesheet),
+            ".a:b, #10 {\n    content: \"x\\\"y\"\n}"
+        );
+    }
+
+    #[test]
+    fn compound_selectors_may_repeat_ids() {
+        let (stylesheet, errors) = parse("#a#b, #a#a { color: red }");
+
+        assert_eq!(errors, Vec::<String>::new());
+        assert_eq!(format!("{:?}", stylesheet.rules[0].selectors[0]), "#a#b");
+        assert_eq!(stylesheet.rules[0].selectors[1].specificity(), (2, 0, 0));
+    }
+
+    #[test]
+    fn invalid_declarations_are_dropped_alone() {
+        let (stylesheet, errors) = parse(
+            "a { color: ; width: 10px }\n\
+             b {\n  content: \"abc\n  ; width: 1px; background-color: url(a b); color: blue }",
+        );
+
+        assert_eq!(
+            format!("{:?}", stylesheet.rules[1]),
+            "b {\n    width: 1px\n    color: r: 0 g: 0 b: 1 a: 1\n}"
+        );
+        assert_eq!(stylesheet.rules[0].declarations.len(), 1);
+        assert_eq!(
+            errors,
+            vec![
+                "1:5: dropped declaration 'color': missing value",
+                "3:3: dropped declaration 'content': unterminated string",
+                "4:17: dropped declaration 'background-color': invalid url",
+            ]
+        );
+    }
+
+    #[test]
+    fn lengths_take_only_the_keywords_their_property_allows() {
+        assert_eq!(
+            declarations("width: auto; margin-left: auto; font-size: Large; word-spacing: normal"),
+            vec![
+                "width: auto",
+                "margin-left: auto",
+                "font-size: large",
+                "word-spacing: normal",
+            ]
+        );
+
+        let (stylesheet, errors) = parse(
+            "a { width: blue; padding-top: auto; font-size: bogus; height: min(1px, 2px) }",
+        );
+        assert!(stylesheet.rules[0].declarations.is_empty());
+        assert_eq!(
+            errors,
+            vec![
+                "1:5: dropped declaration 'width': expected a length, got 'blue'",
+                "1:18: dropped declaration 'padding-top': expected a length, got 'auto'",
+                "1:37: dropped declaration 'font-size': expected a length, got 'bogus'",
+                "1:55: dropped declaration 'height': expected a length, got 'min(1px, 2px)'",
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_selectors_drop_only_their_rule() {
+        let (stylesheet, errors) = parse("a, ?b { color: red }\nc { width: 1px }\nd[ { } e { }");
+
+        assert_eq!(selectors(&stylesheet), vec!["c"]);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], "1:1: dropped rule: unexpected '?' in selector");
+        assert_eq!(errors[1], "3:1: unexpected end of stylesheet in rule");
+    }
+
+    #[test]
+    fn unsupported_at_rules_are_reported() {
+        let (stylesheet, errors) = parse(
+            "@charset \"utf-8\";\n@import url(a.css);\n@font-face { font-family: x }\n\
+             @supports (display: grid) { a { color: red } }\nb { color: red }",
+        );
+
+        assert_eq!(selectors(&stylesheet), vec!["b"]);
+        assert_eq!(
+            errors,
+            vec![
+                "2:1: dropped rule: @import is not supported",
+                "3:1: dropped rule: @font-face is not supported",
+                "4:1: dropped rule: @supports is not supported",
+            ]
+        );
+    }
+
+    #[test]
+    fn unclosed_blocks_end_with_the_stylesheet() {
+        let (stylesheet, errors) = parse("a { width: 1px; b { color: red }");
+
+        assert_eq!(selectors(&stylesheet), vec!["a"]);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn shorthands_expand_to_longhands() {
+        assert_eq!(
+            declarations("margin: 1px 2px"),
+            vec![
+                "margin-top: 1px",
+                "margin-right: 2px",
+                "margin-bottom: 1px",
+                "margin-left: 2px",
+            ]
+        );
+        assert_eq!(
+            declarations("border-left: dashed"),
+            vec![
+                "border-left-width: 3px",
+                "border-left-style: dashed",
+                "border-left-color: currentcolor",
+            ]
+        );
+        assert_eq!(
+            declarations("background: url(A.png) center / cover"),
+            vec![
+                "background-color: r: 0 g: 0 b: 0 a: 0",
+                "background-image: url(\"A.png\")",
+                "background-repeat: repeat",
+                "background-attachment: scroll",
+                "background-position: center",
+                "background-size: cover",
+                "background-origin: padding-box",
+                "background-clip: border-box",
+            ]
+        );
+        assert_eq!(
+            declarations("background: red left top / 10px 20px no-repeat")[4..6],
+            ["background-position: left top", "background-size: 10px 20px"]
+        );
+        let (_, errors) = parse("a { margin: 0 inherit; border: solid initial }");
+        assert_eq!(
+            errors,
+            vec![
+                "1:5: dropped declaration 'margin': 'inherit' can't be combined with other values",
+                "1:24: dropped declaration 'border': 'initial' can't be combined with other values",
+            ]
+        );
+        assert_eq!(
+            declarations("font: italic bold 12px/1.5 serif !important"),
+            vec![
+                "font-style: italic !important",
+                "font-variant: normal !important",
+                "font-weight: bold !important",
+                "font-stretch: normal !important",
+                "font-size: 12px !important",
+                "line-height: 1.5 !important",
+                "font-family: serif !important",
+            ]
+        );
+
+        // The words before the size are typed like the longhands themselves.
+        let (font, _) = parse("a { font: 700 12px serif }");
+        let (weight, _) = parse("a { font-weight: 700 }");
+        assert_eq!(font.rules[0].declarations[2].value, Value::Number(700.0));
+        assert_eq!(weight.rules[0].declarations[0].value, Value::Number(700.0));
+    }
+
+    #[test]
+    fn counter_names_are_keywords() {
+        let (stylesheet, _) = parse("a { counter-reset: a Sec 3; content: counter(Sec) \"Sec\" }");
+        let declarations = &stylesheet.rules[0].declarations;
+        let keyword = |s: &str| Value::Keyword(String::from(s));
+
+        assert_eq!(
+            declarations[0].value,
+            Value::List(
+                vec![keyword("a"), Value::Number(0.0), keyword("Sec"), Value::Number(3.0)],
+                ' '
+            )
+        );
+        assert_eq!(
+            declarations[1].value,
+            Value::List(
+                vec![
+                    Value::Function(
+                        String::from("counter"),
+                        vec![keyword("Sec"), keyword("decimal")],
+                    ),
+                    Value::String(String::from("Sec")),
+                ],
+                ' '
+            )
+        );
+    }
+
+    #[test]
+    fn color_forms() {
+        assert_eq!(
+            declarations(
+                "color: #f00; color: #0000ff80; color: rgb(255 0 0 / 50%); \
+                 color: rgba(0, 0, 255, .5); color: hsl(120, 100%, 50%); \
+                 color: transparent; color: TEAL",
+            ),
+            vec![
+                "color: r: 1 g: 0 b: 0 a: 1",
+                "color: r: 0 g: 0 b: 1 a: 0.5019608",
+                "color: r: 1 g: 0 b: 0 a: 0.5",
+                "color: r: 0 g: 0 b: 1 a: 0.5",
+                "color: r: 0 g: 1 b: 0 a: 1",
+                "color: r: 0 g: 0 b: 0 a: 0",
+                "color: r: 0 g: 0.5019608 b: 0.5019608 a: 1",
+            ]
+        );
+
+        let (_, errors) = parse("a { color: #ggg; color: rgb(1, 2) }");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0], "1:5: dropped declaration 'color': invalid hex color '#ggg'");
+    }
+}
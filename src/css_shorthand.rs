@@ -0,0 +1,397 @@
+use css::{Declaration, Value};
+use css_parser::{is_css_wide_keyword, parse_number, parse_value, translate_color,
+                 translate_length, FONT_SIZE_KEYWORDS};
+
+// Shorthands are expanded while parsing, so the cascade and layout only ever
+// see longhands. A shorthand sets every one of its longhands: those its value
+// leaves out are reset to their initial values.
+
+const SIDES: [&'static str; 4] = ["top", "right", "bottom", "left"];
+
+const BORDER_STYLES: &'static [&'static str] = &[
+    "none", "hidden", "dotted", "dashed", "solid", "double", "groove", "ridge", "inset", "outset"
+];
+
+const FONT_STRETCHES: &'static [&'static str] = &[
+    "ultra-condensed",
+    "extra-condensed",
+    "condensed",
+    "semi-condensed",
+    "semi-expanded",
+    "expanded",
+    "extra-expanded",
+    "ultra-expanded",
+];
+
+const SYSTEM_FONTS: &'static [&'static str] = &[
+    "caption", "icon", "menu", "message-box", "small-caption", "status-bar"
+];
+
+type Longhands = Vec<(String, Value)>;
+
+pub fn is_shorthand(property: &str) -> bool {
+    !longhand_names(property).is_empty()
+}
+
+pub fn is_border_style(value: &str) -> bool {
+    BORDER_STYLES.contains(&value)
+}
+
+// `words` are the value's space-separated parts as written, with `/` and `,`
+// as words of their own. Keywords are matched against lowercased copies, but
+// the longhands get the words themselves so that names and urls keep their
+// case.
+pub fn expand(
+    property: &str,
+    words: &[String],
+    important: bool,
+) -> Result<Vec<Declaration>, String> {
+    let lower = lowercase(words);
+
+    let longhands = if words.len() == 1 && is_css_wide_keyword(&lower[0]) {
+        longhand_names(property)
+            .into_iter()
+            .map(|name| (name, Value::Keyword(lower[0].clone())))
+            .collect()
+    } else if let Some(keyword) = lower.iter().find(|w| is_css_wide_keyword(w)) {
+        return Err(format!("'{}' can't be combined with other values", keyword));
+    } else {
+        match property {
+            "margin" | "padding" => expand_box(words, |side| format!("{}-{}", property, side))?,
+            "border-width" | "border-style" | "border-color" => {
+                let part = &property["border-".len()..];
+                expand_box(words, |side| format!("border-{}-{}", side, part))?
+            }
+            "border" => {
+                let side = expand_border(words)?;
+                SIDES
+                    .iter()
+                    .flat_map(|s| side_longhands(s, &side))
+                    .collect()
+            }
+            "border-top" | "border-right" | "border-bottom" | "border-left" => {
+                side_longhands(&property["border-".len()..], &expand_border(words)?)
+            }
+            "background" => expand_background(words)?,
+            "font" => expand_font(words)?,
+            _ => return Err(format!("'{}' is not a shorthand", property)),
+        }
+    };
+
+    Ok(longhands
+        .into_iter()
+        .map(|(name, value)| Declaration::new(name, value, important))
+        .collect())
+}
+
+fn longhand_names(property: &str) -> Vec<String> {
+    match property {
+        "margin" | "padding" => each_side(|side| format!("{}-{}", property, side)),
+        "border-width" | "border-style" | "border-color" => {
+            let part = &property["border-".len()..];
+            each_side(|side| format!("border-{}-{}", side, part))
+        }
+        "border" => SIDES
+            .iter()
+            .flat_map(|side| longhand_names(&format!("border-{}", side)))
+            .collect(),
+        "border-top" | "border-right" | "border-bottom" | "border-left" => {
+            ["width", "style", "color"]
+                .iter()
+                .map(|part| format!("{}-{}", property, part))
+                .collect()
+        }
+        "background" => [
+            "background-color",
+            "background-image",
+            "background-repeat",
+            "background-attachment",
+            "background-position",
+            "background-size",
+            "background-origin",
+            "background-clip",
+        ].iter()
+            .map(|s| String::from(*s))
+            .collect(),
+        "font" => [
+            "font-style",
+            "font-variant",
+            "font-weight",
+            "font-stretch",
+            "font-size",
+            "line-height",
+            "font-family",
+        ].iter()
+            .map(|s| String::from(*s))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+fn lowercase(words: &[String]) -> Vec<String> {
+    words.iter().map(|w| w.to_lowercase()).collect()
+}
+
+fn each_side<F>(name: F) -> Vec<String>
+where
+    F: Fn(&str) -> String,
+{
+    SIDES.iter().map(|side| name(side)).collect()
+}
+
+// One to four values for top, right, bottom and left: a missing right copies
+// top, a missing bottom copies top and a missing left copies right.
+fn expand_box<F>(words: &[String], name: F) -> Result<Longhands, String>
+where
+    F: Fn(&str) -> String,
+{
+    let picks = match words.len() {
+        1 => [0, 0, 0, 0],
+        2 => [0, 1, 0, 1],
+        3 => [0, 1, 2, 1],
+        4 => [0, 1, 2, 3],
+        n => return Err(format!("expected one to four values, got {}", n)),
+    };
+
+    let mut longhands = Vec::new();
+    for (side, &i) in SIDES.iter().zip(picks.iter()) {
+        let property = name(side);
+        let value = parse_value(&property, words[i].clone())?;
+        longhands.push((property, value));
+    }
+    Ok(longhands)
+}
+
+struct BorderSide {
+    width: Value,
+    style: Value,
+    color: Value,
+}
+
+// A width, a style and a color, each optional and in any order.
+fn expand_border(words: &[String]) -> Result<BorderSide, String> {
+    if words.is_empty() || words.len() > 3 {
+        return Err(format!("expected one to three values, got {}", words.len()));
+    }
+
+    let (mut width, mut style, mut color) = (None, None, None);
+    for word in words {
+        let w = word.to_lowercase();
+
+        if style.is_none() && is_border_style(&w) {
+            style = Some(Value::Keyword(w));
+        } else if color.is_none() && is_color(&w) {
+            color = Some(parse_value("border-top-color", word.clone())?);
+        } else if width.is_none() && !is_css_wide_keyword(&w) {
+            width = Some(parse_value("border-top-width", word.clone())?);
+        } else {
+            return Err(format!("unexpected '{}' in border", word));
+        }
+    }
+
+    Ok(BorderSide {
+        width: width.unwrap_or_else(|| keyword_value("border-top-width", "medium")),
+        style: style.unwrap_or_else(|| Value::Keyword(String::from("none"))),
+        color: color.unwrap_or_else(|| Value::Keyword(String::from("currentcolor"))),
+    })
+}
+
+fn side_longhands(side: &str, border: &BorderSide) -> Longhands {
+    vec![
+        (format!("border-{}-width", side), border.width.clone()),
+        (format!("border-{}-style", side), border.style.clone()),
+        (format!("border-{}-color", side), border.color.clone()),
+    ]
+}
+
+fn expand_background(words: &[String]) -> Result<Longhands, String> {
+    let mut color = None;
+    let mut image = Vec::new();
+    let mut attachment = Vec::new();
+    let mut repeat = Vec::new();
+    let mut position = Vec::new();
+    let mut size = Vec::new();
+    let mut boxes = Vec::new();
+    // Set by the `/` until the first size word; the size may take a second.
+    let mut after_slash = false;
+    let mut in_size = false;
+
+    let lower = lowercase(words);
+    for (word, w) in words.iter().zip(lower.iter().map(|w| w.as_str())) {
+        if (after_slash || in_size && size.len() < 2) && is_background_size(w) {
+            size.push(w);
+            after_slash = false;
+            in_size = true;
+            continue;
+        }
+        if after_slash {
+            return Err(String::from("expected a size after '/'"));
+        }
+        in_size = false;
+
+        match w {
+            "," => return Err(String::from("multiple background layers aren't supported")),
+            "/" if !position.is_empty() && size.is_empty() => after_slash = true,
+            "repeat" | "repeat-x" | "repeat-y" | "no-repeat" | "space" | "round"
+                if repeat.len() < 2 =>
+            {
+                repeat.push(w)
+            }
+            "scroll" | "fixed" | "local" if attachment.is_empty() => attachment.push(w),
+            "border-box" | "padding-box" | "content-box" if boxes.len() < 2 => boxes.push(w),
+            "left" | "right" | "top" | "bottom" | "center" => position.push(w),
+            _ if color.is_none() && is_color(w) => {
+                color = Some(parse_value("background-color", word.clone())?)
+            }
+            _ if image.is_empty() && (w == "none" || w.contains('(')) => image.push(word.as_str()),
+            _ if translate_length(w).is_ok() => position.push(w),
+            _ => return Err(format!("unexpected '{}' in background", w)),
+        }
+    }
+    if after_slash {
+        return Err(String::from("expected a size after '/'"));
+    }
+
+    let longhand = |property: &str, words: &[&str], default: &str| {
+        let value = match words.is_empty() {
+            true => String::from(default),
+            false => words.join(" "),
+        };
+        parse_value(property, value).map(|v| (String::from(property), v))
+    };
+
+    Ok(vec![
+        (
+            String::from("background-color"),
+            color.unwrap_or_else(|| keyword_value("background-color", "transparent")),
+        ),
+        longhand("background-image", &image, "none")?,
+        longhand("background-repeat", &repeat, "repeat")?,
+        longhand("background-attachment", &attachment, "scroll")?,
+        longhand("background-position", &position, "0% 0%")?,
+        longhand("background-size", &size, "auto")?,
+        longhand("background-origin", &boxes[..boxes.len().min(1)], "padding-box")?,
+        longhand("background-clip", &boxes[boxes.len().max(1) - 1..], "border-box")?,
+    ])
+}
+
+fn is_background_size(word: &str) -> bool {
+    match word {
+        "auto" | "cover" | "contain" => true,
+        _ => translate_length(word).is_ok(),
+    }
+}
+
+// `[style || variant || weight || stretch]? size [/ line-height]? family`, or
+// one of the system font keywords.
+fn expand_font(words: &[String]) -> Result<Longhands, String> {
+    let normal = || Value::Keyword(String::from("normal"));
+    let lower = lowercase(words);
+
+    if words.len() == 1 && SYSTEM_FONTS.contains(&lower[0].as_ref()) {
+        return Ok(font_longhands(
+            [None; 4],
+            keyword_value("font-size", "medium"),
+            normal(),
+            Value::Keyword(lower[0].clone()),
+        ));
+    }
+
+    // Style, variant, weight and stretch; `normal` may stand for any of them.
+    let mut prefix = [None; 4];
+    let mut i = 0;
+    while let Some(word) = lower.get(i) {
+        let w = word.as_ref();
+        let slot = match w {
+            "normal" => None,
+            "italic" | "oblique" => Some(0),
+            "small-caps" => Some(1),
+            "bold" | "bolder" | "lighter" => Some(2),
+            _ if is_font_weight_number(w) => Some(2),
+            _ if FONT_STRETCHES.contains(&w) => Some(3),
+            _ => break,
+        };
+
+        if i == 4 || slot.map_or(false, |s| prefix[s].is_some()) {
+            return Err(format!("unexpected '{}' in font", w));
+        }
+        if let Some(s) = slot {
+            prefix[s] = Some(w);
+        }
+        i += 1;
+    }
+
+    let size = match words.get(i) {
+        Some(w) if is_font_size(&w.to_lowercase()) => parse_value("font-size", w.clone())?,
+        Some(w) => return Err(format!("expected a font size, got '{}'", w)),
+        None => return Err(String::from("font needs a size and a family")),
+    };
+    i += 1;
+
+    let mut line_height = normal();
+    if words.get(i).map_or(false, |w| w == "/") {
+        line_height = match words.get(i + 1) {
+            Some(w) => parse_value("line-height", w.clone())?,
+            None => return Err(String::from("expected a line height after '/'")),
+        };
+        i += 2;
+    }
+
+    let mut family = String::new();
+    for word in &words[i..] {
+        if !family.is_empty() && word != "," {
+            family.push(' ');
+        }
+        family.push_str(word);
+    }
+    if family.is_empty() || family.starts_with(',') || family.ends_with(',') {
+        return Err(String::from("font needs a family"));
+    }
+
+    let family = parse_value("font-family", family)?;
+    Ok(font_longhands(prefix, size, line_height, family))
+}
+
+fn font_longhands(
+    prefix: [Option<&str>; 4],
+    size: Value,
+    line_height: Value,
+    family: Value,
+) -> Longhands {
+    // Typed the way the longhand itself would be, so `700` is a number.
+    let prefixed = |i: usize, property: &str| {
+        let value = keyword_value(property, prefix[i].unwrap_or("normal"));
+        (String::from(property), value)
+    };
+
+    vec![
+        prefixed(0, "font-style"),
+        prefixed(1, "font-variant"),
+        prefixed(2, "font-weight"),
+        prefixed(3, "font-stretch"),
+        (String::from("font-size"), size),
+        (String::from("line-height"), line_height),
+        (String::from("font-family"), family),
+    ]
+}
+
+fn is_font_size(word: &str) -> bool {
+    FONT_SIZE_KEYWORDS.contains(&word) || translate_length(word).is_ok()
+}
+
+fn is_font_weight_number(word: &str) -> bool {
+    match parse_number(word) {
+        Some((n, "")) => n >= 1.0 && n <= 1000.0,
+        _ => false,
+    }
+}
+
+fn is_color(word: &str) -> bool {
+    word == "currentcolor" || translate_color(word).is_ok()
+}
+
+// The value a longhand gets from one of its own keywords.
+fn keyword_value(property: &str, keyword: &str) -> Value {
+    parse_value(property, String::from(keyword))
+        .unwrap_or_else(|_| Value::Keyword(String::from(keyword)))
+}
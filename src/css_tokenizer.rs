@@ -0,0 +1,548 @@
+// Splits CSS source into tokens following the tokenization algorithm of CSS
+// Syntax Level 3. Comments never make it into the token stream, and each
+// token remembers the text it was read from so that values can be handed on
+// as written.
+#[derive(PartialEq, Clone, Debug)]
+pub enum TokenKind {
+    Ident(String),
+    Function(String),
+    AtKeyword(String),
+    Hash(String),
+    String(String),
+    BadString,
+    Url(String),
+    BadUrl,
+    Delim(char),
+    Number(f32),
+    Percentage(f32),
+    Dimension(f32, String),
+    Whitespace,
+    Cdo,
+    Cdc,
+    Colon,
+    Semicolon,
+    Comma,
+    OpenSquare,
+    CloseSquare,
+    OpenParen,
+    CloseParen,
+    OpenCurly,
+    CloseCurly,
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub text: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+pub struct Tokenizer {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Tokenizer {
+    pub fn new(css: &str) -> Tokenizer {
+        let mut chars = Vec::with_capacity(css.len());
+        let mut input = css.chars().peekable();
+
+        while let Some(c) = input.next() {
+            match c {
+                '\r' => {
+                    if input.peek() == Some(&'\n') {
+                        input.next();
+                    }
+                    chars.push('\n');
+                }
+                '\u{c}' => chars.push('\n'),
+                '\0' => chars.push('\u{fffd}'),
+                _ => chars.push(c),
+            }
+        }
+
+        Tokenizer {
+            chars,
+            pos: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    pub fn tokenize(mut self) -> Vec<Token> {
+        let mut tokens = Vec::new();
+
+        loop {
+            self.consume_comments();
+            let c = match self.peek() {
+                Some(c) => c,
+                None => break,
+            };
+
+            let (start, line, column) = (self.pos, self.line, self.column);
+            let kind = self.consume_token(c);
+            tokens.push(Token {
+                kind,
+                text: self.chars[start..self.pos].iter().collect(),
+                line,
+                column,
+            });
+        }
+        tokens
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.peek_at(0)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<char> {
+        self.chars.get(self.pos + offset).cloned()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+
+        match c {
+            Some('\n') => {
+                self.line += 1;
+                self.column = 1;
+            }
+            Some(_) => self.column += 1,
+            None => return None,
+        }
+        self.pos += 1;
+        c
+    }
+
+    fn consume_comments(&mut self) {
+        while self.peek() == Some('/') && self.peek_at(1) == Some('*') {
+            self.next();
+            self.next();
+
+            loop {
+                match self.next() {
+                    Some('*') if self.peek() == Some('/') => {
+                        self.next();
+                        break;
+                    }
+                    Some(_) => {}
+                    None => break,
+                }
+            }
+        }
+    }
+
+    fn consume_token(&mut self, c: char) -> TokenKind {
+        match c {
+            _ if is_whitespace(c) => {
+                while self.peek().map_or(false, is_whitespace) {
+                    self.next();
+                }
+                TokenKind::Whitespace
+            }
+            _ if c.is_ascii_digit() => self.consume_numeric(),
+            '+' | '-' | '.' if self.starts_number() => self.consume_numeric(),
+            '-' if self.peek_at(1) == Some('-') && self.peek_at(2) == Some('>') => {
+                self.advance(3);
+                TokenKind::Cdc
+            }
+            '-' | '\\' if self.starts_ident(0) => self.consume_ident_like(),
+            _ if is_name_start(c) => self.consume_ident_like(),
+            '<' if self.peek_at(1) == Some('!') && self.peek_at(2) == Some('-')
+                && self.peek_at(3) == Some('-') =>
+            {
+                self.advance(4);
+                TokenKind::Cdo
+            }
+            _ => {
+                self.next();
+                self.consume_single(c)
+            }
+        }
+    }
+
+    fn consume_single(&mut self, c: char) -> TokenKind {
+        match c {
+            '"' | '\'' => self.consume_string(c),
+            '#' if self.peek().map_or(false, is_name_char) || self.starts_escape(0) => {
+                TokenKind::Hash(self.consume_name())
+            }
+            '@' if self.starts_ident(0) => TokenKind::AtKeyword(self.consume_name()),
+            '(' => TokenKind::OpenParen,
+            ')' => TokenKind::CloseParen,
+            '[' => TokenKind::OpenSquare,
+            ']' => TokenKind::CloseSquare,
+            '{' => TokenKind::OpenCurly,
+            '}' => TokenKind::CloseCurly,
+            ',' => TokenKind::Comma,
+            ':' => TokenKind::Colon,
+            ';' => TokenKind::Semicolon,
+            _ => TokenKind::Delim(c),
+        }
+    }
+
+    fn advance(&mut self, count: usize) {
+        for _ in 0..count {
+            self.next();
+        }
+    }
+
+    fn starts_escape(&self, offset: usize) -> bool {
+        self.peek_at(offset) == Some('\\') && self.peek_at(offset + 1) != Some('\n')
+    }
+
+    fn starts_ident(&self, offset: usize) -> bool {
+        match self.peek_at(offset) {
+            Some('-') => match self.peek_at(offset + 1) {
+                Some(c) if is_name_start(c) || c == '-' => true,
+                _ => self.starts_escape(offset + 1),
+            },
+            Some('\\') => self.starts_escape(offset),
+            Some(c) => is_name_start(c),
+            None => false,
+        }
+    }
+
+    fn starts_number(&self) -> bool {
+        let is_digit = |c: Option<char>| c.map_or(false, |c| c.is_ascii_digit());
+
+        match self.peek() {
+            Some('+') | Some('-') => match self.peek_at(1) {
+                Some('.') => is_digit(self.peek_at(2)),
+                c => is_digit(c),
+            },
+            Some('.') => is_digit(self.peek_at(1)),
+            c => is_digit(c),
+        }
+    }
+
+    fn consume_numeric(&mut self) -> TokenKind {
+        let number = self.consume_number();
+
+        if self.starts_ident(0) {
+            TokenKind::Dimension(number, self.consume_name())
+        } else if self.peek() == Some('%') {
+            self.next();
+            TokenKind::Percentage(number)
+        } else {
+            TokenKind::Number(number)
+        }
+    }
+
+    fn consume_number(&mut self) -> f32 {
+        let mut repr = String::new();
+        let is_digit = |c: Option<char>| c.map_or(false, |c| c.is_ascii_digit());
+
+        if let Some(c @ '+') | Some(c @ '-') = self.peek() {
+            self.next();
+            repr.push(c);
+        }
+        self.consume_digits(&mut repr);
+
+        if self.peek() == Some('.') && is_digit(self.peek_at(1)) {
+            self.next();
+            repr.push('.');
+            self.consume_digits(&mut repr);
+        }
+
+        // An exponent only counts when digits follow, so `2em` is a dimension.
+        if let Some(e @ 'e') | Some(e @ 'E') = self.peek() {
+            let sign = match self.peek_at(1) {
+                Some(s @ '+') | Some(s @ '-') => Some(s),
+                _ => None,
+            };
+            let digits_at = if sign.is_some() { 2 } else { 1 };
+
+            if is_digit(self.peek_at(digits_at)) {
+                self.advance(digits_at);
+                repr.push(e);
+                repr.extend(sign);
+                self.consume_digits(&mut repr);
+            }
+        }
+
+        repr.parse().unwrap_or(0.0)
+    }
+
+    fn consume_digits(&mut self, repr: &mut String) {
+        while let Some(c) = self.peek() {
+            if !c.is_ascii_digit() {
+                break;
+            }
+            self.next();
+            repr.push(c);
+        }
+    }
+
+    fn consume_name(&mut self) -> String {
+        let mut name = String::new();
+
+        loop {
+            match self.peek() {
+                Some(c) if is_name_char(c) => {
+                    self.next();
+                    name.push(c);
+                }
+                Some('\\') if self.starts_escape(0) => {
+                    self.next();
+                    name.push(self.consume_escape());
+                }
+                _ => break,
+            }
+        }
+        name
+    }
+
+    // Called with the backslash already consumed.
+    fn consume_escape(&mut self) -> char {
+        let mut hex = String::new();
+
+        while hex.len() < 6 && self.peek().map_or(false, |c| c.is_digit(16)) {
+            hex.push(self.next().unwrap());
+        }
+
+        if hex.is_empty() {
+            return self.next().unwrap_or('\u{fffd}');
+        }
+
+        if self.peek().map_or(false, is_whitespace) {
+            self.next();
+        }
+        match u32::from_str_radix(&hex, 16).ok().and_then(::std::char::from_u32) {
+            Some('\0') | None => '\u{fffd}',
+            Some(c) => c,
+        }
+    }
+
+    fn consume_ident_like(&mut self) -> TokenKind {
+        let name = self.consume_name();
+
+        if self.peek() != Some('(') {
+            return TokenKind::Ident(name);
+        }
+        self.next();
+
+        if !name.eq_ignore_ascii_case("url") {
+            return TokenKind::Function(name);
+        }
+
+        // A quoted url is an ordinary function holding a string.
+        while self.peek().map_or(false, is_whitespace)
+            && self.peek_at(1).map_or(false, is_whitespace)
+        {
+            self.next();
+        }
+        let quote_at = if self.peek().map_or(false, is_whitespace) { 1 } else { 0 };
+        match self.peek_at(quote_at) {
+            Some('"') | Some('\'') => TokenKind::Function(name),
+            _ => self.consume_url(),
+        }
+    }
+
+    fn consume_url(&mut self) -> TokenKind {
+        let mut url = String::new();
+
+        while self.peek().map_or(false, is_whitespace) {
+            self.next();
+        }
+
+        loop {
+            match self.next() {
+                Some(')') | None => return TokenKind::Url(url),
+                Some(c) if is_whitespace(c) => {
+                    while self.peek().map_or(false, is_whitespace) {
+                        self.next();
+                    }
+                    return match self.peek() {
+                        Some(')') | None => {
+                            self.next();
+                            TokenKind::Url(url)
+                        }
+                        _ => self.consume_bad_url(),
+                    };
+                }
+                Some('"') | Some('\'') | Some('(') => return self.consume_bad_url(),
+                Some('\\') => {
+                    if self.peek() == Some('\n') {
+                        return self.consume_bad_url();
+                    }
+                    url.push(self.consume_escape());
+                }
+                Some(c) if is_non_printable(c) => return self.consume_bad_url(),
+                Some(c) => url.push(c),
+            }
+        }
+    }
+
+    fn consume_bad_url(&mut self) -> TokenKind {
+        loop {
+            match self.next() {
+                Some(')') | None => return TokenKind::BadUrl,
+                Some('\\') if self.peek().map_or(false, |c| c != '\n') => {
+                    self.consume_escape();
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    // Called with the opening quote already consumed.
+    fn consume_string(&mut self, quote: char) -> TokenKind {
+        let mut string = String::new();
+
+        loop {
+            match self.peek() {
+                Some(c) if c == quote => {
+                    self.next();
+                    return TokenKind::String(string);
+                }
+                None => return TokenKind::String(string),
+                // The newline is left for the next token.
+                Some('\n') => return TokenKind::BadString,
+                Some('\\') => {
+                    self.next();
+                    match self.peek() {
+                        None => {}
+                        Some('\n') => {
+                            self.next();
+                        }
+                        Some(_) => string.push(self.consume_escape()),
+                    }
+                }
+                Some(c) => {
+                    self.next();
+                    string.push(c);
+                }
+            }
+        }
+    }
+}
+
+fn is_whitespace(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\n'
+}
+
+fn is_name_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c >= '\u{80}' || c == '_'
+}
+
+fn is_name_char(c: char) -> bool {
+    is_name_start(c) || c.is_ascii_digit() || c == '-'
+}
+
+fn is_non_printable(c: char) -> bool {
+    c <= '\u{8}' || c == '\u{b}' || (c >= '\u{e}' && c <= '\u{1f}') || c == '\u{7f}'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(css: &str) -> Vec<TokenKind> {
+        Tokenizer::new(css)
+            .tokenize()
+            .into_iter()
+            .map(|t| t.kind)
+            .filter(|k| *k != TokenKind::Whitespace)
+            .collect()
+    }
+
+    fn dimension(value: f32, unit: &str) -> TokenKind {
+        TokenKind::Dimension(value, String::from(unit))
+    }
+
+    #[test]
+    fn numbers_and_exponents() {
+        assert_eq!(
+            kinds("1e2px .5rem -3 +4.5% 1E-1 1e 2.em"),
+            vec![
+                dimension(100.0, "px"),
+                dimension(0.5, "rem"),
+                TokenKind::Number(-3.0),
+                TokenKind::Percentage(4.5),
+                TokenKind::Number(0.1),
+                dimension(1.0, "e"),
+                TokenKind::Number(2.0),
+                TokenKind::Delim('.'),
+                TokenKind::Ident(String::from("em")),
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        assert_eq!(
+            kinds("\\41 bc #\\31 x \"a\\\"b\" 'c\\\nd' \\\n \\"),
+            vec![
+                TokenKind::Ident(String::from("Abc")),
+                TokenKind::Hash(String::from("1x")),
+                TokenKind::String(String::from("a\"b")),
+                TokenKind::String(String::from("cd")),
+                TokenKind::Delim('\\'),
+                TokenKind::Ident(String::from("\u{fffd}")),
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_strings_end_at_the_newline() {
+        assert_eq!(
+            kinds("\"abc\nd; 'e'"),
+            vec![
+                TokenKind::BadString,
+                TokenKind::Ident(String::from("d")),
+                TokenKind::Semicolon,
+                TokenKind::String(String::from("e")),
+            ]
+        );
+    }
+
+    #[test]
+    fn urls() {
+        assert_eq!(
+            kinds("url(  a.png ) URL(b\\)c) url(\"d\")"),
+            vec![
+                TokenKind::Url(String::from("a.png")),
+                TokenKind::Url(String::from("b)c")),
+                TokenKind::Function(String::from("url")),
+                TokenKind::String(String::from("d")),
+                TokenKind::CloseParen,
+            ]
+        );
+
+        // A bad url swallows everything up to its closing paren.
+        assert_eq!(
+            kinds("url(a b) url(a\"b) x"),
+            vec![
+                TokenKind::BadUrl,
+                TokenKind::BadUrl,
+                TokenKind::Ident(String::from("x")),
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_are_dropped_and_positions_kept() {
+        let tokens = Tokenizer::new("a /* x\ny */{\r\n  b:c }").tokenize();
+        let found: Vec<_> = tokens
+            .iter()
+            .filter(|t| t.kind != TokenKind::Whitespace)
+            .map(|t| (t.kind.clone(), t.line, t.column))
+            .collect();
+
+        assert_eq!(
+            found,
+            vec![
+                (TokenKind::Ident(String::from("a")), 1, 1),
+                (TokenKind::OpenCurly, 2, 5),
+                (TokenKind::Ident(String::from("b")), 3, 3),
+                (TokenKind::Colon, 3, 4),
+                (TokenKind::Ident(String::from("c")), 3, 5),
+                (TokenKind::CloseCurly, 3, 7),
+            ]
+        );
+    }
+}
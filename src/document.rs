@@ -0,0 +1,211 @@
+use css::{Origin, Stylesheet};
+use css_parser::CssParser;
+use dom::{ElementData, Node, NodeType, QuirksMode};
+use html_parse::HtmlParser;
+
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+// The defaults every page starts from, ahead of its own stylesheets.
+const USER_AGENT_CSS: &'static str = "
+html, body, div, p, address, article, aside, blockquote, dd, dl, dt, fieldset,
+figcaption, figure, footer, form, h1, h2, h3, h4, h5, h6, header, hr, li, main,
+nav, ol, pre, section, table, tr, ul {
+    display: block;
+}
+
+head, link, meta, script, style, template, title {
+    display: none;
+}
+
+body {
+    margin-top: 8px;
+    margin-right: 8px;
+    margin-bottom: 8px;
+    margin-left: 8px;
+}
+
+p, dl, ol, ul, pre, blockquote {
+    margin-top: 16px;
+    margin-bottom: 16px;
+}
+
+h1 {
+    font-size: 32px;
+    margin-top: 21px;
+    margin-bottom: 21px;
+}
+
+h2 {
+    font-size: 24px;
+    margin-top: 20px;
+    margin-bottom: 20px;
+}
+
+h3 {
+    font-size: 19px;
+    margin-top: 19px;
+    margin-bottom: 19px;
+}
+
+h4, h5, h6 {
+    margin-top: 21px;
+    margin-bottom: 21px;
+}
+
+ol, ul {
+    padding-left: 40px;
+}
+";
+
+// Added in quirks mode, where tables don't inherit the font and text
+// alignment of the page around them.
+const QUIRKS_CSS: &'static str = "
+table {
+    font-size: initial;
+    font-style: initial;
+    font-weight: initial;
+    line-height: initial;
+    white-space: initial;
+    text-align: initial;
+}
+";
+
+pub struct Document {
+    pub nodes: Vec<Node>,
+    pub quirks_mode: QuirksMode,
+    pub stylesheets: Vec<Stylesheet>,
+    pub warnings: Vec<String>,
+}
+
+impl Document {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Document> {
+        let path = path.as_ref();
+        let html_input = read_file(path)?;
+        let base = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+
+        Ok(Document::from_html(&html_input, &base))
+    }
+
+    pub fn from_html(html: &str, base: &Path) -> Document {
+        let mut parser = HtmlParser::new(html);
+        let mut document = Document {
+            nodes: parser.parse_nodes(),
+            quirks_mode: parser.quirks_mode(),
+            stylesheets: Vec::new(),
+            warnings: Vec::new(),
+        };
+
+        document.load_stylesheet(USER_AGENT_CSS, "user agent stylesheet", Origin::UserAgent);
+        if document.quirks_mode == QuirksMode::Quirks {
+            document.load_stylesheet(QUIRKS_CSS, "quirks mode stylesheet", Origin::UserAgent);
+        }
+
+        let mut sources = Vec::new();
+        for node in &document.nodes {
+            collect_style_sources(node, base, &mut sources);
+        }
+
+        for source in sources {
+            match source {
+                StyleSource::Inline(css) => document.add_stylesheet(&css, "<style>"),
+                StyleSource::Linked(path) => match read_file(&path) {
+                    Ok(css) => document.add_stylesheet(&css, &path.display().to_string()),
+                    Err(e) => document
+                        .warnings
+                        .push(format!("stylesheet: {}, error: {}", path.display(), e)),
+                },
+            }
+        }
+
+        document
+    }
+
+    // Parses `css` after the sheets already loaded; anything the parser had
+    // to drop is kept as a warning naming `source`.
+    pub fn add_stylesheet(&mut self, css: &str, source: &str) {
+        self.load_stylesheet(css, source, Origin::Author);
+    }
+
+    fn load_stylesheet(&mut self, css: &str, source: &str, origin: Origin) {
+        let mut parser = CssParser::new(css);
+        let mut stylesheet = parser.parse_stylesheet();
+        stylesheet.set_origin(origin);
+        self.stylesheets.push(stylesheet);
+
+        for error in parser.errors() {
+            self.warnings.push(format!("stylesheet: {}:{}", source, error));
+        }
+    }
+
+    pub fn root(&self) -> Option<&Node> {
+        self.nodes.iter().find(|n| match n.node_type {
+            NodeType::Element(_) => true,
+            _ => false,
+        })
+    }
+}
+
+enum StyleSource {
+    Inline(String),
+    Linked(PathBuf),
+}
+
+fn collect_style_sources(node: &Node, base: &Path, sources: &mut Vec<StyleSource>) {
+    if let NodeType::Element(ref e) = node.node_type {
+        match e.tag_name.to_lowercase().as_ref() {
+            "link" if is_stylesheet_link(e) => if let Some(href) = e.get_attribute("href") {
+                sources.push(StyleSource::Linked(base.join(href.trim())));
+            },
+            "style" if is_css_type(e) => {
+                sources.push(StyleSource::Inline(text_content(node)));
+                return;
+            }
+            _ => {}
+        }
+    }
+
+    for child in &node.children {
+        collect_style_sources(child, base, sources);
+    }
+}
+
+fn is_stylesheet_link(element: &ElementData) -> bool {
+    let rel = match element.get_attribute("rel") {
+        Some(r) => r.to_lowercase(),
+        None => return false,
+    };
+    let mut tokens = rel.split_whitespace();
+
+    tokens.clone().any(|t| t == "stylesheet") && !tokens.any(|t| t == "alternate")
+        && is_css_type(element)
+}
+
+fn is_css_type(element: &ElementData) -> bool {
+    match element.get_attribute("type") {
+        Some(t) => t.is_empty() || t.eq_ignore_ascii_case("text/css"),
+        None => true,
+    }
+}
+
+fn text_content(node: &Node) -> String {
+    let mut text = String::new();
+
+    for child in &node.children {
+        match child.node_type {
+            NodeType::Text(ref t) => text.push_str(t),
+            NodeType::Element(_) => text.push_str(&text_content(child)),
+            _ => {}
+        }
+    }
+    text
+}
+
+fn read_file(path: &Path) -> io::Result<String> {
+    let mut file_reader = BufReader::new(File::open(path)?);
+
+    let mut input = String::new();
+    file_reader.read_to_string(&mut input)?;
+    Ok(input)
+}
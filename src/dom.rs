@@ -12,6 +12,7 @@ pub enum NodeType {
     Text(String),
     Element(ElementData),
     Comment(String),
+    Doctype(DoctypeData),
 }
 
 #[derive(PartialEq, Eq, Clone)]
@@ -32,14 +33,58 @@ impl ElementData {
         self.attributes.get("id")
     }
 
+    pub fn get_attribute(&self, name: &str) -> Option<&String> {
+        self.attributes.get(name)
+    }
+
+    pub fn is_void(&self) -> bool {
+        match self.tag_name.as_ref() {
+            "area" | "base" | "basefont" | "bgsound" | "br" | "col" | "embed" | "hr" | "img"
+            | "input" | "keygen" | "link" | "meta" | "param" | "source" | "track" | "wbr" => true,
+            _ => false,
+        }
+    }
+
     pub fn get_classes(&self) -> HashSet<&str> {
         match self.attributes.get("class") {
-            Some(s) => s.split(' ').collect(),
+            Some(s) => s.split_whitespace().collect(),
             None => HashSet::new(),
         }
     }
 }
 
+#[derive(PartialEq, Eq, Clone)]
+pub struct DoctypeData {
+    pub name: String,
+    pub public_id: String,
+    pub system_id: String,
+}
+
+impl DoctypeData {
+    pub fn new(name: String, public_id: String, system_id: String) -> DoctypeData {
+        DoctypeData {
+            name,
+            public_id,
+            system_id,
+        }
+    }
+}
+
+// How closely the page asked to follow the standards, decided by its doctype.
+// A few rendering rules still differ for pages written for old browsers.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum QuirksMode {
+    NoQuirks,
+    LimitedQuirks,
+    Quirks,
+}
+
+impl Default for QuirksMode {
+    fn default() -> QuirksMode {
+        QuirksMode::NoQuirks
+    }
+}
+
 pub type AttrMap = HashMap<String, String>;
 
 impl Node {
@@ -62,6 +107,7 @@ impl fmt::Debug for NodeType {
         match *self {
             NodeType::Text(ref t) | NodeType::Comment(ref t) => write!(f, "{}", t),
             NodeType::Element(ref e) => write!(f, "{:?}", e),
+            NodeType::Doctype(ref d) => write!(f, "{:?}", d),
         }
     }
 }
@@ -77,6 +123,24 @@ impl fmt::Debug for ElementData {
     }
 }
 
+impl fmt::Debug for DoctypeData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<!DOCTYPE")?;
+
+        if !self.name.is_empty() {
+            write!(f, " {}", self.name)?;
+        }
+        if !self.public_id.is_empty() {
+            write!(f, " PUBLIC \"{}\"", self.public_id)?;
+        } else if !self.system_id.is_empty() {
+            write!(f, " SYSTEM")?;
+        }
+        if !self.system_id.is_empty() {
+            write!(f, " \"{}\"", self.system_id)?;
+        }
+        write!(f, ">")
+    }
+}
 
 pub fn pretty_print(n: &Node, indent_size: usize) {
     let indent = (0..indent_size).map(|_| " ").collect::<String>();
@@ -85,6 +149,7 @@ pub fn pretty_print(n: &Node, indent_size: usize) {
         NodeType::Element(ref e) => println!("{}{:?}", indent, e),
         NodeType::Text(ref t) => println!("{}{}", indent, t),
         NodeType::Comment(ref c) => println!("{}<!--{}-->", indent, c),
+        NodeType::Doctype(ref d) => println!("{}{:?}", indent, d),
     }
 
     for child in n.children.iter() {
@@ -92,7 +157,7 @@ pub fn pretty_print(n: &Node, indent_size: usize) {
     }
 
     match n.node_type {
-        NodeType::Element(ref e) => println!("{}<{}/>", indent, e.tag_name),
+        NodeType::Element(ref e) if !e.is_void() => println!("{}<{}/>", indent, e.tag_name),
         _ => {}
     }
 }
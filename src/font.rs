@@ -0,0 +1,29 @@
+// Metrics for the engine's single built-in face, a monospace bitmap font
+// whose glyphs are scaled to fill the em box. Layout and the rasterizer both
+// go through these numbers so text lands where it was measured.
+#[derive(Clone, Copy, Debug)]
+pub struct FontMetrics {
+    pub size: f32,
+    pub ascent: f32,
+    pub descent: f32,
+    pub advance: f32,
+}
+
+impl FontMetrics {
+    pub fn new(size: f32) -> FontMetrics {
+        FontMetrics {
+            size,
+            ascent: size * 0.8,
+            descent: size * 0.2,
+            advance: size * 0.6,
+        }
+    }
+
+    pub fn text_width(&self, text: &str) -> f32 {
+        text.chars().count() as f32 * self.advance
+    }
+
+    pub fn normal_line_height(&self) -> f32 {
+        self.size * 1.2
+    }
+}
@@ -1,268 +1,1634 @@
-use dom::{AttrMap, ElementData, Node, NodeType};
+use dom::{AttrMap, DoctypeData, ElementData, Node, NodeType, QuirksMode};
+use html_tokenizer::{is_whitespace, Doctype, State, Tag, Token, Tokenizer};
 
-use std::iter::Peekable;
-use std::str::Chars;
+use std::mem;
 
+// Builds the document tree from the tokenizer's output following the tree
+// construction stage of the HTML Standard, so misnested and omitted tags end
+// up where a browser would put them. Templates, framesets, `select` and
+// foreign (SVG and MathML) content get no special treatment.
+#[derive(PartialEq, Clone, Copy, Debug)]
+enum InsertionMode {
+    Initial,
+    BeforeHtml,
+    BeforeHead,
+    InHead,
+    InHeadNoscript,
+    AfterHead,
+    InBody,
+    Text,
+    InTable,
+    InTableText,
+    InCaption,
+    InColumnGroup,
+    InTableBody,
+    InRow,
+    InCell,
+    AfterBody,
+    AfterAfterBody,
+}
+
+// Nodes live in an arena while the tree is built, since the algorithms below
+// move them between parents; index 0 is the document itself.
+struct TreeNode {
+    data: NodeData,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
 
-pub struct HtmlParser<'a> {
-    chars: Peekable<Chars<'a>>,
-    node_q: Vec<String>,
+enum NodeData {
+    Document,
+    Element(String, AttrMap),
+    Text(String),
+    Comment(String),
+    Doctype(DoctypeData),
 }
 
-impl<'a> HtmlParser<'a> {
+enum Formatting {
+    Marker,
+    Element(usize, Tag),
+}
+
+const SPECIAL: &'static [&'static str] = &[
+    "address", "applet", "area", "article", "aside", "base", "basefont", "bgsound",
+    "blockquote", "body", "br", "button", "caption", "center", "col", "colgroup", "dd",
+    "details", "dir", "div", "dl", "dt", "embed", "fieldset", "figcaption", "figure", "footer",
+    "form", "frame", "frameset", "h1", "h2", "h3", "h4", "h5", "h6", "head", "header", "hgroup",
+    "hr", "html", "iframe", "img", "input", "keygen", "li", "link", "listing", "main",
+    "marquee", "menu", "meta", "nav", "noembed", "noframes", "noscript", "object", "ol", "p",
+    "param", "plaintext", "pre", "script", "search", "section", "select", "source", "style",
+    "summary", "table", "tbody", "td", "template", "textarea", "tfoot", "th", "thead", "title",
+    "tr", "track", "ul", "wbr", "xmp",
+];
+
+const SCOPE: &'static [&'static str] = &[
+    "applet", "caption", "html", "table", "td", "th", "marquee", "object", "template",
+];
+
+const HEADINGS: &'static [&'static str] = &["h1", "h2", "h3", "h4", "h5", "h6"];
+
+const IMPLIED_END: &'static [&'static str] = &[
+    "dd", "dt", "li", "optgroup", "option", "p", "rb", "rp", "rt", "rtc",
+];
+
+const TABLE_SECTIONS: &'static [&'static str] = &["tbody", "tfoot", "thead"];
+
+const TABLE_CONTENT: &'static [&'static str] = &[
+    "caption", "col", "colgroup", "tbody", "td", "tfoot", "th", "thead", "tr",
+];
+
+const QUIRKS_PUBLIC_IDS: &'static [&'static str] = &[
+    "-//w3o//dtd w3 html strict 3.0//en//",
+    "-/w3c/dtd html 4.0 transitional/en",
+    "html",
+];
+
+const QUIRKS_PUBLIC_PREFIXES: &'static [&'static str] = &[
+    "+//silmaril//dtd html pro v0r11 19970101//",
+    "-//as//dtd html 3.0 aswedit + extensions//",
+    "-//advasoft ltd//dtd html 3.0 aswedit + extensions//",
+    "-//ietf//dtd html 2.0 level 1//",
+    "-//ietf//dtd html 2.0 level 2//",
+    "-//ietf//dtd html 2.0 strict level 1//",
+    "-//ietf//dtd html 2.0 strict level 2//",
+    "-//ietf//dtd html 2.0 strict//",
+    "-//ietf//dtd html 2.0//",
+    "-//ietf//dtd html 2.1e//",
+    "-//ietf//dtd html 3.0//",
+    "-//ietf//dtd html 3.2 final//",
+    "-//ietf//dtd html 3.2//",
+    "-//ietf//dtd html 3//",
+    "-//ietf//dtd html level 0//",
+    "-//ietf//dtd html level 1//",
+    "-//ietf//dtd html level 2//",
+    "-//ietf//dtd html level 3//",
+    "-//ietf//dtd html strict level 0//",
+    "-//ietf//dtd html strict level 1//",
+    "-//ietf//dtd html strict level 2//",
+    "-//ietf//dtd html strict level 3//",
+    "-//ietf//dtd html strict//",
+    "-//ietf//dtd html//",
+    "-//metrius//dtd metrius presentational//",
+    "-//microsoft//dtd internet explorer 2.0 html strict//",
+    "-//microsoft//dtd internet explorer 2.0 html//",
+    "-//microsoft//dtd internet explorer 2.0 tables//",
+    "-//microsoft//dtd internet explorer 3.0 html strict//",
+    "-//microsoft//dtd internet explorer 3.0 html//",
+    "-//microsoft//dtd internet explorer 3.0 tables//",
+    "-//netscape comm. corp.//dtd html//",
+    "-//netscape comm. corp.//dtd strict html//",
+    "-//o'reilly and associates//dtd html 2.0//",
+    "-//o'reilly and associates//dtd html extended 1.0//",
+    "-//o'reilly and associates//dtd html extended relaxed 1.0//",
+    "-//sq//dtd html 2.0 hotmetal + extensions//",
+    "-//softquad software//dtd hotmetal pro 6.0::19990601::extensions to html 4.0//",
+    "-//softquad//dtd hotmetal pro 4.0::19971010::extensions to html 4.0//",
+    "-//spyglass//dtd html 2.0 extended//",
+    "-//sun microsystems corp.//dtd hotjava html//",
+    "-//sun microsystems corp.//dtd hotjava strict html//",
+    "-//w3c//dtd html 3 1995-03-24//",
+    "-//w3c//dtd html 3.2 draft//",
+    "-//w3c//dtd html 3.2 final//",
+    "-//w3c//dtd html 3.2//",
+    "-//w3c//dtd html 3.2s draft//",
+    "-//w3c//dtd html 4.0 frameset//",
+    "-//w3c//dtd html 4.0 transitional//",
+    "-//w3c//dtd html experimental 19960712//",
+    "-//w3c//dtd html experimental 970421//",
+    "-//w3c//dtd w3 html//",
+    "-//w3o//dtd w3 html 3.0//",
+    "-//webtechs//dtd mozilla html 2.0//",
+    "-//webtechs//dtd mozilla html//",
+];
+
+const HTML_401_PREFIXES: &'static [&'static str] = &[
+    "-//w3c//dtd html 4.01 frameset//",
+    "-//w3c//dtd html 4.01 transitional//",
+];
+
+const XHTML_10_PREFIXES: &'static [&'static str] = &[
+    "-//w3c//dtd xhtml 1.0 frameset//",
+    "-//w3c//dtd xhtml 1.0 transitional//",
+];
+
+pub struct HtmlParser {
+    tokenizer: Tokenizer,
+    mode: InsertionMode,
+    original_mode: InsertionMode,
+    nodes: Vec<TreeNode>,
+    open: Vec<usize>,
+    formatting: Vec<Formatting>,
+    head: Option<usize>,
+    form: Option<usize>,
+    frameset_ok: bool,
+    foster_parenting: bool,
+    skip_newline: bool,
+    table_text: Vec<char>,
+    quirks_mode: QuirksMode,
+}
+
+impl HtmlParser {
     pub fn new(full_html: &str) -> HtmlParser {
         HtmlParser {
-            chars: full_html.chars().peekable(),
-            node_q: Vec::new(),
+            tokenizer: Tokenizer::new(full_html),
+            mode: InsertionMode::Initial,
+            original_mode: InsertionMode::Initial,
+            nodes: vec![TreeNode {
+                data: NodeData::Document,
+                parent: None,
+                children: Vec::new(),
+            }],
+            open: Vec::new(),
+            formatting: Vec::new(),
+            head: None,
+            form: None,
+            frameset_ok: true,
+            foster_parenting: false,
+            skip_newline: false,
+            table_text: Vec::new(),
+            quirks_mode: QuirksMode::NoQuirks,
         }
     }
 
     pub fn parse_nodes(&mut self) -> Vec<Node> {
-        let mut nodes = Vec::new();
+        loop {
+            let token = self.tokenizer.next_token();
+            let eof = token == Token::Eof;
 
-        while self.chars.peek().is_some() {
-            self.consume_while(char::is_whitespace);
-            if self.chars.peek().map_or(false, |c| *c == '<') {
-                self.chars.next();
-                if self.chars.peek().map_or(false, |c| *c == '/') {
-                    self.chars.next();
-                    self.consume_while(char::is_whitespace);
+            if mem::replace(&mut self.skip_newline, false) && token == Token::Character('\n') {
+                continue;
+            }
+            self.process(token);
+            if eof {
+                break;
+            }
+        }
 
-                    let close_tag_name = self.consume_while(is_valid_tag_name);
+        let children = self.nodes[0].children.clone();
+        children.into_iter().map(|id| self.build_node(id)).collect()
+    }
 
-                    self.consume_while(|x| x != '>');
-                    self.chars.next();
+    pub fn quirks_mode(&self) -> QuirksMode {
+        self.quirks_mode
+    }
 
-                    self.node_q.push(close_tag_name);
-                    break;
-                } else if self.chars.peek().map_or(false, |c| *c == '!') {
-                    self.chars.next();
-                    nodes.push(self.parse_comment_node());
-                } else {
-                    let mut node = self.parse_node();
-                    let insert_index = nodes.len();
-
-                    match node.node_type {
-                        NodeType::Element(ref e) => if self.node_q.len() > 0 {
-                            let assumed_tag = self.node_q.remove(0);
-
-                            if e.tag_name != assumed_tag {
-                                nodes.append(&mut node.children);
-                                self.node_q.insert(0, assumed_tag);
-                            }
-                        },
-                        _ => {}
-                    }
+    fn build_node(&mut self, id: usize) -> Node {
+        let data = mem::replace(&mut self.nodes[id].data, NodeData::Document);
+        let node_type = match data {
+            NodeData::Element(name, attributes) => {
+                NodeType::Element(ElementData::new(name, attributes))
+            }
+            NodeData::Text(text) => NodeType::Text(text),
+            NodeData::Comment(comment) => NodeType::Comment(comment),
+            NodeData::Doctype(doctype) => NodeType::Doctype(doctype),
+            NodeData::Document => unreachable!(),
+        };
+        let children = self.nodes[id].children.clone();
+
+        Node::new(
+            node_type,
+            children.into_iter().map(|c| self.build_node(c)).collect(),
+        )
+    }
+
+    fn process(&mut self, token: Token) {
+        match self.mode {
+            InsertionMode::Initial => self.initial(token),
+            InsertionMode::BeforeHtml => self.before_html(token),
+            InsertionMode::BeforeHead => self.before_head(token),
+            InsertionMode::InHead => self.in_head(token),
+            InsertionMode::InHeadNoscript => self.in_head_noscript(token),
+            InsertionMode::AfterHead => self.after_head(token),
+            InsertionMode::InBody => self.in_body(token),
+            InsertionMode::Text => self.text(token),
+            InsertionMode::InTable => self.in_table(token),
+            InsertionMode::InTableText => self.in_table_text(token),
+            InsertionMode::InCaption => self.in_caption(token),
+            InsertionMode::InColumnGroup => self.in_column_group(token),
+            InsertionMode::InTableBody => self.in_table_body(token),
+            InsertionMode::InRow => self.in_row(token),
+            InsertionMode::InCell => self.in_cell(token),
+            InsertionMode::AfterBody => self.after_body(token),
+            InsertionMode::AfterAfterBody => self.after_after_body(token),
+        }
+    }
+
+    fn reprocess(&mut self, mode: InsertionMode, token: Token) {
+        self.mode = mode;
+        self.process(token);
+    }
+
+    fn initial(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => {}
+            Token::Comment(comment) => self.insert_comment(comment, Some(0)),
+            Token::Doctype(doctype) => {
+                self.quirks_mode = quirks_mode(&doctype);
+                self.nodes.push(TreeNode {
+                    data: NodeData::Doctype(DoctypeData::new(
+                        doctype.name.unwrap_or_default(),
+                        doctype.public_id.unwrap_or_default(),
+                        doctype.system_id.unwrap_or_default(),
+                    )),
+                    parent: None,
+                    children: Vec::new(),
+                });
+                let id = self.nodes.len() - 1;
+                self.append(0, id);
+                self.mode = InsertionMode::BeforeHtml;
+            }
+            _ => {
+                self.quirks_mode = QuirksMode::Quirks;
+                self.reprocess(InsertionMode::BeforeHtml, token);
+            }
+        }
+    }
+
+    fn before_html(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => {}
+            Token::Comment(comment) => self.insert_comment(comment, Some(0)),
+            Token::Doctype(_) => {}
+            Token::StartTag(ref tag) if tag.name == "html" => {
+                let html = self.create_element(tag);
+                self.append(0, html);
+                self.open.push(html);
+                self.mode = InsertionMode::BeforeHead;
+            }
+            Token::EndTag(ref tag) if !is_one_of(&tag.name, &["head", "body", "html", "br"]) => {}
+            _ => {
+                let html = self.create_element(&Tag::new("html"));
+                self.append(0, html);
+                self.open.push(html);
+                self.reprocess(InsertionMode::BeforeHead, token);
+            }
+        }
+    }
+
+    fn before_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => {}
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => {}
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token.clone()),
+            Token::StartTag(ref tag) if tag.name == "head" => {
+                self.head = Some(self.insert_element(tag));
+                self.mode = InsertionMode::InHead;
+            }
+            Token::EndTag(ref tag) if !is_one_of(&tag.name, &["head", "body", "html", "br"]) => {}
+            _ => {
+                self.head = Some(self.insert_element(&Tag::new("head")));
+                self.reprocess(InsertionMode::InHead, token);
+            }
+        }
+    }
 
-                    nodes.insert(insert_index, node);
+    fn in_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => {}
+            Token::StartTag(ref tag) => match tag.name.as_ref() {
+                "html" => self.in_body(token.clone()),
+                "base" | "basefont" | "bgsound" | "link" | "meta" => self.insert_void_element(tag),
+                "title" => self.insert_raw_text_element(tag, State::Rcdata),
+                "noframes" | "style" => self.insert_raw_text_element(tag, State::Rawtext),
+                "script" => self.insert_raw_text_element(tag, State::ScriptData),
+                "noscript" => {
+                    self.insert_element(tag);
+                    self.mode = InsertionMode::InHeadNoscript;
                 }
-            } else {
-                nodes.push(self.parse_text_node());
+                "head" => {}
+                _ => self.leave_head(token.clone()),
+            },
+            Token::EndTag(ref tag) => match tag.name.as_ref() {
+                "head" => {
+                    self.open.pop();
+                    self.mode = InsertionMode::AfterHead;
+                }
+                "body" | "html" | "br" => self.leave_head(token.clone()),
+                _ => {}
+            },
+            _ => self.leave_head(token),
+        }
+    }
+
+    // Scripting is off, so `<noscript>` holds markup, though in the head
+    // only the kind that belongs there.
+    fn in_head_noscript(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.in_head(token),
+            Token::Comment(_) => self.in_head(token),
+            Token::Doctype(_) => {}
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token.clone()),
+            Token::StartTag(ref tag)
+                if is_one_of(
+                    &tag.name,
+                    &["basefont", "bgsound", "link", "meta", "noframes", "style"],
+                ) =>
+            {
+                self.in_head(token.clone())
+            }
+            Token::StartTag(ref tag) if is_one_of(&tag.name, &["head", "noscript"]) => {}
+            Token::EndTag(ref tag) if tag.name == "noscript" => {
+                self.open.pop();
+                self.mode = InsertionMode::InHead;
+            }
+            Token::EndTag(ref tag) if tag.name != "br" => {}
+            _ => {
+                self.open.pop();
+                self.reprocess(InsertionMode::InHead, token);
             }
         }
-        nodes
     }
 
-    fn parse_node(&mut self) -> Node {
-        let tagname = self.consume_while(is_valid_tag_name);
-        let attributes = self.parse_attributes();
+    fn leave_head(&mut self, token: Token) {
+        self.open.pop();
+        self.reprocess(InsertionMode::AfterHead, token);
+    }
 
-        let elem = ElementData::new(tagname, attributes);
-        let children = self.parse_nodes();
-        Node::new(NodeType::Element(elem), children)
+    fn after_head(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => {}
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token.clone()),
+            Token::StartTag(ref tag) if tag.name == "body" => {
+                self.insert_element(tag);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InBody;
+            }
+            Token::StartTag(ref tag) if is_head_content(&tag.name) => {
+                // Stray head content after `</head>` still goes in the head.
+                let head = self.head.unwrap_or(0);
+                self.open.push(head);
+                self.in_head(token.clone());
+                self.remove_open(head);
+            }
+            Token::StartTag(ref tag) if tag.name == "head" => {}
+            Token::EndTag(ref tag) if !is_one_of(&tag.name, &["body", "html", "br"]) => {}
+            _ => {
+                self.insert_element(&Tag::new("body"));
+                self.reprocess(InsertionMode::InBody, token);
+            }
+        }
     }
 
-    fn parse_text_node(&mut self) -> Node {
-        let mut text_content = String::new();
+    fn in_body(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => {}
+            Token::Character(c) => {
+                self.reconstruct_formatting();
+                self.insert_character(c);
+                if !is_whitespace(c) {
+                    self.frameset_ok = false;
+                }
+            }
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => {}
+            Token::StartTag(tag) => self.start_tag_in_body(tag),
+            Token::EndTag(tag) => self.end_tag_in_body(tag),
+            Token::Eof => {}
+        }
+    }
+
+    fn start_tag_in_body(&mut self, tag: Tag) {
+        match tag.name.as_ref() {
+            "html" => {
+                let html = self.open[0];
+                self.merge_attributes(html, tag);
+            }
+            name if is_head_content(name) => self.in_head(Token::StartTag(tag)),
+            "body" => {
+                if self.open.len() > 1 && self.name(self.open[1]) == "body" {
+                    self.frameset_ok = false;
+                    let body = self.open[1];
+                    self.merge_attributes(body, tag);
+                }
+            }
+            "address" | "article" | "aside" | "blockquote" | "center" | "details" | "dialog"
+            | "dir" | "div" | "dl" | "fieldset" | "figcaption" | "figure" | "footer"
+            | "header" | "hgroup" | "main" | "menu" | "nav" | "ol" | "p" | "search"
+            | "section" | "summary" | "ul" => {
+                self.close_p_in_button_scope();
+                self.insert_element(&tag);
+            }
+            name if is_one_of(name, HEADINGS) => {
+                self.close_p_in_button_scope();
+                if is_one_of(self.current_name(), HEADINGS) {
+                    self.open.pop();
+                }
+                self.insert_element(&tag);
+            }
+            "pre" | "listing" => {
+                self.close_p_in_button_scope();
+                self.insert_element(&tag);
+                self.skip_newline = true;
+                self.frameset_ok = false;
+            }
+            "form" => if self.form.is_none() {
+                self.close_p_in_button_scope();
+                self.form = Some(self.insert_element(&tag));
+            },
+            "li" | "dd" | "dt" => {
+                self.frameset_ok = false;
+                let closes: &[&str] = if tag.name == "li" { &["li"] } else { &["dd", "dt"] };
 
-        while self.chars.peek().map_or(false, |c| *c != '<') {
-            let whitespace = self.consume_while(char::is_whitespace);
-            if whitespace.len() > 0 {
-                text_content.push(' ');
+                for &id in self.open.clone().iter().rev() {
+                    let name = self.name(id).to_string();
+                    if is_one_of(&name, closes) {
+                        self.generate_implied_end_tags(Some(name.as_str()));
+                        self.pop_until(&[name.as_str()]);
+                        break;
+                    }
+                    if is_special(&name) && !is_one_of(&name, &["address", "div", "p"]) {
+                        break;
+                    }
+                }
+                self.close_p_in_button_scope();
+                self.insert_element(&tag);
+            }
+            "button" => {
+                if self.in_scope(&["button"], SCOPE) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&["button"]);
+                }
+                self.reconstruct_formatting();
+                self.insert_element(&tag);
+                self.frameset_ok = false;
+            }
+            "a" => {
+                let existing = self.formatting_element("a");
+                if let Some(a) = existing {
+                    self.adoption_agency("a");
+                    self.remove_formatting(a);
+                    self.remove_open(a);
+                }
+                self.reconstruct_formatting();
+                let a = self.insert_element(&tag);
+                self.push_formatting(a, tag);
+            }
+            "b" | "big" | "code" | "em" | "font" | "i" | "s" | "small" | "strike" | "strong"
+            | "tt" | "u" => {
+                self.reconstruct_formatting();
+                let element = self.insert_element(&tag);
+                self.push_formatting(element, tag);
+            }
+            "nobr" => {
+                self.reconstruct_formatting();
+                if self.in_scope(&["nobr"], SCOPE) {
+                    self.adoption_agency("nobr");
+                    self.reconstruct_formatting();
+                }
+                let nobr = self.insert_element(&tag);
+                self.push_formatting(nobr, tag);
+            }
+            "applet" | "marquee" | "object" => {
+                self.reconstruct_formatting();
+                self.insert_element(&tag);
+                self.formatting.push(Formatting::Marker);
+                self.frameset_ok = false;
+            }
+            "table" => {
+                // Old pages relied on tables nesting inside paragraphs.
+                if self.quirks_mode != QuirksMode::Quirks {
+                    self.close_p_in_button_scope();
+                }
+                self.insert_element(&tag);
+                self.frameset_ok = false;
+                self.mode = InsertionMode::InTable;
+            }
+            "area" | "br" | "embed" | "img" | "keygen" | "wbr" => {
+                self.reconstruct_formatting();
+                self.insert_void_element(&tag);
+                self.frameset_ok = false;
+            }
+            "input" => {
+                self.reconstruct_formatting();
+                self.insert_void_element(&tag);
+                if !is_hidden_input(&tag) {
+                    self.frameset_ok = false;
+                }
+            }
+            "param" | "source" | "track" => self.insert_void_element(&tag),
+            "hr" => {
+                self.close_p_in_button_scope();
+                self.insert_void_element(&tag);
+                self.frameset_ok = false;
+            }
+            "image" => self.start_tag_in_body(Tag {
+                name: "img".to_string(),
+                ..tag
+            }),
+            "textarea" => {
+                self.insert_raw_text_element(&tag, State::Rcdata);
+                self.skip_newline = true;
+                self.frameset_ok = false;
+            }
+            "xmp" => {
+                self.close_p_in_button_scope();
+                self.reconstruct_formatting();
+                self.frameset_ok = false;
+                self.insert_raw_text_element(&tag, State::Rawtext);
+            }
+            "iframe" => {
+                self.frameset_ok = false;
+                self.insert_raw_text_element(&tag, State::Rawtext);
+            }
+            "noembed" => self.insert_raw_text_element(&tag, State::Rawtext),
+            "plaintext" => {
+                self.close_p_in_button_scope();
+                self.insert_element(&tag);
+                self.tokenizer.set_state(State::Plaintext);
+            }
+            "optgroup" | "option" => {
+                if self.current_name() == "option" {
+                    self.open.pop();
+                }
+                self.reconstruct_formatting();
+                self.insert_element(&tag);
+            }
+            "rb" | "rtc" => {
+                if self.in_scope(&["ruby"], SCOPE) {
+                    self.generate_implied_end_tags(None);
+                }
+                self.insert_element(&tag);
+            }
+            "rp" | "rt" => {
+                if self.in_scope(&["ruby"], SCOPE) {
+                    self.generate_implied_end_tags(Some("rtc"));
+                }
+                self.insert_element(&tag);
+            }
+            "frame" | "head" => {}
+            name if is_one_of(name, TABLE_CONTENT) => {}
+            _ => {
+                self.reconstruct_formatting();
+                self.insert_element(&tag);
             }
-            let text_part = self.consume_while(|x| !x.is_whitespace() && x != '<');
-            text_content.push_str(&text_part);
         }
-        Node::new(NodeType::Text(text_content), Vec::new())
     }
 
-    fn parse_comment_node(&mut self) -> Node {
-        let mut comment_content = String::new();
+    fn end_tag_in_body(&mut self, tag: Tag) {
+        match tag.name.as_ref() {
+            "body" => if self.in_scope(&["body"], SCOPE) {
+                self.mode = InsertionMode::AfterBody;
+            },
+            "html" => if self.in_scope(&["body"], SCOPE) {
+                self.reprocess(InsertionMode::AfterBody, Token::EndTag(tag));
+            },
+            "address" | "article" | "aside" | "blockquote" | "button" | "center" | "details"
+            | "dialog" | "dir" | "div" | "dl" | "fieldset" | "figcaption" | "figure"
+            | "footer" | "header" | "hgroup" | "listing" | "main" | "menu" | "nav" | "ol"
+            | "pre" | "search" | "section" | "summary" | "ul" => {
+                if self.in_scope(&[tag.name.as_str()], SCOPE) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&[tag.name.as_str()]);
+                }
+            }
+            "form" => {
+                let form = self.form.take();
+                if let Some(form) = form {
+                    if self.in_scope_element(form) {
+                        self.generate_implied_end_tags(None);
+                        self.remove_open(form);
+                    }
+                }
+            }
+            "p" => {
+                if !self.in_button_scope("p") {
+                    self.insert_element(&Tag::new("p"));
+                }
+                self.close_p();
+            }
+            "li" => if self.in_scope(&["li"], &[SCOPE, &["ol", "ul"]].concat()) {
+                self.generate_implied_end_tags(Some("li"));
+                self.pop_until(&["li"]);
+            },
+            "dd" | "dt" => if self.in_scope(&[tag.name.as_str()], SCOPE) {
+                self.generate_implied_end_tags(Some(tag.name.as_str()));
+                self.pop_until(&[tag.name.as_str()]);
+            },
+            name if is_one_of(name, HEADINGS) => if self.in_scope(HEADINGS, SCOPE) {
+                self.generate_implied_end_tags(None);
+                self.pop_until(HEADINGS);
+            },
+            "a" | "b" | "big" | "code" | "em" | "font" | "i" | "nobr" | "s" | "small"
+            | "strike" | "strong" | "tt" | "u" => self.adoption_agency(&tag.name),
+            // `</br>` is taken for a `<br>`.
+            "br" => self.start_tag_in_body(Tag::new("br")),
+            "applet" | "marquee" | "object" => if self.in_scope(&[tag.name.as_str()], SCOPE) {
+                self.generate_implied_end_tags(None);
+                self.pop_until(&[tag.name.as_str()]);
+                self.clear_formatting_to_marker();
+            },
+            _ => self.any_other_end_tag(&tag.name),
+        }
+    }
+
+    fn any_other_end_tag(&mut self, name: &str) {
+        for i in (0..self.open.len()).rev() {
+            let node_name = self.name(self.open[i]).to_string();
 
-        if self.chars.peek().map_or(false, |c| *c == '-') {
-            self.chars.next();
-            if self.chars.peek().map_or(false, |c| *c == '-') {
-                self.chars.next();
-            } else {
-                self.consume_while(|c| c != '>');
-                return Node::new(NodeType::Comment(comment_content), Vec::new());
+            if node_name == name {
+                self.generate_implied_end_tags(Some(name));
+                self.open.truncate(i);
+                return;
+            }
+            if is_special(&node_name) {
+                return;
             }
-        } else {
-            self.consume_while(|c| c != '>');
-            return Node::new(NodeType::Comment(comment_content), Vec::new());
         }
+    }
 
-        if self.chars.peek().map_or(false, |c| *c == '>') {
-            self.chars.next();
-            return Node::new(NodeType::Comment(comment_content), Vec::new());
+    // Raw text elements hold nothing but text up to their end tag, which the
+    // tokenizer makes sure of.
+    fn text(&mut self, token: Token) {
+        match token {
+            Token::Character(c) => self.insert_character(c),
+            Token::EndTag(_) => {
+                self.open.pop();
+                self.mode = self.original_mode;
+            }
+            Token::Eof => {
+                self.open.pop();
+                let mode = self.original_mode;
+                self.reprocess(mode, token);
+            }
+            _ => {}
         }
+    }
 
-        if self.chars.peek().map_or(false, |c| *c == '-') {
-            self.chars.next();
-            if self.chars.peek().map_or(false, |c| *c == '>') {
-                self.chars.next();
-                return Node::new(NodeType::Comment(comment_content), Vec::new());
-            } else {
-                comment_content.push('-');
+    fn in_table(&mut self, token: Token) {
+        match token {
+            Token::Character(_) if is_one_of(self.current_name(), &["table", "tr"])
+                || is_one_of(self.current_name(), TABLE_SECTIONS) =>
+            {
+                self.table_text.clear();
+                self.original_mode = self.mode;
+                self.reprocess(InsertionMode::InTableText, token);
             }
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::StartTag(ref tag) => match tag.name.as_ref() {
+                "caption" => {
+                    self.clear_to_context(&["table"]);
+                    self.formatting.push(Formatting::Marker);
+                    self.insert_element(tag);
+                    self.mode = InsertionMode::InCaption;
+                }
+                "colgroup" => {
+                    self.clear_to_context(&["table"]);
+                    self.insert_element(tag);
+                    self.mode = InsertionMode::InColumnGroup;
+                }
+                "col" => {
+                    self.clear_to_context(&["table"]);
+                    self.insert_element(&Tag::new("colgroup"));
+                    self.reprocess(InsertionMode::InColumnGroup, token.clone());
+                }
+                name if is_one_of(name, TABLE_SECTIONS) => {
+                    self.clear_to_context(&["table"]);
+                    self.insert_element(tag);
+                    self.mode = InsertionMode::InTableBody;
+                }
+                "td" | "th" | "tr" => {
+                    self.clear_to_context(&["table"]);
+                    self.insert_element(&Tag::new("tbody"));
+                    self.reprocess(InsertionMode::InTableBody, token.clone());
+                }
+                "table" => if self.in_table_scope(&["table"]) {
+                    self.pop_until(&["table"]);
+                    self.reset_insertion_mode();
+                    self.process(token.clone());
+                },
+                "style" | "script" => self.in_head(token.clone()),
+                "input" if is_hidden_input(tag) => self.insert_void_element(tag),
+                "form" => if self.form.is_none() {
+                    self.form = Some(self.insert_element(tag));
+                    self.open.pop();
+                },
+                _ => self.foster_in_body(token.clone()),
+            },
+            Token::EndTag(ref tag) => match tag.name.as_ref() {
+                "table" => if self.in_table_scope(&["table"]) {
+                    self.pop_until(&["table"]);
+                    self.reset_insertion_mode();
+                },
+                "body" | "html" => {}
+                name if is_one_of(name, TABLE_CONTENT) => {}
+                _ => self.foster_in_body(token.clone()),
+            },
+            Token::Eof => self.in_body(token),
+            _ => self.foster_in_body(token),
         }
+    }
+
+    // Content that doesn't belong in a table is moved out in front of it.
+    fn foster_in_body(&mut self, token: Token) {
+        self.foster_parenting = true;
+        self.in_body(token);
+        self.foster_parenting = false;
+    }
 
-        while self.chars.peek().is_some() {
-            comment_content.push_str(&self.consume_while(|c| c != '<' && c != '-'));
-            if self.chars.peek().map_or(false, |c| *c == '<') {
-                self.chars.next();
-                if self.chars.peek().map_or(false, |c| *c == '!') {
-                    self.chars.next();
-                    if self.chars.peek().map_or(false, |c| *c == '-') {
-                        self.chars.next();
-                        if self.chars.peek().map_or(false, |c| *c == '-') {
-                            self.consume_while(|c| c != '>');
+    fn in_table_text(&mut self, token: Token) {
+        match token {
+            Token::Character('\0') => {}
+            Token::Character(c) => self.table_text.push(c),
+            _ => {
+                let text = mem::replace(&mut self.table_text, Vec::new());
 
-                            return Node::new(NodeType::Comment(String::from("")), Vec::new());
-                        } else {
-                            comment_content.push_str("<!-");
-                        }
-                    } else if self.chars.peek().map_or(false, |c| *c == ' ') {
-                        self.chars.next();
-                        if self.chars.peek().map_or(false, |c| *c == '-') {
-                            self.chars.next();
-                            if self.chars.peek().map_or(false, |c| *c == '-') {
-                                self.chars.next();
-                                if self.chars.peek().map_or(false, |c| *c == '-') {
-                                    self.chars.next();
-                                    if self.chars.peek().map_or(false, |c| *c == '>') {
-                                        self.chars.next();
-                                        return Node::new(
-                                            NodeType::Comment(String::from("")),
-                                            Vec::new(),
-                                        );
-                                    } else {
-                                        comment_content.push_str("<! --");
-                                    }
-                                } else {
-                                    comment_content.push_str("<! -");
-                                }
-                            } else {
-                                comment_content.push_str("<! ");
-                            }
-                        }
-                    } else {
-                        comment_content.push_str("<!");
+                if text.iter().all(|&c| is_whitespace(c)) {
+                    for c in text {
+                        self.insert_character(c);
                     }
                 } else {
-                    comment_content.push('<');
-                }
-            } else if self.chars.peek().map_or(false, |c| *c == '-') {
-                self.chars.next();
-                if self.chars.peek().map_or(false, |c| *c == '-') {
-                    self.chars.next();
-                    if self.chars.peek().map_or(false, |c| *c == '>') {
-                        self.chars.next();
-                        break;
-                    } else {
-                        comment_content.push_str("--");
+                    for c in text {
+                        self.foster_in_body(Token::Character(c));
                     }
-                } else {
-                    comment_content.push('-');
                 }
+                let mode = self.original_mode;
+                self.reprocess(mode, token);
             }
         }
+    }
 
-        Node::new(NodeType::Comment(comment_content), Vec::new())
+    fn in_caption(&mut self, token: Token) {
+        match token {
+            Token::EndTag(ref tag) if tag.name == "caption" => {
+                self.close_caption();
+            }
+            Token::StartTag(ref tag) if is_one_of(&tag.name, TABLE_CONTENT) => {
+                if self.close_caption() {
+                    self.process(token.clone());
+                }
+            }
+            Token::EndTag(ref tag) if tag.name == "table" => if self.close_caption() {
+                self.process(token.clone());
+            },
+            Token::EndTag(ref tag)
+                if is_one_of(&tag.name, &["body", "html"])
+                    || (is_one_of(&tag.name, TABLE_CONTENT) && tag.name != "caption") => {}
+            _ => self.in_body(token),
+        }
     }
 
-    fn parse_attributes(&mut self) -> AttrMap {
-        let mut attributes = AttrMap::new();
+    fn close_caption(&mut self) -> bool {
+        if !self.in_table_scope(&["caption"]) {
+            return false;
+        }
+        self.generate_implied_end_tags(None);
+        self.pop_until(&["caption"]);
+        self.clear_formatting_to_marker();
+        self.mode = InsertionMode::InTable;
+        true
+    }
+
+    fn in_column_group(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.insert_character(c),
+            Token::Comment(comment) => self.insert_comment(comment, None),
+            Token::Doctype(_) => {}
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token.clone()),
+            Token::StartTag(ref tag) if tag.name == "col" => self.insert_void_element(tag),
+            Token::EndTag(ref tag) if tag.name == "colgroup" => {
+                if self.current_name() == "colgroup" {
+                    self.open.pop();
+                    self.mode = InsertionMode::InTable;
+                }
+            }
+            Token::EndTag(ref tag) if tag.name == "col" => {}
+            Token::Eof => self.in_body(token),
+            _ => if self.current_name() == "colgroup" {
+                self.open.pop();
+                self.reprocess(InsertionMode::InTable, token);
+            },
+        }
+    }
+
+    fn in_table_body(&mut self, token: Token) {
+        match token {
+            Token::StartTag(ref tag) if tag.name == "tr" => {
+                self.clear_to_context(TABLE_SECTIONS);
+                self.insert_element(tag);
+                self.mode = InsertionMode::InRow;
+            }
+            Token::StartTag(ref tag) if is_one_of(&tag.name, &["td", "th"]) => {
+                self.clear_to_context(TABLE_SECTIONS);
+                self.insert_element(&Tag::new("tr"));
+                self.reprocess(InsertionMode::InRow, token.clone());
+            }
+            Token::EndTag(ref tag) if is_one_of(&tag.name, TABLE_SECTIONS) => {
+                if self.in_table_scope(&[tag.name.as_str()]) {
+                    self.clear_to_context(TABLE_SECTIONS);
+                    self.open.pop();
+                    self.mode = InsertionMode::InTable;
+                }
+            }
+            Token::StartTag(ref tag)
+                if is_one_of(&tag.name, &["caption", "col", "colgroup"])
+                    || is_one_of(&tag.name, TABLE_SECTIONS) =>
+            {
+                self.leave_table_body(token.clone())
+            }
+            Token::EndTag(ref tag) if tag.name == "table" => self.leave_table_body(token.clone()),
+            Token::EndTag(ref tag)
+                if is_one_of(&tag.name, &["body", "caption", "col", "colgroup", "html"])
+                    || is_one_of(&tag.name, &["td", "th", "tr"]) => {}
+            _ => self.in_table(token),
+        }
+    }
+
+    fn leave_table_body(&mut self, token: Token) {
+        if self.in_table_scope(TABLE_SECTIONS) {
+            self.clear_to_context(TABLE_SECTIONS);
+            self.open.pop();
+            self.reprocess(InsertionMode::InTable, token);
+        }
+    }
+
+    fn in_row(&mut self, token: Token) {
+        match token {
+            Token::StartTag(ref tag) if is_one_of(&tag.name, &["td", "th"]) => {
+                self.clear_to_context(&["tr"]);
+                self.insert_element(tag);
+                self.mode = InsertionMode::InCell;
+                self.formatting.push(Formatting::Marker);
+            }
+            Token::EndTag(ref tag) if tag.name == "tr" => {
+                self.close_row();
+            }
+            Token::StartTag(ref tag) if is_one_of(&tag.name, TABLE_CONTENT) => {
+                if self.close_row() {
+                    self.process(token.clone());
+                }
+            }
+            Token::EndTag(ref tag) if tag.name == "table" => if self.close_row() {
+                self.process(token.clone());
+            },
+            Token::EndTag(ref tag) if is_one_of(&tag.name, TABLE_SECTIONS) => {
+                if self.in_table_scope(&[tag.name.as_str()]) && self.close_row() {
+                    self.process(token.clone());
+                }
+            }
+            Token::EndTag(ref tag)
+                if is_one_of(&tag.name, &["body", "caption", "col", "colgroup", "html"])
+                    || is_one_of(&tag.name, &["td", "th"]) => {}
+            _ => self.in_table(token),
+        }
+    }
+
+    fn close_row(&mut self) -> bool {
+        if !self.in_table_scope(&["tr"]) {
+            return false;
+        }
+        self.clear_to_context(&["tr"]);
+        self.open.pop();
+        self.mode = InsertionMode::InTableBody;
+        true
+    }
+
+    fn in_cell(&mut self, token: Token) {
+        match token {
+            Token::EndTag(ref tag) if is_one_of(&tag.name, &["td", "th"]) => {
+                if self.in_table_scope(&[tag.name.as_str()]) {
+                    self.generate_implied_end_tags(None);
+                    self.pop_until(&[tag.name.as_str()]);
+                    self.clear_formatting_to_marker();
+                    self.mode = InsertionMode::InRow;
+                }
+            }
+            Token::StartTag(ref tag) if is_one_of(&tag.name, TABLE_CONTENT) => {
+                if self.in_table_scope(&["td", "th"]) {
+                    self.close_cell();
+                    self.process(token.clone());
+                }
+            }
+            Token::EndTag(ref tag) if is_one_of(&tag.name, &["body", "caption", "col", "colgroup"])
+                || tag.name == "html" => {}
+            Token::EndTag(ref tag) if tag.name == "table" || tag.name == "tr"
+                || is_one_of(&tag.name, TABLE_SECTIONS) =>
+            {
+                if self.in_table_scope(&[tag.name.as_str()]) {
+                    self.close_cell();
+                    self.process(token.clone());
+                }
+            }
+            _ => self.in_body(token),
+        }
+    }
+
+    fn close_cell(&mut self) {
+        self.generate_implied_end_tags(None);
+        self.pop_until(&["td", "th"]);
+        self.clear_formatting_to_marker();
+        self.mode = InsertionMode::InRow;
+    }
+
+    fn after_body(&mut self, token: Token) {
+        match token {
+            Token::Character(c) if is_whitespace(c) => self.in_body(token),
+            Token::Doctype(_) => {}
+            Token::Comment(comment) => {
+                let html = self.open[0];
+                self.insert_comment(comment, Some(html));
+            }
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token.clone()),
+            Token::EndTag(ref tag) if tag.name == "html" => {
+                self.mode = InsertionMode::AfterAfterBody;
+            }
+            Token::Eof => {}
+            _ => self.reprocess(InsertionMode::InBody, token),
+        }
+    }
+
+    fn after_after_body(&mut self, token: Token) {
+        match token {
+            Token::Comment(comment) => self.insert_comment(comment, Some(0)),
+            Token::Character(c) if is_whitespace(c) => self.in_body(token),
+            Token::Doctype(_) => {}
+            Token::StartTag(ref tag) if tag.name == "html" => self.in_body(token.clone()),
+            Token::Eof => {}
+            _ => self.reprocess(InsertionMode::InBody, token),
+        }
+    }
 
-        while self.chars.peek().map_or(false, |c| *c != '>') {
-            self.consume_while(char::is_whitespace);
-            let name = self.consume_while(|c| is_valid_attr_name(c)).to_lowercase();
-            self.consume_while(char::is_whitespace);
+    fn reset_insertion_mode(&mut self) {
+        for (i, &id) in self.open.iter().enumerate().rev() {
+            let last = i == 0;
 
-            let value = if self.chars.peek().map_or(false, |c| *c == '=') {
-                self.chars.next();
-                self.consume_while(char::is_whitespace);
-                let s = self.parse_attr_value();
-                self.consume_while(|c| !c.is_whitespace() && c != '>');
-                self.consume_while(char::is_whitespace);
-                s
-            } else {
-                "".to_string()
+            self.mode = match self.name(id) {
+                "td" | "th" if !last => InsertionMode::InCell,
+                "tr" => InsertionMode::InRow,
+                "tbody" | "thead" | "tfoot" => InsertionMode::InTableBody,
+                "caption" => InsertionMode::InCaption,
+                "colgroup" => InsertionMode::InColumnGroup,
+                "table" => InsertionMode::InTable,
+                "head" if !last => InsertionMode::InHead,
+                "body" => InsertionMode::InBody,
+                "html" if self.head.is_none() => InsertionMode::BeforeHead,
+                "html" => InsertionMode::AfterHead,
+                _ if last => InsertionMode::InBody,
+                _ => continue,
             };
-            attributes.insert(name, value);
+            return;
         }
-        self.chars.next();
+    }
+
+    fn name(&self, id: usize) -> &str {
+        match self.nodes[id].data {
+            NodeData::Element(ref name, _) => name,
+            _ => "",
+        }
+    }
+
+    fn current(&self) -> usize {
+        self.open.last().cloned().unwrap_or(0)
+    }
 
-        attributes
+    fn current_name(&self) -> &str {
+        self.name(self.current())
     }
 
-    fn parse_attr_value(&mut self) -> String {
-        self.consume_while(char::is_whitespace);
+    fn create_element(&mut self, tag: &Tag) -> usize {
+        self.nodes.push(TreeNode {
+            data: NodeData::Element(tag.name.clone(), tag.attributes.clone()),
+            parent: None,
+            children: Vec::new(),
+        });
+        self.nodes.len() - 1
+    }
+
+    fn insert_element(&mut self, tag: &Tag) -> usize {
+        let id = self.create_element(tag);
+        let (parent, before) = self.insertion_place(None);
+
+        self.insert_at(parent, id, before);
+        self.open.push(id);
+        id
+    }
+
+    fn insert_raw_text_element(&mut self, tag: &Tag, state: State) {
+        self.insert_element(tag);
+        self.tokenizer.set_state(state);
+        self.original_mode = self.mode;
+        self.mode = InsertionMode::Text;
+    }
 
-        let result = match self.chars.peek() {
-            Some(&c) if c == '"' || c == '\'' => {
-                self.chars.next();
-                let ret = self.consume_while(|x| x != c);
-                self.chars.next();
-                ret
+    // Void elements never have children, so they are closed as soon as they
+    // are opened; a trailing `/>` on them is allowed and changes nothing.
+    fn insert_void_element(&mut self, tag: &Tag) {
+        self.insert_element(tag);
+        self.open.pop();
+    }
+
+    fn insert_character(&mut self, c: char) {
+        let (parent, before) = self.insertion_place(None);
+        if parent == 0 {
+            return;
+        }
+
+        let position = before.unwrap_or(self.nodes[parent].children.len());
+        if position > 0 {
+            let previous = self.nodes[parent].children[position - 1];
+            if let NodeData::Text(ref mut text) = self.nodes[previous].data {
+                text.push(c);
+                return;
             }
-            _ => self.consume_while(is_valid_attr_value),
-        };
+        }
 
-        result
+        self.nodes.push(TreeNode {
+            data: NodeData::Text(c.to_string()),
+            parent: None,
+            children: Vec::new(),
+        });
+        let id = self.nodes.len() - 1;
+        self.insert_at(parent, id, before);
     }
 
+    fn insert_comment(&mut self, comment: String, parent: Option<usize>) {
+        self.nodes.push(TreeNode {
+            data: NodeData::Comment(comment),
+            parent: None,
+            children: Vec::new(),
+        });
+        let id = self.nodes.len() - 1;
 
-    fn consume_while<F>(&mut self, condition: F) -> String
-    where
-        F: Fn(char) -> bool,
-    {
-        let mut result = String::new();
-        while self.chars.peek().map_or(false, |c| condition(*c)) {
-            result.push(self.chars.next().unwrap());
+        match parent {
+            Some(parent) => self.append(parent, id),
+            None => {
+                let (parent, before) = self.insertion_place(None);
+                self.insert_at(parent, id, before);
+            }
+        }
+    }
+
+    // The parent a new node goes into, and the child it goes before when it
+    // is not simply appended.
+    fn insertion_place(&self, target: Option<usize>) -> (usize, Option<usize>) {
+        let target = target.unwrap_or_else(|| self.current());
+        let fosters = self.foster_parenting
+            && is_one_of(self.name(target), &["table", "tbody", "tfoot", "thead", "tr"]);
+
+        if !fosters {
+            return (target, None);
+        }
+
+        let table = self.open.iter().rposition(|&id| self.name(id) == "table");
+        match table {
+            Some(i) => {
+                let table = self.open[i];
+                match self.nodes[table].parent {
+                    Some(parent) => {
+                        let position = self.nodes[parent].children.iter().position(|&c| c == table);
+                        (parent, position)
+                    }
+                    None => (self.open[i - 1], None),
+                }
+            }
+            None => (self.open[0], None),
+        }
+    }
+
+    fn insert_at(&mut self, parent: usize, id: usize, before: Option<usize>) {
+        self.detach(id);
+        match before {
+            Some(i) => self.nodes[parent].children.insert(i, id),
+            None => self.nodes[parent].children.push(id),
+        }
+        self.nodes[id].parent = Some(parent);
+    }
+
+    fn append(&mut self, parent: usize, id: usize) {
+        self.insert_at(parent, id, None);
+    }
+
+    fn detach(&mut self, id: usize) {
+        if let Some(parent) = self.nodes[id].parent.take() {
+            self.nodes[parent].children.retain(|&c| c != id);
+        }
+    }
+
+    fn merge_attributes(&mut self, id: usize, tag: Tag) {
+        if let NodeData::Element(_, ref mut attributes) = self.nodes[id].data {
+            for (name, value) in tag.attributes {
+                attributes.entry(name).or_insert(value);
+            }
+        }
+    }
+
+    fn remove_open(&mut self, id: usize) {
+        self.open.retain(|&o| o != id);
+    }
+
+    fn pop_until(&mut self, names: &[&str]) {
+        while let Some(id) = self.open.pop() {
+            if is_one_of(self.name(id), names) {
+                break;
+            }
+        }
+    }
+
+    fn clear_to_context(&mut self, names: &[&str]) {
+        while !is_one_of(self.current_name(), names) && self.current_name() != "html" {
+            self.open.pop();
+        }
+    }
+
+    fn in_scope(&self, names: &[&str], boundaries: &[&str]) -> bool {
+        for &id in self.open.iter().rev() {
+            let name = self.name(id);
+            if is_one_of(name, names) {
+                return true;
+            }
+            if is_one_of(name, boundaries) {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn in_scope_element(&self, element: usize) -> bool {
+        for &id in self.open.iter().rev() {
+            if id == element {
+                return true;
+            }
+            if is_one_of(self.name(id), SCOPE) {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn in_button_scope(&self, name: &str) -> bool {
+        self.in_scope(&[name], &[SCOPE, &["button"]].concat())
+    }
+
+    fn in_table_scope(&self, names: &[&str]) -> bool {
+        self.in_scope(names, &["html", "table", "template"])
+    }
+
+    fn generate_implied_end_tags(&mut self, except: Option<&str>) {
+        loop {
+            let name = self.current_name();
+            if !is_one_of(name, IMPLIED_END) || Some(name) == except {
+                break;
+            }
+            self.open.pop();
+        }
+    }
+
+    fn close_p(&mut self) {
+        self.generate_implied_end_tags(Some("p"));
+        self.pop_until(&["p"]);
+    }
+
+    fn close_p_in_button_scope(&mut self) {
+        if self.in_button_scope("p") {
+            self.close_p();
+        }
+    }
+
+    // The most recent formatting element of this name since the last marker.
+    fn formatting_element(&self, name: &str) -> Option<usize> {
+        for entry in self.formatting.iter().rev() {
+            match *entry {
+                Formatting::Marker => return None,
+                Formatting::Element(id, _) if self.name(id) == name => return Some(id),
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn formatting_position(&self, element: usize) -> Option<usize> {
+        self.formatting.iter().position(|entry| match *entry {
+            Formatting::Element(id, _) => id == element,
+            Formatting::Marker => false,
+        })
+    }
+
+    fn remove_formatting(&mut self, element: usize) {
+        if let Some(i) = self.formatting_position(element) {
+            self.formatting.remove(i);
+        }
+    }
+
+    // At most three identical entries are kept after the last marker.
+    fn push_formatting(&mut self, element: usize, tag: Tag) {
+        let mut same = Vec::new();
+
+        for (i, entry) in self.formatting.iter().enumerate().rev() {
+            match *entry {
+                Formatting::Marker => break,
+                Formatting::Element(_, ref t)
+                    if t.name == tag.name && t.attributes == tag.attributes =>
+                {
+                    same.push(i)
+                }
+                _ => {}
+            }
+        }
+        if same.len() >= 3 {
+            self.formatting.remove(*same.last().unwrap());
+        }
+        self.formatting.push(Formatting::Element(element, tag));
+    }
+
+    fn clear_formatting_to_marker(&mut self) {
+        while let Some(entry) = self.formatting.pop() {
+            if let Formatting::Marker = entry {
+                break;
+            }
+        }
+    }
+
+    // Reopens formatting elements that were implicitly closed, such as a
+    // `<b>` left open across the end of a paragraph.
+    fn reconstruct_formatting(&mut self) {
+        let mut start = self.formatting.len();
+
+        while start > 0 {
+            match self.formatting[start - 1] {
+                Formatting::Marker => break,
+                Formatting::Element(id, _) if self.open.contains(&id) => break,
+                _ => start -= 1,
+            }
+        }
+
+        for i in start..self.formatting.len() {
+            let tag = match self.formatting[i] {
+                Formatting::Element(_, ref tag) => tag.clone(),
+                Formatting::Marker => continue,
+            };
+            let element = self.insert_element(&tag);
+            self.formatting[i] = Formatting::Element(element, tag);
         }
+    }
+
+    // The adoption agency algorithm, which untangles misnested formatting
+    // elements like `<b><i></b></i>`.
+    fn adoption_agency(&mut self, subject: &str) {
+        let current = self.current();
+        if self.current_name() == subject && self.formatting_position(current).is_none() {
+            self.open.pop();
+            return;
+        }
+
+        for _ in 0..8 {
+            let formatting_element = match self.formatting_element(subject) {
+                Some(id) => id,
+                None => return self.any_other_end_tag(subject),
+            };
+
+            let stack_index = match self.open.iter().position(|&id| id == formatting_element) {
+                Some(i) => i,
+                None => return self.remove_formatting(formatting_element),
+            };
+            if !self.in_scope_element(formatting_element) {
+                return;
+            }
+
+            let furthest_block = self.open[stack_index + 1..]
+                .iter()
+                .cloned()
+                .find(|&id| is_special(self.name(id)));
+            let furthest_block = match furthest_block {
+                Some(id) => id,
+                None => {
+                    self.open.truncate(stack_index);
+                    return self.remove_formatting(formatting_element);
+                }
+            };
+
+            let common_ancestor = self.open[stack_index - 1];
+            let mut bookmark = self.formatting_position(formatting_element).unwrap();
+            let mut node_index = self.open.iter().position(|&id| id == furthest_block).unwrap();
+            let mut last_node = furthest_block;
+            let mut inner = 0;
+
+            loop {
+                inner += 1;
+                node_index -= 1;
+                let mut node = self.open[node_index];
 
-        result
+                if node == formatting_element {
+                    break;
+                }
+                if inner > 3 {
+                    if let Some(i) = self.formatting_position(node) {
+                        if i < bookmark {
+                            bookmark -= 1;
+                        }
+                        self.formatting.remove(i);
+                    }
+                }
+                let position = match self.formatting_position(node) {
+                    Some(i) => i,
+                    None => {
+                        self.open.remove(node_index);
+                        continue;
+                    }
+                };
+
+                let tag = match self.formatting[position] {
+                    Formatting::Element(_, ref tag) => tag.clone(),
+                    Formatting::Marker => unreachable!(),
+                };
+                node = self.create_element(&tag);
+                self.formatting[position] = Formatting::Element(node, tag);
+                self.open[node_index] = node;
+
+                if last_node == furthest_block {
+                    bookmark = position + 1;
+                }
+                self.append(node, last_node);
+                last_node = node;
+            }
+
+            self.detach(last_node);
+            let (parent, before) = self.insertion_place(Some(common_ancestor));
+            self.insert_at(parent, last_node, before);
+
+            let position = self.formatting_position(formatting_element).unwrap();
+            let tag = match self.formatting[position] {
+                Formatting::Element(_, ref tag) => tag.clone(),
+                Formatting::Marker => unreachable!(),
+            };
+            let element = self.create_element(&tag);
+
+            let children = mem::replace(&mut self.nodes[furthest_block].children, Vec::new());
+            for child in children {
+                self.nodes[child].parent = None;
+                self.append(element, child);
+            }
+            self.append(furthest_block, element);
+
+            self.formatting.remove(position);
+            if position < bookmark {
+                bookmark -= 1;
+            }
+            self.formatting.insert(bookmark, Formatting::Element(element, tag));
+
+            self.remove_open(formatting_element);
+            let fb_index = self.open.iter().position(|&id| id == furthest_block).unwrap();
+            self.open.insert(fb_index + 1, element);
+        }
     }
 }
 
-fn is_valid_tag_name(ch: char) -> bool {
-    ch.is_digit(36)
+// Doctypes from before HTML5 ask for quirks mode, or the limited quirks of
+// XHTML 1.0 and transitional HTML 4.01, while `<!DOCTYPE html>` asks for
+// none.
+fn quirks_mode(doctype: &Doctype) -> QuirksMode {
+    let public_id = doctype.public_id.as_ref().map(|id| id.to_ascii_lowercase());
+    let system_id = doctype.system_id.as_ref().map(|id| id.to_ascii_lowercase());
+    let public = public_id.as_ref().map_or("", |id| id.as_str());
+    let system = system_id.as_ref().map_or("", |id| id.as_str());
+    let starts_with_any = |prefixes: &[&str]| prefixes.iter().any(|p| public.starts_with(p));
+
+    if doctype.force_quirks || doctype.name.as_ref().map_or(true, |n| n != "html")
+        || is_one_of(public, QUIRKS_PUBLIC_IDS)
+        || system == "http://www.ibm.com/data/dtd/v11/ibmxhtml1-transitional.dtd"
+        || starts_with_any(QUIRKS_PUBLIC_PREFIXES)
+        || (system_id.is_none() && starts_with_any(HTML_401_PREFIXES))
+    {
+        QuirksMode::Quirks
+    } else if starts_with_any(XHTML_10_PREFIXES)
+        || (system_id.is_some() && starts_with_any(HTML_401_PREFIXES))
+    {
+        QuirksMode::LimitedQuirks
+    } else {
+        QuirksMode::NoQuirks
+    }
 }
 
-fn is_valid_attr_name(c: char) -> bool {
-    !is_excluded_name(c) && !is_control(c)
+fn is_one_of(name: &str, names: &[&str]) -> bool {
+    names.iter().any(|&n| n == name)
 }
 
-fn is_control(ch: char) -> bool {
-    match ch {
-        '\u{007F}' => true,
-        c if c >= '\u{0000}' && c <= '\u{001F}' => true,
-        c if c >= '\u{0080}' && c <= '\u{009F}' => true,
-        _ => false,
-    }
+fn is_special(name: &str) -> bool {
+    is_one_of(name, SPECIAL)
 }
 
-fn is_excluded_name(c: char) -> bool {
-    match c {
-        ' ' | '"' | '\'' | '>' | '/' | '=' => true,
-        _ => false,
-    }
+fn is_hidden_input(tag: &Tag) -> bool {
+    tag.attributes
+        .get("type")
+        .map_or(false, |t| t.eq_ignore_ascii_case("hidden"))
+}
+
+fn is_head_content(name: &str) -> bool {
+    is_one_of(
+        name,
+        &[
+            "base", "basefont", "bgsound", "link", "meta", "noframes", "script", "style",
+            "title",
+        ],
+    )
 }
 
-fn is_valid_attr_value(c: char) -> bool {
-    match c {
-        ' ' | '"' | '\'' | '=' | '<' | '>' | '`' => false,
-        _ => true,
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(html: &str) -> (Vec<Node>, QuirksMode) {
+        let mut parser = HtmlParser::new(html);
+        let nodes = parser.parse_nodes();
+        (nodes, parser.quirks_mode())
+    }
+
+    // The tree as markup, with every end tag written out, so tests can
+    // compare whole documents.
+    fn serialize(nodes: &[Node]) -> String {
+        let mut out = String::new();
+
+        for node in nodes {
+            match node.node_type {
+                NodeType::Element(ref e) => {
+                    out.push_str(&format!("<{}>", e.tag_name));
+                    out.push_str(&serialize(&node.children));
+                    if !e.is_void() {
+                        out.push_str(&format!("</{}>", e.tag_name));
+                    }
+                }
+                NodeType::Text(ref t) => out.push_str(t),
+                NodeType::Comment(ref c) => out.push_str(&format!("<!--{}-->", c)),
+                NodeType::Doctype(ref d) => out.push_str(&format!("{:?}", d)),
+            }
+        }
+        out
+    }
+
+    fn body(html: &str) -> String {
+        let (nodes, _) = parse(html);
+        let html = nodes.last().unwrap();
+        let body = html.children.last().unwrap();
+
+        match body.node_type {
+            NodeType::Element(ref e) => assert_eq!(e.tag_name, "body"),
+            _ => panic!("expected a body element"),
+        }
+        serialize(&body.children)
+    }
+
+    fn element(node: &Node) -> &ElementData {
+        match node.node_type {
+            NodeType::Element(ref e) => e,
+            _ => panic!("expected an element, found {:?}", node),
+        }
+    }
+
+    #[test]
+    fn empty_document_gets_html_head_and_body() {
+        let (nodes, _) = parse("");
+        assert_eq!(serialize(&nodes), "<html><head></head><body></body></html>");
+
+        let (nodes, _) = parse("hello");
+        assert_eq!(serialize(&nodes), "<html><head></head><body>hello</body></html>");
+    }
+
+    #[test]
+    fn paragraphs_are_closed_implicitly() {
+        assert_eq!(body("<p>one<p>two"), "<p>one</p><p>two</p>");
+        assert_eq!(body("<p>one<div>two</div>"), "<p>one</p><div>two</div>");
+        assert_eq!(body("<p>one</p></p>"), "<p>one</p><p></p>");
+    }
+
+    #[test]
+    fn misnested_formatting_elements_are_reopened() {
+        assert_eq!(body("<b><i>x</b>y</i>"), "<b><i>x</i></b><i>y</i>");
+        assert_eq!(body("<a>1<p>2</a>3</p>"), "<a>1</a><p><a>2</a>3</p>");
+    }
+
+    #[test]
+    fn text_in_tables_is_foster_parented() {
+        assert_eq!(
+            body("<table>foo<tr><td>bar</td></tr></table>"),
+            "foo<table><tbody><tr><td>bar</td></tr></tbody></table>"
+        );
+    }
+
+    #[test]
+    fn void_elements_have_no_children() {
+        assert_eq!(body("<p>a<br>b<img>c</p>"), "<p>a<br>b<img>c</p>");
+        assert_eq!(body("<input>x</input>y"), "<input>xy");
+        assert_eq!(body("a</br>b"), "a<br>b");
+    }
+
+    #[test]
+    fn character_references_are_decoded() {
+        assert_eq!(body("&lt;&#65;&#x42;&amp"), "<AB&");
+        assert_eq!(body("I'm &notit; I tell you"), "I'm \u{ac}it; I tell you");
+        assert_eq!(body("?a=1&copy=2"), "?a=1\u{a9}=2");
+        assert_eq!(body("&#x80;&#0;&bogus;"), "\u{20ac}\u{fffd}&bogus;");
+
+        // In attributes a reference without its `;` is left alone when an
+        // alphanumeric or `=` follows.
+        let (nodes, _) = parse("<a href=\"?a=1&copy=2&amp;b=&lt\">x</a>");
+        let a = &nodes[0].children[1].children[0];
+        assert_eq!(
+            element(a).get_attribute("href").map(|s| s.as_ref()),
+            Some("?a=1&copy=2&b=<")
+        );
+    }
+
+    #[test]
+    fn raw_text_elements_keep_their_contents() {
+        let (nodes, _) = parse("<style>p > a { content: \"&amp;\" }</style><p>x");
+        let head = &nodes[0].children[0];
+        assert_eq!(
+            serialize(&head.children),
+            "<style>p > a { content: \"&amp;\" }</style>"
+        );
+
+        assert_eq!(
+            body("x<script>if (a < b && c) { x = '</p>'; }</script>"),
+            "x<script>if (a < b && c) { x = '</p>'; }</script>"
+        );
+        assert_eq!(
+            body("<textarea><b>&amp;</b></textarea>"),
+            "<textarea><b>&</b></textarea>"
+        );
+    }
+
+    #[test]
+    fn doctype_selects_quirks_mode() {
+        let (nodes, mode) = parse("<!DOCTYPE html><p>x");
+        assert_eq!(mode, QuirksMode::NoQuirks);
+        assert_eq!(format!("{:?}", nodes[0]), "<!DOCTYPE html>");
+
+        let (_, mode) = parse("<p>x");
+        assert_eq!(mode, QuirksMode::Quirks);
+
+        let (_, mode) = parse(
+            "<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\">",
+        );
+        assert_eq!(mode, QuirksMode::Quirks);
+
+        let (_, mode) = parse(
+            "<!DOCTYPE HTML PUBLIC \"-//W3C//DTD HTML 4.01 Transitional//EN\" \
+             \"http://www.w3.org/TR/html4/loose.dtd\">",
+        );
+        assert_eq!(mode, QuirksMode::LimitedQuirks);
+
+        let (_, mode) = parse(
+            "<!DOCTYPE html PUBLIC \"-//W3C//DTD XHTML 1.0 Strict//EN\" \
+             \"http://www.w3.org/TR/xhtml1/DTD/xhtml1-strict.dtd\">",
+        );
+        assert_eq!(mode, QuirksMode::NoQuirks);
+    }
+
+    #[test]
+    fn tables_close_paragraphs_only_outside_quirks_mode() {
+        let (nodes, _) = parse("<!DOCTYPE html><p><table></table>");
+        assert_eq!(
+            serialize(&nodes[1..]),
+            "<html><head></head><body><p></p><table></table></body></html>"
+        );
+
+        let (nodes, _) = parse("<p><table></table>");
+        assert_eq!(
+            serialize(&nodes),
+            "<html><head></head><body><p><table></table></p></body></html>"
+        );
     }
 }
@@ -0,0 +1,715 @@
+use dom::AttrMap;
+use entities::ENTITIES;
+
+use std::collections::VecDeque;
+use std::mem;
+
+// Splits HTML source into tokens following the tokenization stage of the
+// HTML Standard. The tree builder pulls tokens one at a time, so it can
+// switch the tokenizer's state between them.
+#[derive(PartialEq, Clone, Debug)]
+pub enum Token {
+    StartTag(Tag),
+    EndTag(Tag),
+    Comment(String),
+    Doctype(Doctype),
+    Character(char),
+    Eof,
+}
+
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Tag {
+    pub name: String,
+    pub attributes: AttrMap,
+    pub self_closing: bool,
+}
+
+impl Tag {
+    pub fn new(name: &str) -> Tag {
+        Tag {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+}
+
+// Identifiers that are left out stay None, which is not the same as empty
+// for deciding on quirks mode.
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct Doctype {
+    pub name: Option<String>,
+    pub public_id: Option<String>,
+    pub system_id: Option<String>,
+    pub force_quirks: bool,
+}
+
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum State {
+    Data,
+    Rcdata,
+    Rawtext,
+    ScriptData,
+    Plaintext,
+    TagOpen,
+    EndTagOpen,
+    TagName,
+    BeforeAttributeName,
+    AttributeName,
+    AfterAttributeName,
+    BeforeAttributeValue,
+    AttributeValueQuoted(char),
+    AttributeValueUnquoted,
+    AfterAttributeValueQuoted,
+    SelfClosingStartTag,
+    BogusComment,
+    MarkupDeclarationOpen,
+    CommentStart,
+    CommentStartDash,
+    Comment,
+    CommentEndDash,
+    CommentEnd,
+    CommentEndBang,
+    Doctype,
+    BeforeDoctypeName,
+    DoctypeName,
+    AfterDoctypeName,
+    BeforeDoctypePublicId,
+    DoctypePublicId(char),
+    AfterDoctypePublicId,
+    BeforeDoctypeSystemId,
+    DoctypeSystemId(char),
+    AfterDoctypeSystemId,
+    BogusDoctype,
+}
+
+pub struct Tokenizer {
+    chars: Vec<char>,
+    pos: usize,
+    state: State,
+    tokens: VecDeque<Token>,
+    tag: Tag,
+    is_end_tag: bool,
+    last_start_tag: String,
+    attribute: Option<(String, String)>,
+    comment: String,
+    doctype: Doctype,
+}
+
+impl Tokenizer {
+    pub fn new(html: &str) -> Tokenizer {
+        let mut chars = Vec::with_capacity(html.len());
+        let mut input = html.chars().peekable();
+
+        while let Some(c) = input.next() {
+            match c {
+                '\r' => {
+                    if input.peek() == Some(&'\n') {
+                        input.next();
+                    }
+                    chars.push('\n');
+                }
+                _ => chars.push(c),
+            }
+        }
+
+        Tokenizer {
+            chars,
+            pos: 0,
+            state: State::Data,
+            tokens: VecDeque::new(),
+            tag: Tag::default(),
+            is_end_tag: false,
+            last_start_tag: String::new(),
+            attribute: None,
+            comment: String::new(),
+            doctype: Doctype::default(),
+        }
+    }
+
+    pub fn next_token(&mut self) -> Token {
+        while self.tokens.is_empty() {
+            self.step();
+        }
+        self.tokens.pop_front().unwrap()
+    }
+
+    // The tree builder switches states for elements whose contents aren't
+    // markup, like `<style>` and `<textarea>`.
+    pub fn set_state(&mut self, state: State) {
+        self.state = state;
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.get(self.pos).cloned();
+        self.pos += 1;
+        c
+    }
+
+    // Hands the character just read to the next state instead.
+    fn reconsume(&mut self, state: State) {
+        self.pos -= 1;
+        self.state = state;
+    }
+
+    fn starts_with(&self, text: &str) -> bool {
+        self.starts_with_at(self.pos, text)
+    }
+
+    fn starts_with_at(&self, pos: usize, text: &str) -> bool {
+        let mut chars = self.chars[pos.min(self.chars.len())..].iter();
+        text.chars()
+            .all(|t| chars.next().map_or(false, |c| c.eq_ignore_ascii_case(&t)))
+    }
+
+    fn emit(&mut self, token: Token) {
+        self.tokens.push_back(token);
+    }
+
+    fn emit_eof(&mut self) {
+        self.pos = self.chars.len() + 1;
+        self.emit(Token::Eof);
+    }
+
+    fn start_tag(&mut self, is_end_tag: bool) {
+        self.tag = Tag::default();
+        self.is_end_tag = is_end_tag;
+    }
+
+    fn start_attribute(&mut self, name: &str) {
+        self.finish_attribute();
+        self.attribute = Some((name.to_string(), String::new()));
+    }
+
+    // The first of several attributes with the same name wins.
+    fn finish_attribute(&mut self) {
+        if let Some((name, value)) = self.attribute.take() {
+            self.tag.attributes.entry(name).or_insert(value);
+        }
+    }
+
+    fn push_attribute_name(&mut self, c: char) {
+        if let Some((ref mut name, _)) = self.attribute {
+            name.push(c);
+        }
+    }
+
+    fn push_attribute_value(&mut self, c: char) {
+        if let Some((_, ref mut value)) = self.attribute {
+            value.push(c);
+        }
+    }
+
+    fn push_attribute_reference(&mut self) {
+        for c in self.consume_character_reference(true).chars() {
+            self.push_attribute_value(c);
+        }
+    }
+
+    fn emit_tag(&mut self) {
+        self.finish_attribute();
+        let tag = mem::replace(&mut self.tag, Tag::default());
+
+        self.state = State::Data;
+        if self.is_end_tag {
+            self.emit(Token::EndTag(tag));
+        } else {
+            self.last_start_tag = tag.name.clone();
+            self.emit(Token::StartTag(tag));
+        }
+    }
+
+    fn emit_comment(&mut self) {
+        let comment = mem::replace(&mut self.comment, String::new());
+
+        self.state = State::Data;
+        self.emit(Token::Comment(comment));
+    }
+
+    fn emit_doctype(&mut self, force_quirks: bool) {
+        let mut doctype = mem::replace(&mut self.doctype, Doctype::default());
+        doctype.force_quirks |= force_quirks;
+
+        self.state = State::Data;
+        self.emit(Token::Doctype(doctype));
+    }
+
+    fn push_doctype_id(&mut self, c: char) {
+        let id = match self.state {
+            State::DoctypePublicId(_) => &mut self.doctype.public_id,
+            _ => &mut self.doctype.system_id,
+        };
+        id.get_or_insert_with(String::new).push(c);
+    }
+
+    // Raw text only ends at the end tag of the element it started in; any
+    // other `<` is just text. Called with the `<` already consumed.
+    fn text_less_than_sign(&mut self) {
+        let name_end = self.pos + 1 + self.last_start_tag.len();
+        let closes = !self.last_start_tag.is_empty()
+            && self.chars.get(self.pos) == Some(&'/')
+            && self.starts_with_at(self.pos + 1, &self.last_start_tag)
+            && self.chars.get(name_end).map_or(false, |&c| {
+                is_whitespace(c) || c == '/' || c == '>'
+            });
+
+        if closes {
+            self.pos += 1;
+            self.start_tag(true);
+            self.state = State::TagName;
+        } else {
+            self.emit(Token::Character('<'));
+        }
+    }
+
+    // Called with the ampersand already consumed. Returns the text the
+    // reference stands for, or just the ampersand when there is none.
+    fn consume_character_reference(&mut self, in_attribute: bool) -> String {
+        match self.chars.get(self.pos) {
+            Some(&'#') => self.consume_numeric_reference(),
+            Some(c) if c.is_ascii_alphanumeric() => self.consume_named_reference(in_attribute),
+            _ => "&".to_string(),
+        }
+    }
+
+    // The longest name in the table wins, so `&notit;` reads as `&not` and
+    // `it;`. Inside attributes a name without its semicolon that runs into
+    // more of a word or an `=` is left alone, as in `?a=1&copy=2`.
+    fn consume_named_reference(&mut self, in_attribute: bool) -> String {
+        let mut candidate: String = self.chars[self.pos..]
+            .iter()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .take(32)
+            .collect();
+        if self.chars.get(self.pos + candidate.len()) == Some(&';') {
+            candidate.push(';');
+        }
+
+        for length in (1..candidate.len() + 1).rev() {
+            let name = &candidate[..length];
+            let value = match ENTITIES.binary_search_by_key(&name, |&(n, _)| n) {
+                Ok(i) => ENTITIES[i].1,
+                Err(_) => continue,
+            };
+            self.pos += length;
+
+            let next = self.chars.get(self.pos).cloned();
+            if in_attribute && !name.ends_with(';')
+                && next.map_or(false, |c| c == '=' || c.is_ascii_alphanumeric())
+            {
+                return format!("&{}", name);
+            }
+            return value.to_string();
+        }
+        "&".to_string()
+    }
+
+    fn consume_numeric_reference(&mut self) -> String {
+        let start = self.pos;
+        self.pos += 1;
+
+        let radix = match self.chars.get(self.pos) {
+            Some(&'x') | Some(&'X') => {
+                self.pos += 1;
+                16
+            }
+            _ => 10,
+        };
+
+        let mut code: u32 = 0;
+        let mut digits = 0;
+        while let Some(d) = self.chars.get(self.pos).and_then(|c| c.to_digit(radix)) {
+            code = code.saturating_mul(radix).saturating_add(d);
+            digits += 1;
+            self.pos += 1;
+        }
+
+        if digits == 0 {
+            self.pos = start;
+            return "&".to_string();
+        }
+        if self.chars.get(self.pos) == Some(&';') {
+            self.pos += 1;
+        }
+
+        let c = match code {
+            _ if code >= 0x80 && code <= 0x9f => WINDOWS_1252[code as usize - 0x80],
+            _ => None,
+        };
+        match c.or_else(|| ::std::char::from_u32(code)) {
+            Some('\0') | None => '\u{fffd}',
+            Some(c) => c,
+        }.to_string()
+    }
+
+    fn step(&mut self) {
+        if self.pos > self.chars.len() {
+            return self.emit(Token::Eof);
+        }
+
+        match self.state {
+            State::Data => match self.next() {
+                Some('&') => for c in self.consume_character_reference(false).chars() {
+                    self.emit(Token::Character(c));
+                },
+                Some('<') => self.state = State::TagOpen,
+                Some(c) => self.emit(Token::Character(c)),
+                None => self.emit_eof(),
+            },
+            State::Rcdata => match self.next() {
+                Some('&') => for c in self.consume_character_reference(false).chars() {
+                    self.emit(Token::Character(c));
+                },
+                Some('<') => self.text_less_than_sign(),
+                Some('\0') => self.emit(Token::Character('\u{fffd}')),
+                Some(c) => self.emit(Token::Character(c)),
+                None => self.emit_eof(),
+            },
+            // Scripts are never run, so script data is read like raw text
+            // without the escaping rules for `<!--` inside it.
+            State::Rawtext | State::ScriptData => match self.next() {
+                Some('<') => self.text_less_than_sign(),
+                Some('\0') => self.emit(Token::Character('\u{fffd}')),
+                Some(c) => self.emit(Token::Character(c)),
+                None => self.emit_eof(),
+            },
+            State::Plaintext => match self.next() {
+                Some('\0') => self.emit(Token::Character('\u{fffd}')),
+                Some(c) => self.emit(Token::Character(c)),
+                None => self.emit_eof(),
+            },
+            State::TagOpen => match self.next() {
+                Some('!') => self.state = State::MarkupDeclarationOpen,
+                Some('/') => self.state = State::EndTagOpen,
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.start_tag(false);
+                    self.reconsume(State::TagName);
+                }
+                Some('?') => self.reconsume(State::BogusComment),
+                Some(_) => {
+                    self.emit(Token::Character('<'));
+                    self.reconsume(State::Data);
+                }
+                None => {
+                    self.emit(Token::Character('<'));
+                    self.emit_eof();
+                }
+            },
+            State::EndTagOpen => match self.next() {
+                Some(c) if c.is_ascii_alphabetic() => {
+                    self.start_tag(true);
+                    self.reconsume(State::TagName);
+                }
+                Some('>') => self.state = State::Data,
+                Some(_) => self.reconsume(State::BogusComment),
+                None => {
+                    self.emit(Token::Character('<'));
+                    self.emit(Token::Character('/'));
+                    self.emit_eof();
+                }
+            },
+            State::TagName => match self.next() {
+                Some(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('>') => self.emit_tag(),
+                Some('\0') => self.tag.name.push('\u{fffd}'),
+                Some(c) => self.tag.name.push(c.to_ascii_lowercase()),
+                None => self.emit_eof(),
+            },
+            State::BeforeAttributeName => match self.next() {
+                Some(c) if is_whitespace(c) => {}
+                Some('/') | Some('>') => self.reconsume(State::AfterAttributeName),
+                Some('=') => {
+                    self.start_attribute("=");
+                    self.state = State::AttributeName;
+                }
+                Some(_) => {
+                    self.start_attribute("");
+                    self.reconsume(State::AttributeName);
+                }
+                None => self.emit_eof(),
+            },
+            State::AttributeName => match self.next() {
+                Some(c) if is_whitespace(c) || c == '/' || c == '>' => {
+                    self.reconsume(State::AfterAttributeName)
+                }
+                Some('=') => self.state = State::BeforeAttributeValue,
+                Some('\0') => self.push_attribute_name('\u{fffd}'),
+                Some(c) => self.push_attribute_name(c.to_ascii_lowercase()),
+                None => self.emit_eof(),
+            },
+            State::AfterAttributeName => match self.next() {
+                Some(c) if is_whitespace(c) => {}
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('=') => self.state = State::BeforeAttributeValue,
+                Some('>') => self.emit_tag(),
+                Some(_) => {
+                    self.start_attribute("");
+                    self.reconsume(State::AttributeName);
+                }
+                None => self.emit_eof(),
+            },
+            State::BeforeAttributeValue => match self.next() {
+                Some(c) if is_whitespace(c) => {}
+                Some(c @ '"') | Some(c @ '\'') => self.state = State::AttributeValueQuoted(c),
+                Some('>') => self.emit_tag(),
+                Some(_) => self.reconsume(State::AttributeValueUnquoted),
+                None => self.emit_eof(),
+            },
+            State::AttributeValueQuoted(quote) => match self.next() {
+                Some(c) if c == quote => self.state = State::AfterAttributeValueQuoted,
+                Some('&') => self.push_attribute_reference(),
+                Some('\0') => self.push_attribute_value('\u{fffd}'),
+                Some(c) => self.push_attribute_value(c),
+                None => self.emit_eof(),
+            },
+            State::AttributeValueUnquoted => match self.next() {
+                Some(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Some('&') => self.push_attribute_reference(),
+                Some('>') => self.emit_tag(),
+                Some('\0') => self.push_attribute_value('\u{fffd}'),
+                Some(c) => self.push_attribute_value(c),
+                None => self.emit_eof(),
+            },
+            State::AfterAttributeValueQuoted => match self.next() {
+                Some(c) if is_whitespace(c) => self.state = State::BeforeAttributeName,
+                Some('/') => self.state = State::SelfClosingStartTag,
+                Some('>') => self.emit_tag(),
+                Some(_) => self.reconsume(State::BeforeAttributeName),
+                None => self.emit_eof(),
+            },
+            State::SelfClosingStartTag => match self.next() {
+                Some('>') => {
+                    self.tag.self_closing = true;
+                    self.emit_tag();
+                }
+                Some(_) => self.reconsume(State::BeforeAttributeName),
+                None => self.emit_eof(),
+            },
+            State::BogusComment => match self.next() {
+                Some('>') => self.emit_comment(),
+                Some('\0') => self.comment.push('\u{fffd}'),
+                Some(c) => self.comment.push(c),
+                None => {
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+            },
+            // Outside SVG and MathML a CDATA section is a bogus comment, just
+            // like a processing instruction.
+            State::MarkupDeclarationOpen => {
+                if self.starts_with("--") {
+                    self.pos += 2;
+                    self.state = State::CommentStart;
+                } else if self.starts_with("doctype") {
+                    self.pos += 7;
+                    self.state = State::Doctype;
+                } else {
+                    self.state = State::BogusComment;
+                }
+            }
+            State::CommentStart => match self.next() {
+                Some('-') => self.state = State::CommentStartDash,
+                Some('>') => self.emit_comment(),
+                _ => self.reconsume(State::Comment),
+            },
+            State::CommentStartDash => match self.next() {
+                Some('-') => self.state = State::CommentEnd,
+                Some('>') => self.emit_comment(),
+                Some(_) => {
+                    self.comment.push('-');
+                    self.reconsume(State::Comment);
+                }
+                None => {
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+            },
+            State::Comment => match self.next() {
+                Some('-') => self.state = State::CommentEndDash,
+                Some('\0') => self.comment.push('\u{fffd}'),
+                Some(c) => self.comment.push(c),
+                None => {
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+            },
+            State::CommentEndDash => match self.next() {
+                Some('-') => self.state = State::CommentEnd,
+                Some(_) => {
+                    self.comment.push('-');
+                    self.reconsume(State::Comment);
+                }
+                None => {
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+            },
+            State::CommentEnd => match self.next() {
+                Some('>') => self.emit_comment(),
+                Some('!') => self.state = State::CommentEndBang,
+                Some('-') => self.comment.push('-'),
+                Some(_) => {
+                    self.comment.push_str("--");
+                    self.reconsume(State::Comment);
+                }
+                None => {
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+            },
+            State::CommentEndBang => match self.next() {
+                Some('-') => {
+                    self.comment.push_str("--!");
+                    self.state = State::CommentEndDash;
+                }
+                Some('>') => self.emit_comment(),
+                Some(_) => {
+                    self.comment.push_str("--!");
+                    self.reconsume(State::Comment);
+                }
+                None => {
+                    self.emit_comment();
+                    self.emit_eof();
+                }
+            },
+            State::Doctype => match self.next() {
+                Some(c) if is_whitespace(c) => self.state = State::BeforeDoctypeName,
+                Some(_) => self.reconsume(State::BeforeDoctypeName),
+                None => {
+                    self.emit_doctype(true);
+                    self.emit_eof();
+                }
+            },
+            State::BeforeDoctypeName => match self.next() {
+                Some(c) if is_whitespace(c) => {}
+                Some('>') => self.emit_doctype(true),
+                Some(c) => {
+                    let c = if c == '\0' { '\u{fffd}' } else { c.to_ascii_lowercase() };
+                    self.doctype.name = Some(c.to_string());
+                    self.state = State::DoctypeName;
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.emit_eof();
+                }
+            },
+            State::DoctypeName => match self.next() {
+                Some(c) if is_whitespace(c) => self.state = State::AfterDoctypeName,
+                Some('>') => self.emit_doctype(false),
+                Some(c) => {
+                    let c = if c == '\0' { '\u{fffd}' } else { c.to_ascii_lowercase() };
+                    self.doctype.name.get_or_insert_with(String::new).push(c);
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.emit_eof();
+                }
+            },
+            State::AfterDoctypeName => match self.next() {
+                Some(c) if is_whitespace(c) => {}
+                Some('>') => self.emit_doctype(false),
+                Some(_) => {
+                    self.pos -= 1;
+                    if self.starts_with("public") {
+                        self.pos += 6;
+                        self.state = State::BeforeDoctypePublicId;
+                    } else if self.starts_with("system") {
+                        self.pos += 6;
+                        self.state = State::BeforeDoctypeSystemId;
+                    } else {
+                        self.doctype.force_quirks = true;
+                        self.state = State::BogusDoctype;
+                    }
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.emit_eof();
+                }
+            },
+            State::BeforeDoctypePublicId | State::BeforeDoctypeSystemId => match self.next() {
+                Some(c) if is_whitespace(c) => {}
+                Some(c @ '"') | Some(c @ '\'') => {
+                    if self.state == State::BeforeDoctypePublicId {
+                        self.doctype.public_id = Some(String::new());
+                        self.state = State::DoctypePublicId(c);
+                    } else {
+                        self.doctype.system_id = Some(String::new());
+                        self.state = State::DoctypeSystemId(c);
+                    }
+                }
+                Some('>') => self.emit_doctype(true),
+                Some(_) => {
+                    self.doctype.force_quirks = true;
+                    self.reconsume(State::BogusDoctype);
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.emit_eof();
+                }
+            },
+            State::DoctypePublicId(quote) | State::DoctypeSystemId(quote) => match self.next() {
+                Some(c) if c == quote => {
+                    self.state = match self.state {
+                        State::DoctypePublicId(_) => State::AfterDoctypePublicId,
+                        _ => State::AfterDoctypeSystemId,
+                    }
+                }
+                Some('>') => self.emit_doctype(true),
+                Some('\0') => self.push_doctype_id('\u{fffd}'),
+                Some(c) => self.push_doctype_id(c),
+                None => {
+                    self.emit_doctype(true);
+                    self.emit_eof();
+                }
+            },
+            State::AfterDoctypePublicId => match self.next() {
+                Some(c) if is_whitespace(c) => {}
+                Some('>') => self.emit_doctype(false),
+                Some(c @ '"') | Some(c @ '\'') => {
+                    self.doctype.system_id = Some(String::new());
+                    self.state = State::DoctypeSystemId(c);
+                }
+                Some(_) => {
+                    self.doctype.force_quirks = true;
+                    self.reconsume(State::BogusDoctype);
+                }
+                None => {
+                    self.emit_doctype(true);
+                    self.emit_eof();
+                }
+            },
+            State::AfterDoctypeSystemId => match self.next() {
+                Some(c) if is_whitespace(c) => {}
+                Some('>') => self.emit_doctype(false),
+                Some(_) => self.reconsume(State::BogusDoctype),
+                None => {
+                    self.emit_doctype(true);
+                    self.emit_eof();
+                }
+            },
+            State::BogusDoctype => match self.next() {
+                Some('>') => self.emit_doctype(false),
+                Some(_) => {}
+                None => {
+                    self.emit_doctype(false);
+                    self.emit_eof();
+                }
+            },
+        }
+    }
+}
+
+// Numeric references to C1 controls are taken as the windows-1252 characters
+// they were almost certainly meant to be.
+const WINDOWS_1252: [Option<char>; 32] = [
+    Some('\u{20ac}'), None, Some('\u{201a}'), Some('\u{192}'), Some('\u{201e}'),
+    Some('\u{2026}'), Some('\u{2020}'), Some('\u{2021}'), Some('\u{2c6}'), Some('\u{2030}'),
+    Some('\u{160}'), Some('\u{2039}'), Some('\u{152}'), None, Some('\u{17d}'), None, None,
+    Some('\u{2018}'), Some('\u{2019}'), Some('\u{201c}'), Some('\u{201d}'), Some('\u{2022}'),
+    Some('\u{2013}'), Some('\u{2014}'), Some('\u{2dc}'), Some('\u{2122}'), Some('\u{161}'),
+    Some('\u{203a}'), Some('\u{153}'), None, Some('\u{17e}'), Some('\u{178}'),
+];
+
+pub fn is_whitespace(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\n' || c == '\u{c}'
+}
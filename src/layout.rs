@@ -1,14 +1,17 @@
-use std::fmt;
+use std::{fmt, mem};
 
-use css::{Unit, Value};
+use css::Value;
+use dom::QuirksMode;
+use font::FontMetrics;
 use style::{Display, StyledNode};
 
 #[derive(Clone)]
 pub struct LayoutBox<'a> {
     pub dimensions: Dimensions,
-    box_type: BoxType,
+    pub box_type: BoxType,
     pub styled_node: &'a StyledNode<'a>,
     pub children: Vec<LayoutBox<'a>>,
+    pub text_runs: Vec<TextRun>,
 }
 #[derive(Clone, Copy, Default)]
 pub struct Dimensions {
@@ -16,7 +19,6 @@ pub struct Dimensions {
     padding: EdgeSizes,
     pub border: EdgeSizes,
     margin: EdgeSizes,
-    current: Rectangle,
 }
 
 #[derive(Clone, Copy, Default)]
@@ -41,6 +43,46 @@ pub enum BoxType {
     Inline,
     InlineBlock,
     Anonymous,
+    Text,
+}
+
+// The part of a text box that landed on one line.
+#[derive(Clone)]
+pub struct TextRun {
+    pub text: String,
+    pub rect: Rectangle,
+}
+
+// A piece of inline content, flattened out of the box tree so that it can be
+// broken into lines. `path` leads from the formatting context's box to the
+// box the item came from.
+enum InlineItem {
+    Word {
+        path: Vec<usize>,
+        text: String,
+        width: f32,
+        font: FontMetrics,
+        line_height: f32,
+    },
+    Space { width: f32 },
+    Atomic {
+        path: Vec<usize>,
+        width: f32,
+        height: f32,
+    },
+}
+
+struct LineBox {
+    items: Vec<PlacedItem>,
+    width: f32,
+    ascent: f32,
+    descent: f32,
+}
+
+struct PlacedItem {
+    index: usize,
+    x: f32,
+    space_before: bool,
 }
 
 
@@ -51,6 +93,14 @@ impl<'a> LayoutBox<'a> {
             styled_node: styled_node,
             dimensions: Default::default(),
             children: Vec::new(),
+            text_runs: Vec::new(),
+        }
+    }
+
+    fn is_inline_level(&self) -> bool {
+        match self.box_type {
+            BoxType::Inline | BoxType::InlineBlock | BoxType::Text => true,
+            BoxType::Block | BoxType::Anonymous => false,
         }
     }
 
@@ -60,14 +110,33 @@ impl<'a> LayoutBox<'a> {
             BoxType::Block => self.layout_block(b_box),
             BoxType::Inline => self.layout_block(b_box),
             BoxType::InlineBlock => self.layout_inline_block(b_box),
-            BoxType::Anonymous => {}
+            BoxType::Anonymous => self.layout_anonymous(b_box),
+            BoxType::Text => {}
         }
     }
 
+    fn layout_anonymous(&mut self, b_box: Dimensions) {
+        let d = &mut self.dimensions;
+
+        d.content.x = b_box.content.x;
+        d.content.y = b_box.content.y + b_box.content.height;
+        d.content.width = b_box.content.width;
+        self.layout_children();
+    }
+
     fn layout_inline_block(&mut self, b_box: Dimensions) {
         self.calculate_inline_width(b_box);
         self.calculate_inline_position(b_box);
-        self.layout_children();
+        let used_width = self.layout_children();
+
+        // An auto width shrinks to the content, which takes a second pass so
+        // that line alignment uses the final width.
+        if get_absolute_num(self.styled_node, b_box, "width").is_none()
+            && used_width < self.dimensions.content.width
+        {
+            self.dimensions.content.width = used_width;
+            self.layout_children();
+        }
         self.calculate_height();
     }
 
@@ -75,28 +144,33 @@ impl<'a> LayoutBox<'a> {
         let s = self.styled_node;
         let d = &mut self.dimensions;
 
-        d.content.width = get_absolute_num(s, b_box, "width").unwrap_or(0.0);
-        d.margin.left = s.num_or("margin-left", 0.0);
-        d.margin.right = s.num_or("margin-right", 0.0);
-        d.padding.left = s.num_or("padding-left", 0.0);
-        d.padding.right = s.num_or("padding-right", 0.0);
-        d.border.left = s.num_or("border-left-width", 0.0);
-        d.border.right = s.num_or("border-right-width", 0.0);
+        d.margin.left = get_absolute_num(s, b_box, "margin-left").unwrap_or(0.0);
+        d.margin.right = get_absolute_num(s, b_box, "margin-right").unwrap_or(0.0);
+        d.padding.left = get_absolute_num(s, b_box, "padding-left").unwrap_or(0.0);
+        d.padding.right = get_absolute_num(s, b_box, "padding-right").unwrap_or(0.0);
+        d.border.left = get_absolute_num(s, b_box, "border-left-width").unwrap_or(0.0);
+        d.border.right = get_absolute_num(s, b_box, "border-right-width").unwrap_or(0.0);
+
+        let edges = d.margin.left + d.margin.right + d.padding.left + d.padding.right
+            + d.border.left + d.border.right;
+        d.content.width = match get_absolute_num(s, b_box, "width") {
+            Some(w) => w,
+            None => (b_box.content.width - edges).max(0.0),
+        };
     }
 
     fn calculate_inline_position(&mut self, b_box: Dimensions) {
         let style = self.styled_node;
         let d = &mut self.dimensions;
 
-        d.margin.top = style.num_or("margin-top", 0.0);
-        d.margin.bottom = style.num_or("margin-bottom", 0.0);
-        d.border.top = style.num_or("border-top-width", 0.0);
-        d.border.bottom = style.num_or("border-bottom-width", 0.0);
-        d.padding.top = style.num_or("padding-top", 0.0);
-        d.padding.bottom = style.num_or("padding-bottom", 0.0);
+        d.margin.top = get_absolute_num(style, b_box, "margin-top").unwrap_or(0.0);
+        d.margin.bottom = get_absolute_num(style, b_box, "margin-bottom").unwrap_or(0.0);
+        d.border.top = get_absolute_num(style, b_box, "border-top-width").unwrap_or(0.0);
+        d.border.bottom = get_absolute_num(style, b_box, "border-bottom-width").unwrap_or(0.0);
+        d.padding.top = get_absolute_num(style, b_box, "padding-top").unwrap_or(0.0);
+        d.padding.bottom = get_absolute_num(style, b_box, "padding-bottom").unwrap_or(0.0);
 
-        d.content.x =
-            b_box.content.x + b_box.current.x + d.margin.left + d.border.left + d.padding.left;
+        d.content.x = b_box.content.x + d.margin.left + d.border.left + d.padding.left;
         d.content.y =
             b_box.content.height + b_box.content.y + d.margin.top + d.border.top + d.padding.top;
     }
@@ -114,35 +188,23 @@ impl<'a> LayoutBox<'a> {
         let d = &mut self.dimensions;
 
         let width = get_absolute_num(style, b_box, "width").unwrap_or(0.0);
-        let margin_l = style.value("margin-left");
-        let margin_r = style.value("margin-right");
+        let auto_l = is_auto(style, "margin-left");
+        let auto_r = is_auto(style, "margin-right");
 
-        let margin_l_num = match margin_l {
-            Some(m) => match **m {
-                Value::Other(ref s) => s.parse().unwrap_or(0.0),
-                _ => 0.0,
-            },
-            None => 0.0,
-        };
-        let margin_r_num = match margin_r {
-            Some(m) => match **m {
-                Value::Other(ref s) => s.parse().unwrap_or(0.0),
-                _ => 0.0,
-            },
-            None => 0.0,
-        };
+        let margin_l_num = get_absolute_num(style, b_box, "margin-left").unwrap_or(0.0);
+        let margin_r_num = get_absolute_num(style, b_box, "margin-right").unwrap_or(0.0);
 
-        d.border.left = style.num_or("border-left-width", 0.0);
-        d.border.right = style.num_or("border-right-width", 0.0);
-        d.padding.left = style.num_or("padding-left", 0.0);
-        d.padding.right = style.num_or("padding-right", 0.0);
+        d.border.left = get_absolute_num(style, b_box, "border-left-width").unwrap_or(0.0);
+        d.border.right = get_absolute_num(style, b_box, "border-right-width").unwrap_or(0.0);
+        d.padding.left = get_absolute_num(style, b_box, "padding-left").unwrap_or(0.0);
+        d.padding.right = get_absolute_num(style, b_box, "padding-right").unwrap_or(0.0);
 
         let total = width + margin_l_num + margin_r_num + d.border.left + d.border.right
             + d.padding.left + d.padding.right;
 
         let underflow = b_box.content.width - total;
 
-        match (width, margin_l, margin_r) {
+        match (width, auto_l, auto_r) {
             (0.0, _, _) => {
                 if underflow >= 0.0 {
                     d.content.width = underflow;
@@ -153,17 +215,17 @@ impl<'a> LayoutBox<'a> {
                 }
                 d.margin.left = margin_l_num;
             }
-            (w, None, Some(_)) if w != 0.0 => {
+            (w, true, false) if w != 0.0 => {
                 d.margin.left = underflow;
                 d.margin.right = margin_r_num;
                 d.content.width = w;
             }
-            (w, Some(_), None) if w != 0.0 => {
+            (w, false, true) if w != 0.0 => {
                 d.margin.right = underflow;
                 d.margin.left = margin_l_num;
                 d.content.width = w;
             }
-            (w, None, None) if w != 0.0 => {
+            (w, true, true) if w != 0.0 => {
                 d.margin.left = underflow / 2.0;
                 d.margin.right = underflow / 2.0;
                 d.content.width = w;
@@ -180,72 +242,296 @@ impl<'a> LayoutBox<'a> {
         let style = self.styled_node;
         let d = &mut self.dimensions;
 
-        d.margin.top = style.num_or("margin-top", 0.0);
-        d.margin.bottom = style.num_or("margin-bottom", 0.0);
-        d.border.top = style.num_or("border-top-width", 0.0);
-        d.border.bottom = style.num_or("border-bottom-width", 0.0);
-        d.padding.top = style.num_or("padding-top", 0.0);
-        d.padding.bottom = style.num_or("padding-bottom", 0.0);
+        d.margin.top = get_absolute_num(style, b_box, "margin-top").unwrap_or(0.0);
+        d.margin.bottom = get_absolute_num(style, b_box, "margin-bottom").unwrap_or(0.0);
+        d.border.top = get_absolute_num(style, b_box, "border-top-width").unwrap_or(0.0);
+        d.border.bottom = get_absolute_num(style, b_box, "border-bottom-width").unwrap_or(0.0);
+        d.padding.top = get_absolute_num(style, b_box, "padding-top").unwrap_or(0.0);
+        d.padding.bottom = get_absolute_num(style, b_box, "padding-bottom").unwrap_or(0.0);
 
         d.content.x = b_box.content.x + d.margin.left + d.border.left + d.padding.left;
         d.content.y =
             b_box.content.height + b_box.content.y + d.margin.top + d.border.top + d.padding.top;
     }
 
+    // Percentage heights would need a containing block of definite height,
+    // which the engine never has, so they behave as auto.
     fn calculate_height(&mut self) {
-        self.styled_node.value("height").map_or((), |h| match **h {
-            Value::Length(n, _) => self.dimensions.content.height = n,
-            _ => {}
-        })
+        if let Some(&Value::Length(..)) = self.styled_node.value("height") {
+            self.dimensions.content.height = self.styled_node.length("height", 0.0).unwrap_or(0.0);
+        }
     }
 
-    fn layout_children(&mut self) {
-        let d = &mut self.dimensions;
-        let mut max_child_height = 0.0;
+    // Lays out the children and returns the width they actually used, which
+    // is narrower than the content box when the lines do not fill it.
+    fn layout_children(&mut self) -> f32 {
+        self.dimensions.content.height = 0.0;
 
-        let mut prev_box_type = BoxType::Block;
+        if !self.children.is_empty() && self.children.iter().all(|c| c.is_inline_level()) {
+            return self.layout_inline_children();
+        }
 
+        let d = &mut self.dimensions;
         for child in &mut self.children {
-            match prev_box_type {
-                BoxType::InlineBlock => match child.box_type {
-                    BoxType::Block => {
-                        d.content.height += max_child_height;
-                        d.current.x = 0.0;
+            child.layout(*d);
+            d.content.height += child.dimensions.margin_box().height;
+        }
+        d.content.width
+    }
+
+    fn layout_inline_children(&mut self) -> f32 {
+        let containing = self.dimensions;
+        let mut items = Vec::new();
+        let mut path = Vec::new();
+
+        for (i, child) in self.children.iter_mut().enumerate() {
+            path.push(i);
+            child.collect_inline_items(containing, &mut path, &mut items);
+            path.pop();
+        }
+
+        let strut = line_metrics(self.styled_node);
+        let lines = break_lines(&items, containing.content.width, strut);
+        let align = match self.styled_node.keyword("text-align") {
+            Some("center") => 0.5,
+            Some("right") => 1.0,
+            _ => 0.0,
+        };
+
+        let mut y = containing.content.y;
+        let mut used_width: f32 = 0.0;
+
+        for line in &lines {
+            let offset = ((containing.content.width - line.width) * align).max(0.0);
+            let baseline = y + line.ascent;
+
+            for placed in &line.items {
+                let x = containing.content.x + offset + placed.x;
+
+                match items[placed.index] {
+                    InlineItem::Word {
+                        ref path,
+                        ref text,
+                        width,
+                        font,
+                        ..
+                    } => self.descendant_mut(path).push_word(
+                        text,
+                        placed.space_before,
+                        Rectangle {
+                            x,
+                            y: baseline - font.ascent,
+                            width,
+                            height: font.ascent + font.descent,
+                        },
+                    ),
+                    InlineItem::Atomic {
+                        ref path, height, ..
+                    } => {
+                        let atomic = self.descendant_mut(path);
+                        let margin_box = atomic.dimensions.margin_box();
+                        atomic.translate(x - margin_box.x, baseline - height - margin_box.y);
                     }
-                    _ => {}
-                },
-                _ => {}
+                    InlineItem::Space { .. } => {}
+                }
             }
 
-            child.layout(*d);
-            let new_height = child.dimensions.margin_box().height;
+            y += line.ascent + line.descent;
+            used_width = used_width.max(line.width);
+        }
 
-            if new_height > max_child_height {
-                max_child_height = new_height;
-            }
+        self.dimensions.content.height = y - containing.content.y;
+        for child in &mut self.children {
+            child.fit_inline_bounds();
+        }
+        used_width
+    }
+
+    fn collect_inline_items(
+        &mut self,
+        containing: Dimensions,
+        path: &mut Vec<usize>,
+        items: &mut Vec<InlineItem>,
+    ) {
+        match self.box_type {
+            BoxType::Text => {
+                self.text_runs.clear();
 
-            match child.box_type {
-                BoxType::Block => d.content.height += child.dimensions.margin_box().height,
-                BoxType::InlineBlock => {
-                    d.current.x += child.dimensions.margin_box().width;
+                let (font, line_height) = line_metrics(self.styled_node);
+                let space = font.text_width(" ");
+                let text = self.styled_node.text().unwrap_or("");
 
-                    if d.current.x > d.content.width {
-                        d.content.height += max_child_height;
-                        d.current.x = 0.0;
-                        child.layout(*d);
-                        d.current.x += child.dimensions.margin_box().width;
+                // Only document white space collapses; a no-break space is part
+                // of the word around it.
+                let words = text.split(is_collapsible).filter(|w| !w.is_empty());
+
+                if text.starts_with(is_collapsible) {
+                    items.push(InlineItem::Space { width: space });
+                }
+                for (i, word) in words.enumerate() {
+                    if i > 0 {
+                        items.push(InlineItem::Space { width: space });
                     }
+                    items.push(InlineItem::Word {
+                        path: path.clone(),
+                        text: word.to_string(),
+                        width: font.text_width(word),
+                        font,
+                        line_height,
+                    });
                 }
-                _ => {}
+                if text.ends_with(is_collapsible) && !text.trim_matches(is_collapsible).is_empty() {
+                    items.push(InlineItem::Space { width: space });
+                }
+            }
+            BoxType::Inline => for (i, child) in self.children.iter_mut().enumerate() {
+                path.push(i);
+                child.collect_inline_items(containing, path, items);
+                path.pop();
+            },
+            _ => {
+                // Inline blocks, and any block that ended up inside an inline,
+                // are laid out on their own and placed on a line as a whole.
+                let mut b_box = containing;
+                b_box.content.height = 0.0;
+                self.layout(b_box);
+
+                let margin_box = self.dimensions.margin_box();
+                let width = match self.box_type {
+                    BoxType::InlineBlock => margin_box.width,
+                    _ => margin_box.width.max(containing.content.width),
+                };
+                items.push(InlineItem::Atomic {
+                    path: path.clone(),
+                    width,
+                    height: margin_box.height,
+                });
             }
-            prev_box_type = child.box_type.clone();
         }
     }
+
+    fn push_word(&mut self, word: &str, space_before: bool, rect: Rectangle) {
+        if let Some(run) = self.text_runs.last_mut() {
+            if run.rect.y == rect.y && run.rect.x < rect.x {
+                if space_before {
+                    run.text.push(' ');
+                }
+                run.text.push_str(word);
+                run.rect.width = rect.x + rect.width - run.rect.x;
+                return;
+            }
+        }
+
+        self.text_runs.push(TextRun {
+            text: word.to_string(),
+            rect,
+        });
+    }
+
+    fn descendant_mut(&mut self, path: &[usize]) -> &mut LayoutBox<'a> {
+        match path.split_first() {
+            Some((&i, rest)) => self.children[i].descendant_mut(rest),
+            None => self,
+        }
+    }
+
+    fn translate(&mut self, dx: f32, dy: f32) {
+        self.dimensions.content.x += dx;
+        self.dimensions.content.y += dy;
+
+        for run in &mut self.text_runs {
+            run.rect.x += dx;
+            run.rect.y += dy;
+        }
+        for child in &mut self.children {
+            child.translate(dx, dy);
+        }
+    }
+
+    // Text and inline boxes have no size of their own; once their content is
+    // placed they take the bounding box of what ended up inside them.
+    fn fit_inline_bounds(&mut self) -> Option<Rectangle> {
+        let bounds = match self.box_type {
+            BoxType::Text => self.text_runs
+                .iter()
+                .map(|r| r.rect)
+                .fold(None, union_bounds),
+            BoxType::Inline => self.children
+                .iter_mut()
+                .filter_map(|c| c.fit_inline_bounds())
+                .fold(None, union_bounds),
+            _ => return Some(self.dimensions.border_box()),
+        };
+
+        if let Some(b) = bounds {
+            self.dimensions.content = b;
+        }
+        bounds
+    }
+
+    // Keeps a box's children either all block-level or all inline-level by
+    // wrapping each run of inline-level children in an anonymous block.
+    fn wrap_inline_runs(&mut self) {
+        let has_block = self.children.iter().any(|c| !c.is_inline_level());
+        let has_inline = self.children.iter().any(|c| c.is_inline_level());
+
+        if !(has_block && has_inline) {
+            return;
+        }
+
+        for child in mem::replace(&mut self.children, Vec::new()) {
+            if !child.is_inline_level() {
+                self.children.push(child);
+                continue;
+            }
+
+            let needs_container = match self.children.last() {
+                Some(&LayoutBox {
+                    box_type: BoxType::Anonymous,
+                    ..
+                }) => false,
+                _ => true,
+            };
+            if needs_container {
+                self.children
+                    .push(LayoutBox::new(BoxType::Anonymous, self.styled_node));
+            }
+            self.children.last_mut().unwrap().children.push(child);
+        }
+    }
+
+    // In quirks mode a root element of auto height fills the viewport, and
+    // an auto-height body fills the root, so a page's body is never shorter
+    // than the window.
+    fn fill_viewport(&mut self, viewport_height: f32) {
+        self.fill_height(viewport_height);
+
+        let height = self.dimensions.content.height;
+        for child in &mut self.children {
+            if child.styled_node.tag_name() == Some("body") {
+                child.fill_height(height);
+            }
+        }
+    }
+
+    fn fill_height(&mut self, height: f32) {
+        if !is_auto(self.styled_node, "height") {
+            return;
+        }
+        let d = &mut self.dimensions;
+        let outside = d.margin_box().height - d.content.height;
+        d.content.height = d.content.height.max(height - outside);
+    }
 }
 
 impl<'a> fmt::Debug for LayoutBox<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "type:\n  {:?}\n{:?}\n", self.box_type, self.dimensions)
+        write!(f, "type:\n  {:?}\n{:?}\n", self.box_type, self.dimensions)?;
+
+        for run in &self.text_runs {
+            write!(f, "text:\n  {:?} {:?}\n", run.text, run.rect)?;
+        }
+        Ok(())
     }
 }
 
@@ -319,45 +605,143 @@ impl fmt::Debug for BoxType {
             BoxType::Inline => "inline",
             BoxType::InlineBlock => "inline-block",
             BoxType::Anonymous => "anonymous",
+            BoxType::Text => "text",
         };
 
         write!(f, "{}", display_type)
     }
 }
 
+// Percentages of widths, margins and paddings all refer to the width of the
+// containing block.
 fn get_absolute_num(s_node: &StyledNode, b_box: Dimensions, prop: &str) -> Option<f32> {
-    match s_node.value(prop) {
-        Some(ref v) => match ***v {
-            Value::Length(l, ref u) => match *u {
-                Unit::Px => Some(l),
-                Unit::Pct => Some(l * b_box.content.width / 100.0),
-                _ => panic!("Unimplemented css length unit"),
-            },
-            _ => None,
-        },
-        None => None,
+    s_node.length(prop, b_box.content.width)
+}
+
+fn union_bounds(acc: Option<Rectangle>, rect: Rectangle) -> Option<Rectangle> {
+    match acc {
+        Some(a) => {
+            let x = a.x.min(rect.x);
+            let y = a.y.min(rect.y);
+            Some(Rectangle {
+                x,
+                y,
+                width: (a.x + a.width).max(rect.x + rect.width) - x,
+                height: (a.y + a.height).max(rect.y + rect.height) - y,
+            })
+        }
+        None => Some(rect),
+    }
+}
+
+fn line_metrics(style: &StyledNode) -> (FontMetrics, f32) {
+    let font = FontMetrics::new(style.num_or("font-size", 16.0));
+    let line_height = match style.value("line-height") {
+        Some(&Value::Length(..)) | Some(&Value::Percentage(_)) => {
+            style.length("line-height", font.size).unwrap_or(0.0)
+        }
+        Some(&Value::Number(n)) => n * font.size,
+        _ => font.normal_line_height(),
+    };
+    (font, line_height)
+}
+
+fn break_lines(items: &[InlineItem], available: f32, strut: (FontMetrics, f32)) -> Vec<LineBox> {
+    let new_line = || {
+        let (ascent, descent) = above_below_baseline(strut.0, strut.1);
+        LineBox {
+            items: Vec::new(),
+            width: 0.0,
+            ascent,
+            descent,
+        }
+    };
+
+    let mut lines = Vec::new();
+    let mut line = new_line();
+    let mut pending_space: f32 = 0.0;
+
+    for (index, item) in items.iter().enumerate() {
+        let (width, ascent, descent) = match *item {
+            InlineItem::Space { width } => {
+                if !line.items.is_empty() {
+                    pending_space = pending_space.max(width);
+                }
+                continue;
+            }
+            InlineItem::Word {
+                width,
+                font,
+                line_height,
+                ..
+            } => {
+                let (ascent, descent) = above_below_baseline(font, line_height);
+                (width, ascent, descent)
+            }
+            InlineItem::Atomic { width, height, .. } => (width, height, 0.0),
+        };
+
+        if !line.items.is_empty() && line.width + pending_space + width > available {
+            lines.push(mem::replace(&mut line, new_line()));
+            pending_space = 0.0;
+        }
+
+        let x = line.width + pending_space;
+        line.items.push(PlacedItem {
+            index,
+            x,
+            space_before: pending_space > 0.0,
+        });
+        line.width = x + width;
+        line.ascent = line.ascent.max(ascent);
+        line.descent = line.descent.max(descent);
+        pending_space = 0.0;
     }
+
+    if !line.items.is_empty() {
+        lines.push(line);
+    }
+    lines
 }
 
+// The leading is split evenly above and below the glyphs.
+fn above_below_baseline(font: FontMetrics, line_height: f32) -> (f32, f32) {
+    let half_leading = (line_height - font.ascent - font.descent) / 2.0;
+    (font.ascent + half_leading, font.descent + half_leading)
+}
+
+fn is_collapsible(c: char) -> bool {
+    c == ' ' || c == '\t' || c == '\n' || c == '\r' || c == '\u{c}'
+}
+
+fn is_auto(style: &StyledNode, prop: &str) -> bool {
+    style.keyword(prop) == Some("auto")
+}
 
 pub fn layout_tree<'a>(
     root: &'a StyledNode<'a>,
     mut containing_block: Dimensions,
+    quirks_mode: QuirksMode,
 ) -> LayoutBox<'a> {
+    let viewport_height = containing_block.content.height;
     containing_block.content.height = 0.0;
 
     let mut root_box = build_layout_tree(root);
     root_box.layout(containing_block);
+    if quirks_mode == QuirksMode::Quirks {
+        root_box.fill_viewport(viewport_height);
+    }
     return root_box;
 }
 
 fn build_layout_tree<'a>(node: &'a StyledNode) -> LayoutBox<'a> {
     let mut layout_node = LayoutBox::new(
         match node.get_display() {
+            _ if node.text().is_some() => BoxType::Text,
             Display::Block => BoxType::Block,
             Display::Inline => BoxType::Inline,
             Display::InlineBlock => BoxType::InlineBlock,
-            Display::None => BoxType::Anonymous,
+            Display::None => return LayoutBox::new(BoxType::Anonymous, node),
         },
         node,
     );
@@ -370,6 +754,7 @@ fn build_layout_tree<'a>(node: &'a StyledNode) -> LayoutBox<'a> {
             Display::None => {}
         }
     }
+    layout_node.wrap_inline_runs();
     layout_node
 }
 
@@ -380,3 +765,120 @@ pub fn pretty_print<'a>(n: &'a LayoutBox, level: usize) {
         pretty_print(&child, level + 1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use document::Document;
+    use std::path::Path;
+    use style::StyledNode;
+
+    // Lays `html` out in a viewport `width` pixels wide and hands the root
+    // box to `check`.
+    fn with_layout<F>(html: &str, width: f32, check: F)
+    where
+        F: FnOnce(&LayoutBox),
+    {
+        let document = Document::from_html(html, Path::new(""));
+        let root = document.root().unwrap();
+        let styled = StyledNode::new(root, &document.stylesheets, width, 600.0);
+
+        let mut viewport = Dimensions::default();
+        viewport.content.width = width;
+        viewport.content.height = 600.0;
+        check(&layout_tree(&styled, viewport, document.quirks_mode));
+    }
+
+    fn find<'t, 'a>(layout_box: &'t LayoutBox<'a>, tag_name: &str) -> &'t LayoutBox<'a> {
+        fn search<'t, 'a>(b: &'t LayoutBox<'a>, tag_name: &str) -> Option<&'t LayoutBox<'a>> {
+            match b.box_type {
+                BoxType::Anonymous | BoxType::Text => None,
+                _ if b.styled_node.tag_name() == Some(tag_name) => Some(b),
+                _ => None,
+            }
+            .or_else(|| b.children.iter().filter_map(|c| search(c, tag_name)).next())
+        }
+        search(layout_box, tag_name).unwrap_or_else(|| panic!("no <{}> box", tag_name))
+    }
+
+    fn box_types(layout_box: &LayoutBox) -> Vec<String> {
+        layout_box
+            .children
+            .iter()
+            .map(|c| format!("{:?}", c.box_type))
+            .collect()
+    }
+
+    // Each text run as its text and the top of its line.
+    fn runs(layout_box: &LayoutBox) -> Vec<(String, f32)> {
+        let mut runs: Vec<_> = layout_box
+            .text_runs
+            .iter()
+            .map(|r| (r.text.clone(), r.rect.y))
+            .collect();
+        for child in &layout_box.children {
+            runs.extend(self::runs(child));
+        }
+        runs
+    }
+
+    #[test]
+    fn text_wraps_at_the_containing_width() {
+        // Glyphs are 6px wide at 10px, so each word with its space takes 30px.
+        // Lines are 12px tall and each run sits 1px down, below the half-leading.
+        let html = "<!DOCTYPE html><style>body { margin: 0 } \
+                    p { margin: 0; width: 60px; font-size: 10px }</style>\
+                    <p>aaaa bbbb cccc dddd abcdefghijkl</p>";
+
+        with_layout(html, 800.0, |root| {
+            let p = find(root, "p");
+            assert_eq!(
+                runs(p),
+                vec![
+                    (String::from("aaaa bbbb"), 1.0),
+                    (String::from("cccc dddd"), 13.0),
+                    (String::from("abcdefghijkl"), 25.0),
+                ]
+            );
+            assert_eq!(p.dimensions.content.height, 36.0);
+        });
+    }
+
+    #[test]
+    fn text_fits_on_one_line_when_there_is_room() {
+        let html = "<!DOCTYPE html><style>body { margin: 0 } \
+                    p { margin: 0; font-size: 10px }</style><p>aaaa <b>bbbb</b> cccc</p>";
+
+        with_layout(html, 800.0, |root| {
+            let p = find(root, "p");
+            assert_eq!(box_types(p), vec!["text", "inline", "text"]);
+            assert_eq!(
+                runs(p),
+                vec![
+                    (String::from("aaaa"), 1.0),
+                    (String::from("bbbb"), 1.0),
+                    (String::from("cccc"), 1.0),
+                ]
+            );
+            assert_eq!(p.dimensions.content.height, 12.0);
+        });
+    }
+
+    #[test]
+    fn inline_runs_beside_blocks_get_anonymous_boxes() {
+        let html = "<!DOCTYPE html><style>body { margin: 0 } \
+                    div, p { margin: 0; font-size: 10px }</style>\
+                    <div>one <span>two</span><p>three</p>four</div>";
+
+        with_layout(html, 800.0, |root| {
+            let div = find(root, "div");
+            assert_eq!(box_types(div), vec!["anonymous", "block", "anonymous"]);
+            assert_eq!(box_types(&div.children[0]), vec!["text", "inline"]);
+            assert_eq!(box_types(&div.children[2]), vec!["text"]);
+
+            // The anonymous boxes stack like any other block.
+            let tops: Vec<f32> = div.children.iter().map(|c| c.dimensions.content.y).collect();
+            assert_eq!(tops, vec![0.0, 12.0, 24.0]);
+        });
+    }
+}
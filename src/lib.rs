@@ -3,12 +3,21 @@ extern crate gfx;
 extern crate gfx_text;
 extern crate gfx_window_glutin;
 extern crate glutin;
+extern crate font8x8;
+extern crate png;
 
 pub mod render;
 pub mod command;
 pub mod dom;
 pub mod html_parse;
+pub mod html_tokenizer;
 pub mod css;
 pub mod css_parser;
+pub mod css_shorthand;
+pub mod css_tokenizer;
+pub mod document;
+pub mod entities;
+pub mod font;
 pub mod style;
 pub mod layout;
+pub mod raster;
@@ -0,0 +1,166 @@
+use font8x8::{UnicodeFonts, BASIC_FONTS, LATIN_FONTS};
+use png;
+use png::HasParameters;
+
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use command::DisplayCommand;
+use css::Color;
+use font::FontMetrics;
+use layout::Rectangle;
+
+pub struct Canvas {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Canvas {
+    pub fn new(width: usize, height: usize, background: &Color) -> Canvas {
+        let mut pixels = Vec::with_capacity(width * height * 4);
+        let clear = to_rgba8(background);
+
+        for _ in 0..width * height {
+            pixels.extend_from_slice(&clear);
+        }
+
+        Canvas {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    pub fn paint(&mut self, command: &DisplayCommand) {
+        match *command {
+            DisplayCommand::SolidRectangle(ref color, ref rect) => self.fill_rectangle(color, rect),
+            DisplayCommand::Text(ref text, ref rect, size, ref color) => {
+                self.draw_text(text, rect, size, color)
+            }
+        }
+    }
+
+    // Each 8x8 glyph is stretched over one character cell, `advance` wide and
+    // as tall as the font's ascent plus descent, matching the layout metrics.
+    fn draw_text(&mut self, text: &str, rect: &Rectangle, size: f32, color: &Color) {
+        if color.a <= 0.0 {
+            return;
+        }
+
+        let font = FontMetrics::new(size);
+        let cell_height = font.ascent + font.descent;
+        let y0 = clamp_index(rect.y - 0.5, self.height);
+        let y1 = clamp_index(rect.y + cell_height - 0.5, self.height);
+
+        for (i, c) in text.chars().enumerate() {
+            let glyph = match glyph(c) {
+                Some(g) => g,
+                None => continue,
+            };
+            let cell_x = rect.x + i as f32 * font.advance;
+            let x0 = clamp_index(cell_x - 0.5, self.width);
+            let x1 = clamp_index(cell_x + font.advance - 0.5, self.width);
+
+            for y in y0..y1 {
+                let row = ((y as f32 + 0.5 - rect.y) / cell_height * 8.0) as usize;
+                for x in x0..x1 {
+                    let col = ((x as f32 + 0.5 - cell_x) / font.advance * 8.0) as usize;
+                    if row < 8 && col < 8 && glyph[row] & (1 << col) != 0 {
+                        self.blend_pixel(x, y, color);
+                    }
+                }
+            }
+        }
+    }
+
+    fn fill_rectangle(&mut self, color: &Color, rect: &Rectangle) {
+        if color.a <= 0.0 {
+            return;
+        }
+
+        // A pixel is covered when its center lies inside the rectangle, the
+        // same rule the GL rasterizer applies to the window's quads.
+        let x0 = clamp_index(rect.x - 0.5, self.width);
+        let y0 = clamp_index(rect.y - 0.5, self.height);
+        let x1 = clamp_index(rect.x + rect.width - 0.5, self.width);
+        let y1 = clamp_index(rect.y + rect.height - 0.5, self.height);
+
+        for y in y0..y1 {
+            for x in x0..x1 {
+                self.blend_pixel(x, y, color);
+            }
+        }
+    }
+
+    fn blend_pixel(&mut self, x: usize, y: usize, color: &Color) {
+        let i = (y * self.width + x) * 4;
+        let alpha = clamp_unit(color.a);
+        let source = [color.r, color.g, color.b];
+
+        for c in 0..3 {
+            let dest = self.pixels[i + c] as f32 / 255.0;
+            let value = clamp_unit(source[c]) * alpha + dest * (1.0 - alpha);
+            self.pixels[i + c] = (value * 255.0).round() as u8;
+        }
+
+        let dest_alpha = self.pixels[i + 3] as f32 / 255.0;
+        let value = alpha + dest_alpha * (1.0 - alpha);
+        self.pixels[i + 3] = (value * 255.0).round() as u8;
+    }
+
+    pub fn write_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let file = File::create(path)?;
+        let mut encoder = png::Encoder::new(
+            BufWriter::new(file),
+            self.width as u32,
+            self.height as u32,
+        );
+        encoder.set(png::ColorType::RGBA).set(png::BitDepth::Eight);
+
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels)?;
+        Ok(())
+    }
+}
+
+pub fn rasterize(command_list: &[DisplayCommand], width: usize, height: usize) -> Canvas {
+    let mut canvas = Canvas::new(width, height, &Color::default());
+
+    for command in command_list {
+        canvas.paint(command);
+    }
+    canvas
+}
+
+fn glyph(c: char) -> Option<[u8; 8]> {
+    match c {
+        ' ' => None,
+        '\u{a0}' => None,
+        // The bitmap fonts have no curly quotes.
+        '\u{201c}' | '\u{201d}' => BASIC_FONTS.get('"'),
+        '\u{2018}' | '\u{2019}' => BASIC_FONTS.get('\''),
+        _ => BASIC_FONTS
+            .get(c)
+            .or_else(|| LATIN_FONTS.get(c))
+            .or_else(|| BASIC_FONTS.get('?')),
+    }
+}
+
+fn to_rgba8(color: &Color) -> [u8; 4] {
+    [
+        (clamp_unit(color.r) * 255.0).round() as u8,
+        (clamp_unit(color.g) * 255.0).round() as u8,
+        (clamp_unit(color.b) * 255.0).round() as u8,
+        (clamp_unit(color.a) * 255.0).round() as u8,
+    ]
+}
+
+fn clamp_unit(n: f32) -> f32 {
+    n.max(0.0).min(1.0)
+}
+
+fn clamp_index(n: f32, max: usize) -> usize {
+    n.ceil().max(0.0).min(max as f32) as usize
+}
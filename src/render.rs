@@ -7,25 +7,26 @@ use gfx::Factory;
 use gfx::traits::FactoryExt;
 use gfx::Device;
 
+use std::collections::HashMap;
+
 use layout;
 use command::DisplayCommand;
 
 pub type DepthFormat = gfx::format::DepthStencil;
 pub type ColorFormat = gfx::format::Rgba8;
 
-const SCREEN_WIDTH: usize = 1024;
-const SCREEN_HEIGHT: usize = 768;
 const CLEAR_COLOR: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
 
 gfx_defines!{
     vertex Vertex {
         pos: [f32; 2] = "a_Pos",
-        color: [f32; 3] = "a_Color",
+        color: [f32; 4] = "a_Color",
     }
 
     pipeline pipe {
         vbuf: gfx::VertexBuffer<Vertex> = (),
-        out: gfx::RenderTarget<ColorFormat> = "Target0",
+        out: gfx::BlendTarget<ColorFormat> =
+            ("Target0", gfx::state::MASK_ALL, gfx::preset::blend::ALPHA),
     }
 }
 
@@ -34,13 +35,33 @@ struct RenderText<'a> {
     text: &'a str,
     position: [i32; 2],
     color: [f32; 4],
+    size: u8,
 }
 
 fn render_texts(command_list: &[DisplayCommand]) -> Vec<RenderText> {
-    Vec::new()
+    let mut texts = Vec::new();
+
+    for command in command_list {
+        match *command {
+            DisplayCommand::Text(ref text, ref rect, size, ref color) => {
+                texts.push(RenderText {
+                    text,
+                    position: [rect.x.round() as i32, rect.y.round() as i32],
+                    color: [color.r, color.g, color.b, color.a],
+                    size: size.round().max(1.0).min(255.0) as u8,
+                });
+            }
+            _ => {}
+        }
+    }
+    texts
 }
 
-fn render_commands(command_list: &[DisplayCommand]) -> (Vec<Vertex>, Vec<u16>) {
+fn render_commands(
+    command_list: &[DisplayCommand],
+    width: usize,
+    height: usize,
+) -> (Vec<Vertex>, Vec<u16>) {
     let mut vertices = Vec::new();
     let mut index_data = Vec::new();
     let mut rect_num: u16 = 0;
@@ -48,9 +69,9 @@ fn render_commands(command_list: &[DisplayCommand]) -> (Vec<Vertex>, Vec<u16>) {
     for command in command_list {
         match *command {
             DisplayCommand::SolidRectangle(ref color, ref rect) => {
-                let c = [color.r, color.g, color.b];
+                let c = [color.r, color.g, color.b, color.a];
 
-                let mut v = render_rectangle(&c, rect);
+                let mut v = render_rectangle(&c, rect, width, height);
                 vertices.append(&mut v);
 
                 let index_base: u16 = rect_num * 4;
@@ -64,13 +85,19 @@ fn render_commands(command_list: &[DisplayCommand]) -> (Vec<Vertex>, Vec<u16>) {
                 ]);
                 rect_num += 1;
             }
+            DisplayCommand::Text(..) => {}
         }
     }
     return (vertices, index_data);
 }
 
-fn render_rectangle(c: &[f32; 3], rect: &layout::Rectangle) -> Vec<Vertex> {
-    let (x, y, h, w) = transform_rectangle(rect);
+fn render_rectangle(
+    c: &[f32; 4],
+    rect: &layout::Rectangle,
+    width: usize,
+    height: usize,
+) -> Vec<Vertex> {
+    let (x, y, h, w) = transform_rectangle(rect, width, height);
     let vertices = vec![
         Vertex {
             pos: [x + w, y],
@@ -93,20 +120,24 @@ fn render_rectangle(c: &[f32; 3], rect: &layout::Rectangle) -> Vec<Vertex> {
     vertices
 }
 
-fn transform_rectangle(rect: &layout::Rectangle) -> (f32, f32, f32, f32) {
-    let w = rect.width / SCREEN_WIDTH as f32 * 2.0;
-    let h = rect.height / SCREEN_HEIGHT as f32 * 2.0;
-    let x = rect.x / SCREEN_WIDTH as f32 * 2.0 - 1.0;
-    let y = -(rect.y / SCREEN_HEIGHT as f32 * 2.0 - 1.0 + h);
+fn transform_rectangle(
+    rect: &layout::Rectangle,
+    width: usize,
+    height: usize,
+) -> (f32, f32, f32, f32) {
+    let w = rect.width / width as f32 * 2.0;
+    let h = rect.height / height as f32 * 2.0;
+    let x = rect.x / width as f32 * 2.0 - 1.0;
+    let y = -(rect.y / height as f32 * 2.0 - 1.0 + h);
 
     (x, y, h, w)
 }
 
 
-pub fn render_loop(command_list: &[DisplayCommand]) {
+pub fn render_loop(command_list: &[DisplayCommand], width: usize, height: usize) {
     let builder = glutin::WindowBuilder::new()
         .with_title(String::from("Browser"))
-        .with_dimensions(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+        .with_dimensions(width as u32, height as u32)
         .with_vsync();
 
     let (window, mut device, mut factory, main_color, _main_depth) =
@@ -122,7 +153,7 @@ pub fn render_loop(command_list: &[DisplayCommand]) {
         )
         .unwrap();
 
-    let (vertices, index_data) = render_commands(command_list);
+    let (vertices, index_data) = render_commands(command_list, width, height);
     let texts = render_texts(command_list);
 
     let (vertex_buffer, slice) =
@@ -133,7 +164,17 @@ pub fn render_loop(command_list: &[DisplayCommand]) {
         out: main_color,
     };
 
-    let mut test_renderer = gfx_text::new(factory).build().unwrap();
+    // gfx_text bakes one font size into each renderer.
+    let mut text_renderers = HashMap::new();
+    for text in &texts {
+        if !text_renderers.contains_key(&text.size) {
+            let renderer = gfx_text::new(factory.clone())
+                .with_size(text.size)
+                .build()
+                .unwrap();
+            text_renderers.insert(text.size, renderer);
+        }
+    }
 
     'main: loop {
         for event in window.poll_events() {
@@ -145,13 +186,17 @@ pub fn render_loop(command_list: &[DisplayCommand]) {
         }
 
         for text in &texts {
-            test_renderer.add(text.text, text.position, text.color);
+            if let Some(renderer) = text_renderers.get_mut(&text.size) {
+                renderer.add(text.text, text.position, text.color);
+            }
         }
 
         encoder.clear(&data.out, CLEAR_COLOR);
 
         encoder.draw(&slice, &pso, &data);
-        test_renderer.draw(&mut encoder, &data.out);
+        for renderer in text_renderers.values_mut() {
+            renderer.draw(&mut encoder, &data.out).unwrap();
+        }
 
         encoder.flush(&mut device);
         window.swap_buffers().unwrap();
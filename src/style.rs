@@ -2,16 +2,31 @@ use std::collections::HashMap;
 use std::{fmt, str};
 
 use dom::{ElementData, Node, NodeType};
-use css::{Selector, Stylesheet, Value};
+use css::{AttrOperator, AttributeSelector, Color, LengthContext, Origin, PseudoClass, PseudoElement,
+          Rule, Selector, SimpleSelector, Stylesheet, Unit, Value};
+use font::FontMetrics;
 
-type PropertyMap<'a> = HashMap<&'a str, &'a Value>;
+type PropertyMap = HashMap<String, Value>;
+
+// The counters in scope, innermost last: a name, a value and the depth of the
+// element that created the counter.
+type Counters = Vec<(String, i32, usize)>;
 
 pub struct StyledNode<'a> {
-    node: &'a Node,
-    styles: PropertyMap<'a>,
+    source: Source<'a>,
+    styles: PropertyMap,
+    context: LengthContext,
     pub children: Vec<StyledNode<'a>>,
 }
 
+// What a styled node was made from: a node of the document, a `::before` or
+// `::after` box, or the text generated into one.
+enum Source<'a> {
+    Node(&'a Node),
+    PseudoElement(PseudoElement),
+    Generated(String),
+}
+
 pub enum Display {
     Block,
     Inline,
@@ -19,115 +34,922 @@ pub enum Display {
     None,
 }
 
+// An element together with the chain of its ancestors, which is what the
+// combinators need to walk while matching a selector from right to left.
+#[derive(Clone, Copy)]
+struct ElementRef<'a: 'b, 'b> {
+    node: &'a Node,
+    parent: Option<&'b ElementRef<'a, 'b>>,
+    index: usize,
+}
+
 impl<'a> StyledNode<'a> {
-    pub fn new(node: &'a Node, stylesheet: &'a Stylesheet) -> StyledNode<'a> {
+    pub fn new(
+        node: &'a Node,
+        stylesheets: &'a [Stylesheet],
+        viewport_width: f32,
+        viewport_height: f32,
+    ) -> StyledNode<'a> {
+        let context = LengthContext {
+            font_size: DEFAULT_FONT_SIZE,
+            root_font_size: DEFAULT_FONT_SIZE,
+            viewport_width,
+            viewport_height,
+            ch_advance: FontMetrics::new(DEFAULT_FONT_SIZE).advance,
+        };
+
+        let mut rules = Vec::new();
+        for stylesheet in stylesheets {
+            active_rules(stylesheet, &context, &mut rules);
+        }
+
+        StyledNode::build(
+            ElementRef {
+                node,
+                parent: None,
+                index: 0,
+            },
+            &rules,
+            None,
+            context,
+            &mut Counters::new(),
+            &mut 0,
+        )
+    }
+
+    fn build(
+        element: ElementRef<'a, '_>,
+        rules: &[(Origin, &Rule)],
+        parent_styles: Option<&PropertyMap>,
+        parent_context: LengthContext,
+        counters: &mut Counters,
+        quote_depth: &mut usize,
+    ) -> StyledNode<'a> {
+        let node = element.node;
+        let (styles, context) = match node.node_type {
+            NodeType::Element(_) => compute_styles(
+                StyledNode::get_styles(&element, rules, None),
+                parent_styles,
+                parent_context,
+            ),
+            _ => (PropertyMap::new(), parent_context),
+        };
+        let depth = element.depth();
+        update_counters(&styles, depth, counters);
+
+        // Counters created below this element go out of scope with it.
+        let scope = counters.len();
         let mut style_children = Vec::new();
+        let before = PseudoElement::Before;
+        style_children.extend(StyledNode::generate(
+            &element,
+            before,
+            rules,
+            &styles,
+            context,
+            counters,
+            quote_depth,
+        ));
 
-        for child in &node.children {
+        for (index, child) in node.children.iter().enumerate() {
             match child.node_type {
-                NodeType::Element(_) => style_children.push(StyledNode::new(&child, stylesheet)),
+                NodeType::Element(_) => style_children.push(StyledNode::build(
+                    ElementRef {
+                        node: child,
+                        parent: Some(&element),
+                        index,
+                    },
+                    rules,
+                    Some(&styles),
+                    context,
+                    counters,
+                    quote_depth,
+                )),
+                NodeType::Text(_) => style_children.push(
+                    StyledNode::text_node(Source::Node(child), &styles, context),
+                ),
                 _ => {}
             }
         }
 
+        let after = PseudoElement::After;
+        style_children.extend(StyledNode::generate(
+            &element,
+            after,
+            rules,
+            &styles,
+            context,
+            counters,
+            quote_depth,
+        ));
+        counters.truncate(scope);
+
         StyledNode {
-            node,
-            styles: match node.node_type {
-                NodeType::Element(ref e) => StyledNode::get_styles(e, stylesheet),
-                _ => PropertyMap::new(),
-            },
+            source: Source::Node(node),
+            styles,
+            context,
             children: style_children,
         }
     }
 
-    fn get_styles(element: &'a ElementData, stylesheet: &'a Stylesheet) -> PropertyMap<'a> {
-        let mut styles = PropertyMap::new();
+    fn text_node(
+        source: Source<'a>,
+        parent_styles: &PropertyMap,
+        parent_context: LengthContext,
+    ) -> StyledNode<'a> {
+        let (styles, context) =
+            compute_styles(PropertyMap::new(), Some(parent_styles), parent_context);
+
+        StyledNode {
+            source,
+            styles,
+            context,
+            children: Vec::new(),
+        }
+    }
+
+    // The `::before` or `::after` box of an element, holding the text its
+    // `content` generates, or None when it has no content or isn't displayed.
+    fn generate(
+        element: &ElementRef,
+        pseudo_element: PseudoElement,
+        rules: &[(Origin, &Rule)],
+        parent_styles: &PropertyMap,
+        parent_context: LengthContext,
+        counters: &mut Counters,
+        quote_depth: &mut usize,
+    ) -> Option<StyledNode<'a>> {
+        let data = element.data()?;
+        let specified = StyledNode::get_styles(element, rules, Some(pseudo_element));
+        if specified.is_empty() {
+            return None;
+        }
+
+        let (styles, context) = compute_styles(specified, Some(parent_styles), parent_context);
+        let keyword = |property: &str| match styles.get(property) {
+            Some(&Value::Keyword(ref k)) => Some(k.clone()),
+            _ => None,
+        };
+        match (keyword("content"), keyword("display")) {
+            (Some(ref content), _) if content == "normal" || content == "none" => return None,
+            (_, Some(ref display)) if display == "none" => return None,
+            _ => {}
+        }
+
+        update_counters(&styles, element.depth() + 1, counters);
+        let text = generated_text(&styles["content"], data, counters, quote_depth);
+        let text_node = StyledNode::text_node(Source::Generated(text), &styles, context);
+
+        Some(StyledNode {
+            source: Source::PseudoElement(pseudo_element),
+            styles,
+            context,
+            children: vec![text_node],
+        })
+    }
+
+    fn get_styles(
+        element: &ElementRef,
+        rules: &[(Origin, &Rule)],
+        pseudo_element: Option<PseudoElement>,
+    ) -> PropertyMap {
+        let mut matched = Vec::new();
+
+        for &(origin, rule) in rules {
+            let specificity = rule.selectors
+                .iter()
+                .filter(|s| s.pseudo_element == pseudo_element && selector_matches(element, s))
+                .map(|s| s.specificity())
+                .max();
 
-        for rule in &stylesheet.rules {
-            for selector in &rule.selectors {
-                if selector_matches(element, &selector) {
-                    for declar in &rule.declarations {
-                        styles.insert(&declar.property, &declar.value);
-                    }
-                    break;
+            if let Some(specificity) = specificity {
+                for declar in &rule.declarations {
+                    matched.push((cascade_level(origin, declar.important), specificity, declar));
                 }
             }
         }
+
+        // The sort is stable, so declarations that tie on origin, importance
+        // and specificity stay in source order and the later one wins.
+        matched.sort_by_key(|&(level, specificity, _)| (level, specificity));
+
+        let mut styles = PropertyMap::new();
+        for (_, _, declar) in matched {
+            styles.insert(declar.property.clone(), declar.value.clone());
+        }
         styles
     }
 
-    pub fn value(&self, name: &str) -> Option<&&Value> {
+    pub fn value(&self, name: &str) -> Option<&Value> {
         self.styles.get(name)
     }
 
+    pub fn keyword(&self, name: &str) -> Option<&str> {
+        match self.value(name) {
+            Some(&Value::Keyword(ref k)) => Some(k),
+            _ => None,
+        }
+    }
+
+    pub fn color(&self, name: &str) -> Option<&Color> {
+        match self.value(name) {
+            Some(&Value::Color(ref c)) => Some(c),
+            _ => None,
+        }
+    }
+
+    pub fn number(&self, name: &str) -> Option<f32> {
+        match self.value(name) {
+            Some(&Value::Number(n)) => Some(n),
+            _ => None,
+        }
+    }
+
+    // A length or percentage in pixels, with percentages taken of
+    // `percent_base`.
+    pub fn length(&self, name: &str, percent_base: f32) -> Option<f32> {
+        self.value(name)
+            .and_then(|v| v.to_px(&self.context, percent_base))
+    }
+
+    pub fn tag_name(&self) -> Option<&str> {
+        match self.source {
+            Source::Node(&Node {
+                node_type: NodeType::Element(ref e),
+                ..
+            }) => Some(&e.tag_name),
+            _ => None,
+        }
+    }
+
+    pub fn length_context(&self) -> LengthContext {
+        self.context
+    }
+
+    pub fn text(&self) -> Option<&str> {
+        match self.source {
+            Source::Node(&Node {
+                node_type: NodeType::Text(ref t),
+                ..
+            }) => Some(t),
+            Source::Generated(ref t) => Some(t),
+            _ => None,
+        }
+    }
+
     pub fn get_display(&self) -> Display {
-        match self.value("display") {
-            Some(s) => match **s {
-                Value::Other(ref v) => match v.as_ref() {
-                    "block" => Display::Block,
-                    "none" => Display::None,
-                    "inline-block" => Display::InlineBlock,
-                    _ => Display::Inline,
-                },
-                _ => Display::Inline,
-            },
-            None => Display::Inline,
+        match self.keyword("display") {
+            Some("block") => Display::Block,
+            Some("none") => Display::None,
+            Some("inline-block") => Display::InlineBlock,
+            _ => Display::Inline,
         }
     }
 
+    // Percentages need a containing block to resolve against, so they fall
+    // back to `default` here; layout resolves them itself.
     pub fn num_or(&self, name: &str, default: f32) -> f32 {
         match self.value(name) {
-            Some(v) => match **v {
-                Value::Length(n, _) => n,
-                _ => default,
-            },
-            None => default,
+            Some(&Value::Length(..)) => self.length(name, 0.0).unwrap_or(default),
+            _ => default,
         }
     }
 }
 
 impl<'a> fmt::Debug for StyledNode<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{:?}: {:?}", self.node, self.styles)
+        match self.source {
+            Source::Node(node) => write!(f, "{:?}: {:?}", node, self.styles),
+            Source::PseudoElement(PseudoElement::Before) => {
+                write!(f, "::before: {:?}", self.styles)
+            }
+            Source::PseudoElement(PseudoElement::After) => write!(f, "::after: {:?}", self.styles),
+            Source::Generated(ref text) => write!(f, "{}: {:?}", text, self.styles),
+        }
+    }
+}
+
+const DEFAULT_FONT_SIZE: f32 = 16.0;
+
+// Collects the style rules of a sheet in source order, with the rules of
+// each @media block whose queries match the viewport spliced in where the
+// block stood.
+fn active_rules<'s>(
+    stylesheet: &'s Stylesheet,
+    context: &LengthContext,
+    rules: &mut Vec<(Origin, &'s Rule)>,
+) {
+    let mut at_rules = stylesheet.at_rules.iter().peekable();
+
+    for i in 0..stylesheet.rules.len() + 1 {
+        while let Some(at_rule) = at_rules.peek().filter(|a| a.index <= i).cloned() {
+            at_rules.next();
+
+            let applies = at_rule.name == "media" && (at_rule.media.is_empty()
+                || at_rule.media.iter().any(|q| q.matches(context)));
+            if let (true, Some(block)) = (applies, at_rule.block.as_ref()) {
+                active_rules(block, context, rules);
+            }
+        }
+
+        if let Some(rule) = stylesheet.rules.get(i) {
+            rules.push((stylesheet.origin, rule));
+        }
     }
 }
 
-fn selector_matches(element: &ElementData, selector: &Selector) -> bool {
-    for simple in &selector.simple {
-        let mut selector_match = true;
+// Where a declaration stands in the cascade before specificity counts:
+// normal user-agent, normal author, important author, important user-agent.
+fn cascade_level(origin: Origin, important: bool) -> u8 {
+    match (important, origin) {
+        (false, Origin::UserAgent) => 0,
+        (false, Origin::Author) => 1,
+        (true, Origin::Author) => 2,
+        (true, Origin::UserAgent) => 3,
+    }
+}
+
+// Properties the engine knows about, in the order they are computed, and
+// whether they inherit. `color` comes first because the border colors start
+// out as the computed `color`.
+const PROPERTIES: &'static [(&'static str, bool)] = &[
+    ("color", true),
+    ("font-family", true),
+    ("font-size", true),
+    ("font-style", true),
+    ("font-weight", true),
+    ("line-height", true),
+    ("letter-spacing", true),
+    ("word-spacing", true),
+    ("text-align", true),
+    ("text-indent", true),
+    ("text-transform", true),
+    ("white-space", true),
+    ("visibility", true),
+    ("list-style-type", true),
+    ("cursor", true),
+    ("display", false),
+    ("width", false),
+    ("height", false),
+    ("margin-top", false),
+    ("margin-right", false),
+    ("margin-bottom", false),
+    ("margin-left", false),
+    ("padding-top", false),
+    ("padding-right", false),
+    ("padding-bottom", false),
+    ("padding-left", false),
+    ("border-top-width", false),
+    ("border-right-width", false),
+    ("border-bottom-width", false),
+    ("border-left-width", false),
+    ("border-top-style", false),
+    ("border-right-style", false),
+    ("border-bottom-style", false),
+    ("border-left-style", false),
+    ("border-top-color", false),
+    ("border-right-color", false),
+    ("border-bottom-color", false),
+    ("border-left-color", false),
+    ("background-color", false),
+    ("content", false),
+    ("counter-reset", false),
+    ("counter-increment", false),
+];
+
+fn initial_value(property: &str, styles: &PropertyMap) -> Option<Value> {
+    let keyword = |k: &str| Some(Value::Keyword(String::from(k)));
+
+    match property {
+        "color" => Some(Value::Color(Color::new(0.0, 0.0, 0.0, 1.0))),
+        "font-family" => keyword("serif"),
+        "font-size" => Some(Value::Length(DEFAULT_FONT_SIZE, Unit::Px)),
+        "font-style" | "font-weight" | "line-height" | "letter-spacing" | "word-spacing" => {
+            keyword("normal")
+        }
+        "text-align" => keyword("left"),
+        "text-indent" => Some(Value::Length(0.0, Unit::Px)),
+        "text-transform" => keyword("none"),
+        "white-space" => keyword("normal"),
+        "visibility" => keyword("visible"),
+        "list-style-type" => keyword("disc"),
+        "cursor" | "width" | "height" => keyword("auto"),
+        "display" => keyword("inline"),
+        "margin-top" | "margin-right" | "margin-bottom" | "margin-left" | "padding-top" |
+        "padding-right" | "padding-bottom" | "padding-left" => Some(Value::Length(0.0, Unit::Px)),
+        // `medium`; sides left without a border style are zeroed once computed.
+        "border-top-width" | "border-right-width" | "border-bottom-width" |
+        "border-left-width" => Some(Value::Length(3.0, Unit::Px)),
+        "border-top-style" | "border-right-style" | "border-bottom-style" |
+        "border-left-style" => keyword("none"),
+        "border-top-color" | "border-right-color" | "border-bottom-color" |
+        "border-left-color" => styles.get("color").cloned(),
+        "background-color" => Some(Value::Color(Color::new(0.0, 0.0, 0.0, 0.0))),
+        "content" => keyword("normal"),
+        "counter-reset" | "counter-increment" => keyword("none"),
+        _ => None,
+    }
+}
 
-        match simple.tag_name {
-            Some(ref t) => if *t != element.tag_name {
-                continue;
+fn is_inherited(property: &str) -> bool {
+    PROPERTIES
+        .iter()
+        .any(|&(name, inherited)| name == property && inherited)
+}
+
+// Turns the cascaded values of an element into computed values: resolves the
+// `inherit`, `initial` and `unset` keywords, copies inherited properties from
+// the parent, fills every other known property with its initial value and
+// converts lengths to pixels. Returns the context the element's own lengths
+// were resolved in, which is also what its children start from.
+fn compute_styles(
+    specified: PropertyMap,
+    parent: Option<&PropertyMap>,
+    parent_context: LengthContext,
+) -> (PropertyMap, LengthContext) {
+    let mut styles = PropertyMap::new();
+    let inherit = |property: &str| parent.and_then(|p| p.get(property)).cloned();
+
+    for &(property, inherited) in PROPERTIES {
+        let value = match specified.get(property) {
+            Some(v) => match css_wide_keyword(v) {
+                Some("inherit") => inherit(property),
+                Some("unset") if inherited => inherit(property),
+                Some(_) => None,
+                // `color: currentcolor` means the parent's color.
+                None if is_current_color(v) => match property {
+                    "color" => inherit(property),
+                    _ => styles.get("color").cloned(),
+                },
+                None => Some(v.clone()),
             },
+            None if inherited => inherit(property),
+            None => None,
+        };
+
+        match value.or_else(|| initial_value(property, &styles)) {
+            Some(v) => {
+                styles.insert(String::from(property), v);
+            }
             None => {}
+        }
+    }
+
+    for (property, v) in specified {
+        if styles.contains_key(&property) {
+            continue;
+        }
+
+        let value = match css_wide_keyword(&v) {
+            Some("inherit") => inherit(&property),
+            Some("unset") if is_inherited(&property) => inherit(&property),
+            Some(_) => None,
+            None if is_current_color(&v) => styles.get("color").cloned(),
+            None => Some(v),
         };
 
-        match element.get_id() {
-            Some(i) => match simple.id {
-                Some(ref id) => if *i != *id {
-                    continue;
-                },
-                None => {}
-            },
-            None => match simple.id {
-                Some(_) => {
-                    continue;
+        if let Some(v) = value {
+            styles.insert(property, v);
+        }
+    }
+
+    let font_size = compute_font_size(styles.get("font-size"), &parent_context);
+    let mut context = LengthContext {
+        font_size,
+        ch_advance: FontMetrics::new(font_size).advance,
+        ..parent_context
+    };
+    if parent.is_none() {
+        context.root_font_size = font_size;
+    }
+
+    for (property, value) in styles.iter_mut() {
+        let px = match *value {
+            _ if property == "font-size" => Some(font_size),
+            Value::Percentage(_) if property == "line-height" => value.to_px(&context, font_size),
+            Value::Length(_, Unit::Px) => None,
+            Value::Length(..) => value.to_px(&context, 0.0),
+            _ => None,
+        };
+
+        if let Some(px) = px {
+            *value = Value::Length(px, Unit::Px);
+        }
+    }
+
+    // A side without a border style has no border, whatever its width.
+    for side in &["top", "right", "bottom", "left"] {
+        let style = styles.get(&format!("border-{}-style", side)).cloned();
+        if let Some(Value::Keyword(ref s)) = style {
+            if s == "none" || s == "hidden" {
+                styles.insert(format!("border-{}-width", side), Value::Length(0.0, Unit::Px));
+            }
+        }
+    }
+    (styles, context)
+}
+
+// Font sizes are relative to the parent's, including percentages.
+fn compute_font_size(value: Option<&Value>, parent: &LengthContext) -> f32 {
+    let scale = |factor: f32| DEFAULT_FONT_SIZE * factor;
+
+    match value {
+        Some(&Value::Length(..)) | Some(&Value::Percentage(_)) => value
+            .and_then(|v| v.to_px(parent, parent.font_size))
+            .unwrap_or(parent.font_size),
+        Some(&Value::Keyword(ref keyword)) => match keyword.as_ref() {
+            "xx-small" => scale(3.0 / 5.0),
+            "x-small" => scale(3.0 / 4.0),
+            "small" => scale(8.0 / 9.0),
+            "medium" => scale(1.0),
+            "large" => scale(6.0 / 5.0),
+            "x-large" => scale(3.0 / 2.0),
+            "xx-large" => scale(2.0),
+            "xxx-large" => scale(3.0),
+            "larger" => parent.font_size * 1.2,
+            "smaller" => parent.font_size / 1.2,
+            _ => parent.font_size,
+        },
+        _ => parent.font_size,
+    }
+}
+
+fn css_wide_keyword(value: &Value) -> Option<&'static str> {
+    match *value {
+        Value::Keyword(ref s) => match s.as_ref() {
+            "inherit" => Some("inherit"),
+            "initial" => Some("initial"),
+            "unset" => Some("unset"),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_current_color(value: &Value) -> bool {
+    match *value {
+        Value::Keyword(ref s) => s == "currentcolor",
+        _ => false,
+    }
+}
+
+impl<'a, 'b> ElementRef<'a, 'b> {
+    fn data(&self) -> Option<&'a ElementData> {
+        match self.node.node_type {
+            NodeType::Element(ref e) => Some(e),
+            _ => None,
+        }
+    }
+
+    fn depth(&self) -> usize {
+        self.parent.map_or(0, |p| p.depth() + 1)
+    }
+
+    fn previous_sibling(&self) -> Option<ElementRef<'a, 'b>> {
+        let parent = self.parent?;
+
+        parent.node.children[..self.index]
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|&(_, n)| is_element(n))
+            .map(|(index, node)| ElementRef {
+                node,
+                parent: Some(parent),
+                index,
+            })
+    }
+
+    // Where the element stands among its parent's element children that
+    // `counts` accepts, as positions from the start and from the end. The
+    // root element is alone.
+    fn position<F>(&self, counts: F) -> (usize, usize)
+    where
+        F: Fn(&Node) -> bool,
+    {
+        let siblings = match self.parent {
+            Some(parent) => &parent.node.children,
+            None => return (1, 1),
+        };
+        let count = |nodes: &[Node]| nodes.iter().filter(|n| is_element(n) && counts(n)).count();
+
+        (
+            count(&siblings[..self.index]) + 1,
+            count(&siblings[self.index + 1..]) + 1,
+        )
+    }
+}
+
+// Applies `counter-reset` and then `counter-increment`. A reset replaces a
+// counter that a preceding sibling created, and an increment of a counter
+// that isn't in scope creates it at 0 first.
+fn update_counters(styles: &PropertyMap, depth: usize, counters: &mut Counters) {
+    for (name, value) in counter_changes(styles.get("counter-reset")) {
+        if let Some(i) = counters.iter().rposition(|c| c.0 == name) {
+            if counters[i].2 == depth {
+                counters.remove(i);
+            }
+        }
+        counters.push((name, value, depth));
+    }
+
+    for (name, value) in counter_changes(styles.get("counter-increment")) {
+        match counters.iter().rposition(|c| c.0 == name) {
+            Some(i) => counters[i].1 += value,
+            None => counters.push((name, value, depth)),
+        }
+    }
+}
+
+// The parser gives counter lists as name and number pairs.
+fn counter_changes(value: Option<&Value>) -> Vec<(String, i32)> {
+    match value {
+        Some(&Value::List(ref values, _)) => values
+            .chunks(2)
+            .filter_map(|pair| match *pair {
+                [Value::Keyword(ref name), Value::Number(n)] => Some((name.clone(), n as i32)),
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Quotes nest across the whole document: each open-quote goes one level
+// deeper and each close-quote back out, and only the marks of the levels in
+// use are inserted.
+fn generated_text(
+    content: &Value,
+    element: &ElementData,
+    counters: &Counters,
+    quote_depth: &mut usize,
+) -> String {
+    let items = match *content {
+        Value::List(ref items, _) => items.iter().collect(),
+        ref item => vec![item],
+    };
+    let values = |name: &str| {
+        let values: Vec<i32> = counters.iter().filter(|c| c.0 == name).map(|c| c.1).collect();
+        match values.is_empty() {
+            true => vec![0],
+            false => values,
+        }
+    };
+    let mut text = String::new();
+
+    for item in items {
+        match *item {
+            Value::String(ref s) => text.push_str(s),
+            Value::Keyword(ref k) if k == "open-quote" => {
+                text.push(QUOTES[(*quote_depth).min(1)].0);
+                *quote_depth += 1;
+            }
+            Value::Keyword(ref k) if k == "close-quote" && *quote_depth > 0 => {
+                *quote_depth -= 1;
+                text.push(QUOTES[(*quote_depth).min(1)].1);
+            }
+            Value::Keyword(ref k) if k == "no-open-quote" => *quote_depth += 1,
+            Value::Keyword(ref k) if k == "no-close-quote" && *quote_depth > 0 => *quote_depth -= 1,
+            Value::Function(ref function, ref args) => match (function.as_ref(), args.as_slice()) {
+                ("attr", &[Value::Keyword(ref name)]) => {
+                    text.push_str(element.get_attribute(name).map_or("", |v| v))
+                }
+                ("counter", &[Value::Keyword(ref name), Value::Keyword(ref style)]) => {
+                    let value = *values(name).last().unwrap();
+                    text.push_str(&format_counter(value, style))
+                }
+                (
+                    "counters",
+                    &[Value::Keyword(ref name), Value::String(ref sep), Value::Keyword(ref style)],
+                ) => {
+                    let formatted: Vec<String> =
+                        values(name).iter().map(|&v| format_counter(v, style)).collect();
+                    text.push_str(&formatted.join(sep))
                 }
                 _ => {}
             },
+            _ => {}
         }
-        let element_classes = element.get_classes();
+    }
+    text
+}
+
+// The open and close marks for the outermost quotes, and for every level
+// inside them.
+const QUOTES: [(char, char); 2] = [('\u{201c}', '\u{201d}'), ('\u{2018}', '\u{2019}')];
+
+// Counter styles the engine doesn't know, and values a style can't show,
+// fall back to decimal.
+fn format_counter(value: i32, style: &str) -> String {
+    match style {
+        "none" => String::new(),
+        "disc" => String::from("\u{2022}"),
+        "circle" => String::from("\u{25e6}"),
+        "square" => String::from("\u{25aa}"),
+        "decimal-leading-zero" if value >= 0 && value < 10 => format!("0{}", value),
+        "lower-roman" if value > 0 && value < 4000 => roman(value),
+        "upper-roman" if value > 0 && value < 4000 => roman(value).to_uppercase(),
+        "lower-alpha" | "lower-latin" if value > 0 => alphabetic(value),
+        "upper-alpha" | "upper-latin" if value > 0 => alphabetic(value).to_uppercase(),
+        _ => value.to_string(),
+    }
+}
 
-        for class in &simple.classes {
-            selector_match &= element_classes.contains::<str>(class);
+fn roman(mut value: i32) -> String {
+    let numerals = [
+        (1000, "m"),
+        (900, "cm"),
+        (500, "d"),
+        (400, "cd"),
+        (100, "c"),
+        (90, "xc"),
+        (50, "l"),
+        (40, "xl"),
+        (10, "x"),
+        (9, "ix"),
+        (5, "v"),
+        (4, "iv"),
+        (1, "i"),
+    ];
+    let mut result = String::new();
+
+    for &(n, numeral) in numerals.iter() {
+        while value >= n {
+            result.push_str(numeral);
+            value -= n;
         }
+    }
+    result
+}
+
+// a, b, ..., z, aa, ab, ...
+fn alphabetic(mut value: i32) -> String {
+    let mut letters = Vec::new();
 
-        if selector_match {
-            return true;
+    while value > 0 {
+        value -= 1;
+        letters.push((b'a' + (value % 26) as u8) as char);
+        value /= 26;
+    }
+    letters.iter().rev().collect()
+}
+
+fn is_element(node: &Node) -> bool {
+    match node.node_type {
+        NodeType::Element(_) => true,
+        _ => false,
+    }
+}
+
+fn selector_matches(element: &ElementRef, selector: &Selector) -> bool {
+    match selector.simple.len() {
+        0 => false,
+        n => matches_from(element, selector, n - 1),
+    }
+}
+
+// Checks `selector.simple[i]` against `element`, then follows the combinator
+// to its left through the ancestors or preceding siblings.
+fn matches_from(element: &ElementRef, selector: &Selector, i: usize) -> bool {
+    if !simple_matches(element, &selector.simple[i]) {
+        return false;
+    }
+
+    if i == 0 {
+        return true;
+    }
+
+    match selector.combinators[i - 1] {
+        '>' => element
+            .parent
+            .map_or(false, |p| matches_from(p, selector, i - 1)),
+        '+' => element
+            .previous_sibling()
+            .map_or(false, |s| matches_from(&s, selector, i - 1)),
+        '~' => {
+            let mut sibling = element.previous_sibling();
+            while let Some(s) = sibling {
+                if matches_from(&s, selector, i - 1) {
+                    return true;
+                }
+                sibling = s.previous_sibling();
+            }
+            false
+        }
+        _ => {
+            let mut ancestor = element.parent;
+            while let Some(a) = ancestor {
+                if matches_from(a, selector, i - 1) {
+                    return true;
+                }
+                ancestor = a.parent;
+            }
+            false
         }
     }
-    false
+}
+
+// HTML element names match whatever their case, while ids and classes must
+// match exactly.
+fn simple_matches(element_ref: &ElementRef, simple: &SimpleSelector) -> bool {
+    let element = match element_ref.data() {
+        Some(e) => e,
+        None => return false,
+    };
+
+    match simple.tag_name {
+        Some(ref t) => if !t.eq_ignore_ascii_case(&element.tag_name) {
+            return false;
+        },
+        None => {}
+    };
+
+    if simple.ids.iter().any(|id| element.get_id() != Some(id)) {
+        return false;
+    }
+    let element_classes = element.get_classes();
+
+    simple
+        .classes
+        .iter()
+        .all(|class| element_classes.contains::<str>(class))
+        && simple
+            .attributes
+            .iter()
+            .all(|attribute| attribute_matches(element, attribute))
+        && simple
+            .pseudo_classes
+            .iter()
+            .all(|pseudo_class| pseudo_class_matches(element_ref, pseudo_class))
+}
+
+fn attribute_matches(element: &ElementData, selector: &AttributeSelector) -> bool {
+    let actual = match element.get_attribute(&selector.name) {
+        Some(v) => v,
+        None => return false,
+    };
+    let (actual, expected) = match selector.case_insensitive {
+        true => (actual.to_ascii_lowercase(), selector.value.to_ascii_lowercase()),
+        false => (actual.clone(), selector.value.clone()),
+    };
+
+    // An empty value can't be a word, prefix, suffix or substring.
+    match selector.operator {
+        AttrOperator::Exists => true,
+        AttrOperator::Equals => actual == expected,
+        AttrOperator::DashMatch => {
+            actual == expected || actual.starts_with(&format!("{}-", expected))
+        }
+        _ if expected.is_empty() => false,
+        AttrOperator::Includes => actual.split_whitespace().any(|word| word == expected),
+        AttrOperator::Prefix => actual.starts_with(&expected),
+        AttrOperator::Suffix => actual.ends_with(&expected),
+        AttrOperator::Substring => actual.contains(&expected),
+    }
+}
+
+fn pseudo_class_matches(element: &ElementRef, pseudo_class: &PseudoClass) -> bool {
+    let tag_name = element.data().map_or("", |e| &e.tag_name);
+    let same_type = |node: &Node| match node.node_type {
+        NodeType::Element(ref e) => e.tag_name.eq_ignore_ascii_case(tag_name),
+        _ => false,
+    };
+    // Counting siblings is linear, so only the arms that need a position
+    // pay for it.
+    let child_position = || element.position(|_| true);
+    let type_position = || element.position(same_type);
+    let any = |list: &[Selector]| list.iter().any(|s| selector_matches(element, s));
+
+    match *pseudo_class {
+        PseudoClass::Root => element.parent.is_none(),
+        PseudoClass::Empty => element.node.children.iter().all(|child| match child.node_type {
+            NodeType::Element(_) => false,
+            NodeType::Text(ref text) => text.is_empty(),
+            NodeType::Comment(_) | NodeType::Doctype(_) => true,
+        }),
+        PseudoClass::FirstChild => child_position().0 == 1,
+        PseudoClass::LastChild => child_position().1 == 1,
+        PseudoClass::OnlyChild => child_position() == (1, 1),
+        PseudoClass::FirstOfType => type_position().0 == 1,
+        PseudoClass::LastOfType => type_position().1 == 1,
+        PseudoClass::OnlyOfType => type_position() == (1, 1),
+        PseudoClass::NthChild(a, b) => nth_matches(a, b, child_position().0),
+        PseudoClass::NthLastChild(a, b) => nth_matches(a, b, child_position().1),
+        PseudoClass::NthOfType(a, b) => nth_matches(a, b, type_position().0),
+        PseudoClass::NthLastOfType(a, b) => nth_matches(a, b, type_position().1),
+        PseudoClass::Not(ref list) => !any(list),
+        PseudoClass::Is(ref list) | PseudoClass::Where(ref list) => any(list),
+    }
+}
+
+// Whether `position` is An+B for some n >= 0.
+fn nth_matches(a: i32, b: i32, position: usize) -> bool {
+    let offset = position as i32 - b;
+
+    match a {
+        0 => offset == 0,
+        a => offset % a == 0 && offset / a >= 0,
+    }
 }
 
 pub fn pretty_print(node: &StyledNode, indent_size: usize) {
@@ -138,3 +960,289 @@ pub fn pretty_print(node: &StyledNode, indent_size: usize) {
         pretty_print(&child, indent_size + 2);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use css_parser::CssParser;
+    use html_parse::HtmlParser;
+
+    // The ids of the elements `selector` matches, in document order.
+    fn matching(html: &str, selector: &str) -> Vec<String> {
+        fn collect(element: &ElementRef, selector: &Selector, found: &mut Vec<String>) {
+            if let Some(id) = element.data().and_then(|e| e.get_id()) {
+                if selector_matches(element, selector) {
+                    found.push(id.clone());
+                }
+            }
+            for (index, child) in element.node.children.iter().enumerate() {
+                if is_element(child) {
+                    let child = ElementRef {
+                        node: child,
+                        parent: Some(element),
+                        index,
+                    };
+                    collect(&child, selector, found);
+                }
+            }
+        }
+
+        let nodes = HtmlParser::new(html).parse_nodes();
+        let root = nodes.iter().find(|n| is_element(n)).unwrap();
+        let stylesheet = CssParser::new(&format!("{} {{}}", selector)).parse_stylesheet();
+
+        let mut found = Vec::new();
+        let root = ElementRef {
+            node: root,
+            parent: None,
+            index: 0,
+        };
+        collect(&root, &stylesheet.rules[0].selectors[0], &mut found);
+        found
+    }
+
+    // Styles `html` with `sheets`, each parsed as coming from its origin, and
+    // hands the root of the style tree to `check`.
+    fn with_style_tree<F>(html: &str, sheets: &[(Origin, &str)], check: F)
+    where
+        F: FnOnce(&StyledNode),
+    {
+        let nodes = HtmlParser::new(html).parse_nodes();
+        let root = nodes.iter().find(|n| is_element(n)).unwrap();
+        let stylesheets: Vec<Stylesheet> = sheets
+            .iter()
+            .map(|&(origin, css)| {
+                let mut stylesheet = CssParser::new(css).parse_stylesheet();
+                stylesheet.set_origin(origin);
+                stylesheet
+            })
+            .collect();
+
+        check(&StyledNode::new(root, &stylesheets, 800.0, 600.0));
+    }
+
+    fn find<'t, 'a>(node: &'t StyledNode<'a>, id: &str) -> &'t StyledNode<'a> {
+        fn search<'t, 'a>(node: &'t StyledNode<'a>, id: &str) -> Option<&'t StyledNode<'a>> {
+            match node.source {
+                Source::Node(&Node {
+                    node_type: NodeType::Element(ref e),
+                    ..
+                }) if e.get_id().map_or(false, |i| i == id) => Some(node),
+                _ => node.children.iter().filter_map(|c| search(c, id)).next(),
+            }
+        }
+        search(node, id).unwrap_or_else(|| panic!("no element with id '{}'", id))
+    }
+
+    // The text of an element's `::before` or `::after` box, if it has one.
+    fn generated(node: &StyledNode, pseudo_element: PseudoElement) -> Option<String> {
+        node.children
+            .iter()
+            .find(|c| match c.source {
+                Source::PseudoElement(p) => p == pseudo_element,
+                _ => false,
+            })
+            .map(|c| c.children[0].text().unwrap().to_string())
+    }
+
+    fn pseudo_class(selector: &str) -> Option<PseudoClass> {
+        let mut stylesheet = CssParser::new(&format!("{} {{}}", selector)).parse_stylesheet();
+        if stylesheet.rules.is_empty() {
+            return None;
+        }
+        let mut simple = stylesheet.rules.remove(0).selectors.remove(0).simple;
+        Some(simple.remove(0).pseudo_classes.remove(0))
+    }
+
+    #[test]
+    fn an_plus_b_syntax() {
+        assert_eq!(pseudo_class(":nth-child(odd)"), Some(PseudoClass::NthChild(2, 1)));
+        assert_eq!(pseudo_class(":nth-child(EVEN)"), Some(PseudoClass::NthChild(2, 0)));
+        assert_eq!(pseudo_class(":nth-child(-n+3)"), Some(PseudoClass::NthChild(-1, 3)));
+        assert_eq!(pseudo_class(":nth-child( 2n- 1 )"), Some(PseudoClass::NthChild(2, -1)));
+        assert_eq!(pseudo_class(":nth-last-child(+n)"), Some(PseudoClass::NthLastChild(1, 0)));
+        assert_eq!(pseudo_class(":nth-of-type(-2n + 5)"), Some(PseudoClass::NthOfType(-2, 5)));
+        assert_eq!(pseudo_class(":nth-last-of-type(7)"), Some(PseudoClass::NthLastOfType(0, 7)));
+
+        for invalid in &[":nth-child(2 n)", ":nth-child(n-)", ":nth-child(+ n)", ":nth-child()"] {
+            assert_eq!(pseudo_class(invalid), None, "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn an_plus_b_positions() {
+        let matches = |a, b| (1..10).filter(|&p| nth_matches(a, b, p)).collect::<Vec<_>>();
+
+        assert_eq!(matches(2, 1), vec![1, 3, 5, 7, 9]);
+        assert_eq!(matches(3, -1), vec![2, 5, 8]);
+        assert_eq!(matches(0, 4), vec![4]);
+        assert_eq!(matches(-1, 3), vec![1, 2, 3]);
+        assert_eq!(matches(-2, 5), vec![1, 3, 5]);
+        assert_eq!(matches(-1, 0), Vec::<usize>::new());
+    }
+
+    #[test]
+    fn nth_child_counts_every_element_sibling() {
+        let html = "<ul><li id=a></li>text<!-- c --><li id=b></li><p id=c></p><li id=d></li></ul>";
+
+        assert_eq!(matching(html, "ul > :nth-child(2n+1)"), vec!["a", "c"]);
+        assert_eq!(matching(html, "li:nth-child(even)"), vec!["b", "d"]);
+        assert_eq!(matching(html, ":nth-last-child(-n+2)"), vec!["c", "d"]);
+        assert_eq!(matching(html, "ul > :first-child"), vec!["a"]);
+        assert_eq!(matching(html, "ul > :last-child"), vec!["d"]);
+    }
+
+    #[test]
+    fn of_type_pseudo_classes_skip_other_tags() {
+        let html = "<div><p id=a></p><span id=b></span><p id=c></p><span id=d></span><p id=e></p>\
+                    <em id=f></em></div>";
+
+        assert_eq!(matching(html, "p:nth-of-type(2)"), vec!["c"]);
+        assert_eq!(matching(html, ":nth-last-of-type(2)"), vec!["b", "c"]);
+        assert_eq!(matching(html, "div > :first-of-type"), vec!["a", "b", "f"]);
+        assert_eq!(matching(html, "div > :last-of-type"), vec!["d", "e", "f"]);
+        assert_eq!(matching(html, "div > :only-of-type"), vec!["f"]);
+    }
+
+    #[test]
+    fn not_is_and_where_take_selector_lists() {
+        let html = "<p id=a class=a></p><p id=b></p><p id=c></p><div id=d><p id=e></p></div>";
+
+        assert_eq!(matching(html, "p:not(.a, #b)"), vec!["c", "e"]);
+        assert_eq!(matching(html, "p:is(#a, div > p)"), vec!["a", "e"]);
+        assert_eq!(matching(html, ":where(#b, #d)"), vec!["b", "d"]);
+        assert_eq!(matching(html, "p:not(:first-child)"), vec!["b", "c"]);
+    }
+
+    #[test]
+    fn ids_beat_later_classes_and_important_beats_both() {
+        let css = "#x { margin-left: 1px } .c { margin-left: 2px }
+                   p { margin-top: 3px !important } #x { margin-top: 4px }
+                   .c { margin-right: 5px } .c { margin-right: 6px }";
+
+        with_style_tree("<p id=x class=c>", &[(Origin::Author, css)], |root| {
+            let p = find(root, "x");
+            assert_eq!(p.length("margin-left", 0.0), Some(1.0));
+            assert_eq!(p.length("margin-top", 0.0), Some(3.0));
+            assert_eq!(p.length("margin-right", 0.0), Some(6.0));
+        });
+    }
+
+    #[test]
+    fn origins_order_normal_and_important_declarations() {
+        let user_agent = "p { margin-left: 1px !important; margin-right: 1px }
+                          p#x { margin-top: 1px; margin-bottom: 1px !important }";
+        let author = "#x { margin-left: 2px !important }
+                      * { margin-right: 2px; margin-top: 2px; margin-bottom: 2px !important }";
+        let sheets = [(Origin::UserAgent, user_agent), (Origin::Author, author)];
+
+        with_style_tree("<p id=x>", &sheets, |root| {
+            let p = find(root, "x");
+            // Important user-agent declarations beat important author ones.
+            assert_eq!(p.length("margin-left", 0.0), Some(1.0));
+            assert_eq!(p.length("margin-bottom", 0.0), Some(1.0));
+            // Author declarations beat the user agent's whatever their
+            // specificity.
+            assert_eq!(p.length("margin-right", 0.0), Some(2.0));
+            assert_eq!(p.length("margin-top", 0.0), Some(2.0));
+        });
+    }
+
+    #[test]
+    fn counters_number_headings() {
+        let html = "<h1 id=a>A</h1><h2 id=b>x</h2><h2 id=c>y</h2>\
+                    <h1 id=d>B</h1><h2 id=e>z</h2><h3 id=f>w</h3>";
+        let css = "body { counter-reset: chapter }
+                   h1 { counter-increment: chapter; counter-reset: section }
+                   h1::before { content: counter(chapter, upper-roman) \". \" }
+                   h2::before { counter-increment: section; content: counter(chapter) \".\"
+                                counter(section) \" \" }
+                   h3::before { content: counter(figure) }";
+
+        with_style_tree(html, &[(Origin::Author, css)], |root| {
+            let before = |id| generated(find(root, id), PseudoElement::Before).unwrap();
+            assert_eq!(before("a"), "I. ");
+            assert_eq!(before("b"), "1.1 ");
+            assert_eq!(before("c"), "1.2 ");
+            assert_eq!(before("d"), "II. ");
+            assert_eq!(before("e"), "2.1 ");
+            // A counter that was never created reads as 0.
+            assert_eq!(before("f"), "0");
+        });
+    }
+
+    #[test]
+    fn nested_resets_create_new_counters() {
+        let html = "<div><ol><li id=a>a<ol><li id=b>b</li><li id=c>c</li></ol></li><li id=d>d</li>\
+                    </ol><p id=e></p></div><p id=f>";
+        let css = "ol { counter-reset: item } li { counter-increment: item }
+                   li::before { content: counters(item, \".\") \" \" }
+                   p::before { content: counter(item) }";
+
+        with_style_tree(html, &[(Origin::Author, css)], |root| {
+            let before = |id| generated(find(root, id), PseudoElement::Before).unwrap();
+            assert_eq!(before("a"), "1 ");
+            assert_eq!(before("b"), "1.1 ");
+            assert_eq!(before("c"), "1.2 ");
+            assert_eq!(before("d"), "2 ");
+            // A reset reaches the element's following siblings, but no
+            // further than its parent.
+            assert_eq!(before("e"), "2");
+            assert_eq!(before("f"), "0");
+        });
+    }
+
+    #[test]
+    fn attr_falls_back_to_nothing() {
+        let html = "<a id=x href=Page.html>x</a><a id=y>y</a>";
+        let css = "a::after { content: \" (\" attr(HREF) \")\" }";
+
+        with_style_tree(html, &[(Origin::Author, css)], |root| {
+            assert_eq!(generated(find(root, "x"), PseudoElement::After).unwrap(), " (Page.html)");
+            assert_eq!(generated(find(root, "y"), PseudoElement::After).unwrap(), " ()");
+        });
+    }
+
+    #[test]
+    fn before_and_after_boxes_need_content() {
+        let html = "<p id=a>text</p><p id=b>text</p><p id=c>text</p><p id=d>text</p>";
+        let css = "p::before { content: \"[\" } p::after { content: \"]\" }
+                   #b::before { content: none } #b::after { content: normal }
+                   #c::before { display: none }
+                   #d::before, #d::after { color: red; content: initial }";
+
+        with_style_tree(html, &[(Origin::Author, css)], |root| {
+            let a = find(root, "a");
+            assert_eq!(a.children.len(), 3);
+            assert_eq!(generated(a, PseudoElement::Before), Some(String::from("[")));
+            assert_eq!(a.children[1].text(), Some("text"));
+            assert_eq!(generated(a, PseudoElement::After), Some(String::from("]")));
+
+            assert_eq!(find(root, "b").children.len(), 1);
+            assert_eq!(generated(find(root, "c"), PseudoElement::Before), None);
+            assert_eq!(find(root, "d").children.len(), 1);
+        });
+    }
+
+    #[test]
+    fn quotes_nest() {
+        let html = "<q id=a>a <q id=b>b</q> <span id=c>c</span></q><q id=d>d</q><i id=e></i>";
+        let css = "q::before { content: open-quote } q::after { content: close-quote }
+                   span::before { content: no-open-quote } span::after { content: no-close-quote }
+                   i::before { content: close-quote \"x\" }";
+
+        with_style_tree(html, &[(Origin::Author, css)], |root| {
+            let quotes = |id| {
+                let node = find(root, id);
+                let before = generated(node, PseudoElement::Before).unwrap();
+                before + &generated(node, PseudoElement::After).unwrap()
+            };
+            assert_eq!(quotes("a"), "\u{201c}\u{201d}");
+            assert_eq!(quotes("b"), "\u{2018}\u{2019}");
+            assert_eq!(quotes("c"), "");
+            assert_eq!(quotes("d"), "\u{201c}\u{201d}");
+            // A close-quote with no quote open inserts nothing.
+            assert_eq!(generated(find(root, "e"), PseudoElement::Before).unwrap(), "x");
+        });
+    }
+}